@@ -0,0 +1,106 @@
+// Compares the decode-and-match loop (`VM::step`) with the pre-decoded
+// handler loop used by `VM::run`. The jit is turned off so both only measure
+// the interpreter.
+//
+//     cargo bench --bench dispatch
+use simplestackmachine::smachine::compiler::{ByteCode, byte_code_compiler};
+use simplestackmachine::smachine::vm::VM;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+const COUNT_LOOP: &str = "
+    push 0
+    loop:
+    inc
+    dup
+    push 1000000
+    cmp
+    jnz loop
+";
+
+const ARITHMETIC: &str = "
+    push 0
+    loop:
+    push 3
+    push 4
+    uadd32
+    push 5
+    usub16
+    swap 1
+    inc
+    swap 1
+    pop
+    dup
+    push 500000
+    cmp
+    jnz loop
+";
+
+const CALLS: &str = "
+    jmp start
+    add:
+    push 1
+    push 2
+    uadd64
+    ret
+    start:
+    push 0
+    loop:
+    call add
+    pop
+    inc
+    dup
+    push 300000
+    cmp
+    jnz loop
+";
+
+fn eval_loop(vm: &mut VM) {
+    while vm.is_running() {
+        if vm.step().is_none() {
+            break;
+        }
+    }
+}
+
+fn threaded(vm: &mut VM) {
+    vm.execute();
+}
+
+fn measure(bin: &[ByteCode], run: fn(&mut VM)) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut vm = VM::new(bin.to_vec());
+            vm.set_jit_enabled(false);
+            let start = Instant::now();
+            run(black_box(&mut vm));
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    println!(
+        "{:<12} {:>12} {:>12} {:>8}",
+        "program", "eval loop", "threaded", "speedup"
+    );
+    for (name, source) in [
+        ("count_loop", COUNT_LOOP),
+        ("arithmetic", ARITHMETIC),
+        ("calls", CALLS),
+    ] {
+        let bin = byte_code_compiler(source).expect("benchmark program should assemble");
+        let old = measure(&bin, eval_loop);
+        let new = measure(&bin, threaded);
+        println!(
+            "{:<12} {:>10.2?} {:>10.2?} {:>7.2}x",
+            name,
+            old,
+            new,
+            old.as_secs_f64() / new.as_secs_f64()
+        );
+    }
+}
@@ -1,7 +1,20 @@
-mod smachine;
+use simplestackmachine::smachine;
+use smachine::compiler::Program;
+use smachine::crash;
+use smachine::debugger::Debugger;
+use smachine::gdbstub;
+use smachine::jit_cache::JitCache;
+use smachine::jit_dump::JitDump;
+use smachine::mapped::{self, MappedCode};
+use smachine::object;
+use smachine::optimizer;
+use smachine::repl::Repl;
+use smachine::text;
+use smachine::trace::{self, Trace, TraceWriter};
 use smachine::vm;
 use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::path::Path;
 
 fn get_stem(file_path: &str) -> Option<&str> {
@@ -11,14 +24,456 @@ fn get_stem(file_path: &str) -> Option<&str> {
 fn get_extension(file_path: &str) -> Option<&str> {
     Path::new(file_path).extension().and_then(OsStr::to_str)
 }
+
+struct Options {
+    debug_flag: bool,
+    jit_cache: Option<String>,
+    jit_dump: Option<String>,
+    perf_map: bool,
+    optimize: bool,
+    debug_info: bool,
+    disasm: bool,
+    trace: Option<String>,
+    no_jit: bool,
+    profile: bool,
+    folded: Option<String>,
+    coverage: Option<String>,
+    object: bool,
+    compact: bool,
+    aligned: bool,
+    size_report: bool,
+}
+
+fn run_vm(program: Program, options: &Options) {
+    if options.disasm {
+        print!("{}", smachine::compiler::disassemble(&program));
+        return;
+    }
+    if options.size_report {
+        print!("{}", smachine::compact::size_report(&program));
+        return;
+    }
+
+    run_loaded(vm::VM::from_program(program), options);
+}
+
+// runs a .bin in the aligned format from the mapped file
+fn run_mapped(file_path: &str, options: &Options) {
+    match MappedCode::open(file_path) {
+        Ok((code, program)) => run_loaded(vm::VM::from_mapped(code, program), options),
+        Err(err) => eprintln!("ERROR: could not map {}: {}", file_path, err),
+    }
+}
+
+fn run_loaded(mut vm: vm::VM, options: &Options) {
+    let debug = vm.debug_info().cloned();
+    let hash = smachine::compiler::program_hash(vm.bin());
+    if options.no_jit {
+        vm.set_jit_enabled(false);
+    }
+    if let Some(path) = &options.trace {
+        match TraceWriter::create(path, hash) {
+            Ok(trace) => vm.set_trace(trace),
+            Err(err) => eprintln!("WARNING: cannot write trace {}: {}", path, err),
+        }
+    }
+    if let Some(dir) = &options.jit_cache {
+        match JitCache::new(dir) {
+            Ok(cache) => vm.set_jit_cache(cache),
+            Err(err) => eprintln!("WARNING: cannot use jit cache {}: {}", dir, err),
+        }
+    }
+
+    if options.jit_dump.is_some() || options.perf_map {
+        match JitDump::new(options.jit_dump.as_deref(), options.perf_map) {
+            Ok(dump) => vm.set_jit_dump(dump),
+            Err(err) => eprintln!("WARNING: cannot dump jit code: {}", err),
+        }
+    }
+
+    if options.profile || options.folded.is_some() {
+        vm.enable_profile();
+    }
+    if options.coverage.is_some() {
+        vm.enable_coverage();
+    }
+
+    if options.debug_flag {
+        Debugger::new(debug).run(&mut vm);
+    } else {
+        vm.run();
+    }
+
+    if let Some(profile) = vm.profile() {
+        if options.profile {
+            print!("{}", profile.report(vm.bin(), vm.debug_info()));
+        }
+        if let Some(path) = &options.folded
+            && let Err(err) = fs::write(path, profile.folded(vm.debug_info()))
+        {
+            eprintln!("WARNING: cannot write folded stacks {}: {}", path, err);
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (vm.coverage(), &options.coverage) {
+        match vm.debug_info() {
+            Some(debug) => {
+                print!("{}", coverage.summary(vm.bin(), debug));
+                if let Err(err) = fs::write(path, coverage.lcov(vm.bin(), debug)) {
+                    eprintln!("WARNING: cannot write coverage {}: {}", path, err);
+                }
+            }
+            None => eprintln!("WARNING: no source positions for coverage, build the .bin with -g"),
+        }
+    }
+}
+
+// gdb [--listen <host:port> | --stdio] <file>
+fn gdb_server(mut arguments: impl Iterator<Item = String>) {
+    let mut listen = String::from("127.0.0.1:1234");
+    let mut stdio = false;
+    let mut file_path = String::new();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--listen" => {
+                if let Some(address) = arguments.next() {
+                    listen = address;
+                }
+            }
+            "--stdio" => {
+                stdio = true;
+            }
+            _ => {
+                file_path = arg;
+            }
+        }
+    }
+
+    let Some(program) = smachine::compiler::load_file(&file_path) else {
+        return;
+    };
+    let vm = vm::VM::from_program(program);
+    let res = if stdio {
+        gdbstub::serve_stdio(vm)
+    } else {
+        gdbstub::serve_tcp(vm, &listen)
+    };
+    if let Err(err) = res {
+        eprintln!("ERROR: gdb stub stopped: {}", err);
+    }
+}
+
+fn read_trace(path: &str) -> Option<Trace> {
+    match Trace::read(path) {
+        Ok(trace) => Some(trace),
+        Err(err) => {
+            eprintln!("ERROR: cannot read trace {}: {}", path, err);
+            None
+        }
+    }
+}
+
+// trace <file> [--pc <start>..<end> | <start>..=<last>] [--op <name>]...
+// trace diff <a> <b>
+fn trace_command(arguments: Vec<String>) {
+    if arguments.first().map(String::as_str) == Some("diff") {
+        let (Some(a), Some(b)) = (arguments.get(1), arguments.get(2)) else {
+            eprintln!("usage: trace diff <a.trace> <b.trace>");
+            return;
+        };
+        if let (Some(a), Some(b)) = (read_trace(a), read_trace(b))
+            && !trace::diff(&a, &b)
+        {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut filter = trace::Filter::default();
+    let mut file_path = None;
+    let mut arguments = arguments.into_iter();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--pc" => {
+                let range = arguments.next().unwrap_or_default();
+                // like rust ranges, 10..20 leaves out 20 and 10..=20 takes it in
+                let bounds = match range.split_once("..=") {
+                    Some((start, end)) => end
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|end| end.checked_add(1))
+                        .map(|end| (start, end)),
+                    None => range
+                        .split_once("..")
+                        .and_then(|(start, end)| Some((start, end.parse().ok()?))),
+                };
+                match bounds.and_then(|(start, end)| Some((start.parse().ok()?, end))) {
+                    Some(bounds) => filter.pcs = Some(bounds),
+                    None => {
+                        eprintln!("ERROR: --pc takes a range like 10..20 or 10..=19");
+                        return;
+                    }
+                }
+            }
+            "--op" => {
+                filter.opcodes.extend(arguments.next());
+            }
+            _ => {
+                file_path = Some(arg);
+            }
+        }
+    }
+
+    match file_path {
+        Some(path) => {
+            if let Some(trace) = read_trace(&path) {
+                trace::print(&trace, &filter);
+            }
+        }
+        None => {
+            eprintln!("usage: trace <file> [--pc <start>..<end> | <start>..=<last>] [--op <name>]")
+        }
+    }
+}
+
+// -c file.s, assembles to file.o for link
+fn compile_object(file_path: &str, options: &Options) {
+    let source = match fs::read_to_string(file_path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("ERROR: could not read {}: {}", file_path, err);
+            return;
+        }
+    };
+    let Some(mut object) = smachine::compiler::assemble_object(&source, file_path) else {
+        return;
+    };
+    if !options.debug_info {
+        object.program.debug = None;
+    }
+    let Some(stem) = get_stem(file_path) else {
+        return;
+    };
+    let path = stem.to_owned() + ".o";
+    if let Err(err) = object.write(&path) {
+        eprintln!("ERROR: could not write {}: {}", path, err);
+    }
+}
+
+// the cost of running code in place, printed with the usage of the commands writing it
+const ALIGNED_HELP: &str = "  --aligned  runs straight from the mapped file so it starts without decoding, \
+each instruction is then dispatched by opcode which is a bit slower than a decoded program";
+
+// link <a.o> <b.o>... [-o out.bin] [--entry <symbol>] [-g] [--compact | --aligned]
+fn link_command(mut arguments: impl Iterator<Item = String>) {
+    let mut objects = Vec::new();
+    let mut output = None;
+    let mut entry = None;
+    let mut debug_info = false;
+    let mut compact = false;
+    let mut aligned = false;
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "-o" => output = arguments.next(),
+            "--entry" => entry = arguments.next(),
+            "-g" | "--debug-info" => debug_info = true,
+            "--compact" => compact = true,
+            "--aligned" => aligned = true,
+            _ => objects.push(arg),
+        }
+    }
+    let Some(first) = objects.first() else {
+        eprintln!(
+            "usage: link <a.o> <b.o>... [-o out.bin] [--entry <symbol>] [-g] [--compact | --aligned]\n{}",
+            ALIGNED_HELP
+        );
+        return;
+    };
+    let output = output.unwrap_or(get_stem(first).unwrap_or("a").to_owned() + ".bin");
+
+    let Some(mut program) = object::link_files(&objects, entry.as_deref()) else {
+        std::process::exit(1);
+    };
+    if !debug_info {
+        program.debug = None;
+    }
+    let written = if compact {
+        smachine::compiler::write_compact_bin(&output, &program)
+    } else if aligned {
+        smachine::compiler::write_aligned_bin(&output, &program)
+    } else {
+        smachine::compiler::write_bin(&output, &program)
+    };
+    if let Err(err) = written {
+        eprintln!("ERROR: could not write {}: {}", output, err);
+        std::process::exit(1);
+    }
+}
+
+// convert <in.s|in.bin|in.bct> <out.bin|out.bct> [-g] [--compact | --aligned]
+fn convert_command(arguments: impl Iterator<Item = String>) {
+    let mut files = Vec::new();
+    let mut debug_info = false;
+    let mut compact = false;
+    let mut aligned = false;
+    for arg in arguments {
+        match arg.as_str() {
+            "-g" | "--debug-info" => debug_info = true,
+            "--compact" => compact = true,
+            "--aligned" => aligned = true,
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = files.as_slice() else {
+        eprintln!(
+            "usage: convert <in.s|in.bin|in.bct> <out.bin|out.bct> [-g] [--compact | --aligned]\n{}",
+            ALIGNED_HELP
+        );
+        return;
+    };
+    let Some(mut program) = smachine::compiler::load_file(input) else {
+        std::process::exit(1);
+    };
+    // a .bin or .bct keeps what it has, assembly only gets a debug section with -g
+    if !matches!(get_extension(input), Some("bin" | "bct")) && !debug_info {
+        program.debug = None;
+    }
+    let written = match get_extension(output) {
+        Some("bct") => text::write_file(output, &program),
+        _ if compact => smachine::compiler::write_compact_bin(output, &program),
+        _ if aligned => smachine::compiler::write_aligned_bin(output, &program),
+        _ => smachine::compiler::write_bin(output, &program),
+    };
+    if let Err(err) = written {
+        eprintln!("ERROR: could not write {}: {}", output, err);
+        std::process::exit(1);
+    }
+}
+
+// inspect-dump <dump.json> [program]
+fn inspect_dump(arguments: Vec<String>) {
+    let Some(dump_path) = arguments.first() else {
+        eprintln!("usage: inspect-dump <dump.json> [program.s|program.bin]");
+        return;
+    };
+    let dump = match crash::read_dump(dump_path) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("ERROR: could not read {}: {}", dump_path, err);
+            return;
+        }
+    };
+    let program = match arguments.get(1) {
+        Some(path) => match smachine::compiler::load_file(path) {
+            Some(program) => Some(program),
+            None => return,
+        },
+        None => None,
+    };
+    print!("{}", crash::inspect(&dump, program.as_ref()));
+}
+
 fn startup() {
-    let arguments = env::args();
-    let mut debug_flag = false;
+    if env::args().nth(1).as_deref() == Some("dap") {
+        if let Err(err) = smachine::dap::serve_stdio() {
+            eprintln!("ERROR: debug adapter stopped: {}", err);
+        }
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("gdb") {
+        gdb_server(env::args().skip(2));
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("trace") {
+        trace_command(env::args().skip(2).collect());
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("link") {
+        link_command(env::args().skip(2));
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("convert") {
+        convert_command(env::args().skip(2));
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("repl") {
+        Repl::new().run();
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("inspect-dump") {
+        inspect_dump(env::args().skip(2).collect());
+        return;
+    }
+
+    let mut arguments = env::args().skip(1);
+    let mut options = Options {
+        debug_flag: false,
+        jit_cache: None,
+        jit_dump: None,
+        perf_map: false,
+        optimize: false,
+        debug_info: false,
+        disasm: false,
+        trace: None,
+        no_jit: false,
+        profile: false,
+        folded: None,
+        coverage: None,
+        object: false,
+        compact: false,
+        aligned: false,
+        size_report: false,
+    };
     let mut file_path: String = String::from("");
-    for arg in arguments {
+    while let Some(arg) = arguments.next() {
         match arg.as_str() {
             "--debug" => {
-                debug_flag = true;
+                options.debug_flag = true;
+            }
+            "--jit-cache" => {
+                options.jit_cache = arguments.next();
+            }
+            "--jit-dump" => {
+                options.jit_dump = arguments.next();
+            }
+            "--perf-map" => {
+                options.perf_map = true;
+            }
+            "-O" | "--optimize" => {
+                options.optimize = true;
+            }
+            "-g" | "--debug-info" => {
+                options.debug_info = true;
+            }
+            "--disasm" => {
+                options.disasm = true;
+            }
+            "--trace" => {
+                options.trace = arguments.next();
+            }
+            "--no-jit" => {
+                options.no_jit = true;
+            }
+            "--profile" => {
+                options.profile = true;
+            }
+            "--folded" => {
+                options.folded = arguments.next();
+            }
+            "--coverage" => {
+                options.coverage = arguments.next();
+            }
+            "-c" => {
+                options.object = true;
+            }
+            "--compact" => {
+                options.compact = true;
+            }
+            "--aligned" => {
+                options.aligned = true;
+            }
+            "--size" => {
+                options.size_report = true;
             }
             _ => {
                 file_path = arg.clone();
@@ -27,32 +482,47 @@ fn startup() {
     }
     if let Some(stem) = get_extension(file_path.as_str()) {
         match stem {
+            // --disasm and --size go through read_bin, they need the code in a Program
+            "bin"
+                if !options.disasm
+                    && !options.size_report
+                    && mapped::is_aligned_bin(&file_path) =>
+            {
+                run_mapped(&file_path, &options);
+            }
             "bin" => {
-                if let Ok(bytecode) = smachine::compiler::read_bin(file_path) {
-                    let mut vm = vm::VM::new(bytecode);
-                    if debug_flag {
-                        println!("Debug mode");
-                        vm.debug_run(debug_flag);
-                    } else {
-                        vm.run();
-                    }
+                if let Ok(program) = smachine::compiler::read_bin(file_path) {
+                    run_vm(program, &options);
                 }
             }
+            "bct" => match text::read_file(&file_path) {
+                Ok(program) => run_vm(program, &options),
+                Err(err) => eprintln!("ERROR: could not read {}: {}", file_path, err),
+            },
+            _ if options.object => compile_object(&file_path, &options),
             _ => {
-                let bin = smachine::compiler::compile_file(&file_path);
-                if let Some(bytecode) = bin
+                let mut bin = smachine::compiler::compile_file(&file_path);
+                if options.optimize {
+                    bin = bin.map(optimizer::optimize_program);
+                }
+                if let Some(program) = bin
                     && let Some(stem) = get_stem(&file_path)
                 {
                     let new_path = stem.to_owned() + ".bin";
-                    let res = smachine::compiler::write_bin(&new_path, bytecode.clone());
-                    if let Ok(_) = res {
-                        let mut vm = vm::VM::new(bytecode);
-                        if debug_flag {
-                            println!("Debug mode");
-                            vm.debug_run(debug_flag);
-                        } else {
-                            vm.run();
-                        }
+                    // the debug section is only written when asked for
+                    let written = Program {
+                        debug: program.debug.clone().filter(|_| options.debug_info),
+                        ..program.clone()
+                    };
+                    let res = if options.compact {
+                        smachine::compiler::write_compact_bin(&new_path, &written)
+                    } else if options.aligned {
+                        smachine::compiler::write_aligned_bin(&new_path, &written)
+                    } else {
+                        smachine::compiler::write_bin(&new_path, &written)
+                    };
+                    if res.is_ok() {
+                        run_vm(program, &options);
                     }
                 }
             }
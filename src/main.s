@@ -1,4 +1,4 @@
-jmp start
+.entry start
 
 func:
     push 11
@@ -0,0 +1,186 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use super::compiler::{ByteCode, Program, TokenType};
+use super::leb128;
+
+/// Starts a `.bin` whose code is in the compact encoding, the fixed one starts with
+/// the instruction count instead
+pub const MAGIC: &[u8; 4] = b"SMCB";
+const VERSION: u8 = 1;
+// set on the opcode byte when an operand follows it
+const OPERAND_BIT: u8 = 0x80;
+// what every instruction takes in the fixed encoding
+const FIXED_SIZE: usize = 9;
+
+// push operands are often small negative numbers, zigzag keeps them short
+fn operand_bits(binary: &ByteCode) -> u64 {
+    match TokenType::from(binary.opcode) {
+        TokenType::Push => leb128::zigzag(binary.value as i64),
+        _ => binary.value,
+    }
+}
+
+fn leb128_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+// instructions without an operand, or with a 0, are a single opcode byte
+fn instruction_len(binary: &ByteCode) -> usize {
+    if binary.value == 0 {
+        1
+    } else {
+        1 + leb128_len(operand_bits(binary))
+    }
+}
+
+/// Writes the magic, the instruction count and the instructions. An operand is only
+/// written when there is one, as LEB128, so `pop` takes one byte and `push 1` two.
+pub fn write_code<W: Write>(writer: &mut W, code: &[ByteCode]) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    leb128::write_u64(writer, code.len() as u64)?;
+    for binary in code {
+        if binary.opcode & OPERAND_BIT != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("opcode {} can not be encoded compactly", binary.opcode),
+            ));
+        }
+        if instruction_len(binary) == 1 {
+            writer.write_all(&[binary.opcode])?;
+        } else {
+            writer.write_all(&[binary.opcode | OPERAND_BIT])?;
+            leb128::write_u64(writer, operand_bits(binary))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the code `write_code` wrote, after the magic
+pub fn read_code<R: Read>(reader: &mut R) -> Result<Vec<ByteCode>> {
+    let mut version = [0u8; 1];
+    reader.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown compact bytecode version {}", version[0]),
+        ));
+    }
+    let len = leb128::read_u64(reader)?;
+    let mut code = Vec::new();
+    for _ in 0..len {
+        let mut opcode = [0u8; 1];
+        reader.read_exact(&mut opcode)?;
+        let mut binary = ByteCode {
+            opcode: opcode[0] & !OPERAND_BIT,
+            value: 0,
+        };
+        if opcode[0] & OPERAND_BIT != 0 {
+            let bits = leb128::read_u64(reader)?;
+            binary.value = match TokenType::from(binary.opcode) {
+                TokenType::Push => leb128::unzigzag(bits) as u64,
+                _ => bits,
+            };
+        }
+        code.push(binary);
+    }
+    Ok(code)
+}
+
+/// How the code of `program` sizes up in the fixed and the compact encoding
+pub fn size_report(program: &Program) -> String {
+    let code = &program.code;
+    let with_operand = code
+        .iter()
+        .filter(|binary| instruction_len(binary) > 1)
+        .count();
+    let fixed = 8 + code.len() * FIXED_SIZE;
+    let compact = MAGIC.len()
+        + 1
+        + leb128_len(code.len() as u64)
+        + code.iter().map(instruction_len).sum::<usize>();
+    let per_instruction = |bytes: usize| bytes as f64 / code.len().max(1) as f64;
+
+    let mut out = format!(
+        "code: {} instructions, {} with an operand\n",
+        code.len(),
+        with_operand
+    );
+    out.push_str(&format!(
+        "  fixed:   {:>8} bytes ({:.1} per instruction)\n",
+        fixed,
+        per_instruction(fixed)
+    ));
+    out.push_str(&format!(
+        "  compact: {:>8} bytes ({:.1} per instruction), {:.0}% smaller\n",
+        compact,
+        per_instruction(compact),
+        100.0 - compact as f64 * 100.0 / fixed as f64
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let code: Vec<ByteCode> = [
+            (TokenType::Push, 0),
+            (TokenType::Push, 1),
+            (TokenType::Push, -1i64 as u64),
+            (TokenType::Push, i64::MIN as u64),
+            (TokenType::Push, u64::MAX >> 1),
+            (TokenType::Jmp, 128),
+            (TokenType::Swap, u64::MAX),
+            (TokenType::Pop, 0),
+        ]
+        .into_iter()
+        .map(|(kind, value)| ByteCode {
+            opcode: kind as u8,
+            value,
+        })
+        .collect();
+
+        let mut bytes = Vec::new();
+        write_code(&mut bytes, &code).unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(read_code(&mut &bytes[4..]).unwrap(), code);
+    }
+
+    #[test]
+    fn small_operands_are_short() {
+        let code = [
+            ByteCode {
+                opcode: TokenType::Pop as u8,
+                value: 0,
+            },
+            ByteCode {
+                opcode: TokenType::Push as u8,
+                value: -1i64 as u64,
+            },
+        ];
+        let mut bytes = Vec::new();
+        write_code(&mut bytes, &code).unwrap();
+        // magic, version, count, pop, push and its zigzagged operand
+        assert_eq!(bytes.len(), 4 + 1 + 1 + 1 + 2);
+    }
+
+    #[test]
+    fn rejects_truncated_code() {
+        let code = [ByteCode {
+            opcode: TokenType::Push as u8,
+            value: 300,
+        }];
+        let mut bytes = Vec::new();
+        write_code(&mut bytes, &code).unwrap();
+        bytes.pop();
+        assert!(read_code(&mut &bytes[4..]).is_err());
+    }
+}
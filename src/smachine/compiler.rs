@@ -1,8 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::fs;
 use std::io;
 use std::io::{Read, Result, Write};
+use std::mem;
+use std::path::Path;
+
+use super::compact;
+use super::debug_info::{self, DebugInfo, SourcePos};
+use super::expr::{self, Number};
+use super::jit_cache;
+use super::mapped;
+use super::object::{Object, Relocation, Site, Target};
+use super::preprocess::{self, Line};
+use super::text;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
@@ -42,6 +53,8 @@ pub enum TokenType {
     Jnz,
     Cmp,
     Int,
+    Load,
+    Store,
     Value,
     Label,
     Name,
@@ -51,60 +64,76 @@ pub enum TokenType {
 impl TokenType {
     pub fn from(opcode: u8) -> TokenType {
         if opcode < (TokenType::Err as u8) {
-            unsafe { std::mem::transmute(opcode) }
+            unsafe { std::mem::transmute::<u8, TokenType>(opcode) }
         } else {
             TokenType::Err
         }
     }
+
+    // the name used in the assembly source
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            TokenType::Push => "push",
+            TokenType::Pop => "pop",
+            TokenType::Uadd8 => "uadd8",
+            TokenType::Usub8 => "usub8",
+            TokenType::Uadd16 => "uadd16",
+            TokenType::Usub16 => "usub16",
+            TokenType::Uadd32 => "uadd32",
+            TokenType::Usub32 => "usub32",
+            TokenType::Uadd64 => "uadd64",
+            TokenType::Usub64 => "usub64",
+            TokenType::Add8 => "add8",
+            TokenType::Sub8 => "sub8",
+            TokenType::Add16 => "add16",
+            TokenType::Sub16 => "sub16",
+            TokenType::Add32 => "add32",
+            TokenType::Sub32 => "sub32",
+            TokenType::Add64 => "add64",
+            TokenType::Sub64 => "sub64",
+            TokenType::Addf64 => "addf64",
+            TokenType::Subf64 => "subf64",
+            TokenType::Addf32 => "addf32",
+            TokenType::Subf32 => "subf32",
+            TokenType::Prt => "prt",
+            TokenType::Inc => "inc",
+            TokenType::Dup => "dup",
+            TokenType::Jmp => "jmp",
+            TokenType::Call => "call",
+            TokenType::Jmpp => "jmpp",
+            TokenType::Halt => "halt",
+            TokenType::Ret => "ret",
+            TokenType::Swap => "swap",
+            TokenType::Jeq => "jeq",
+            TokenType::Jnz => "jnz",
+            TokenType::Cmp => "cmp",
+            TokenType::Int => "int",
+            TokenType::Load => "load",
+            TokenType::Store => "store",
+            TokenType::Value => "value",
+            TokenType::Label => "label",
+            TokenType::Name => "name",
+            TokenType::Err => "err",
+        }
+    }
+
+    // instructions that are followed by an argument in the source
+    pub fn takes_operand(&self) -> bool {
+        matches!(
+            *self,
+            TokenType::Push
+                | TokenType::Jmp
+                | TokenType::Jeq
+                | TokenType::Jnz
+                | TokenType::Swap
+                | TokenType::Call
+        )
+    }
 }
 
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "(TokenType: {})",
-            match *self {
-                TokenType::Push => "Push",
-                TokenType::Pop => "Pop",
-                TokenType::Uadd8 => "Uadd8",
-                TokenType::Usub8 => "Usub8",
-                TokenType::Uadd16 => "uadd16",
-                TokenType::Usub16 => "usub16",
-                TokenType::Uadd32 => "uadd32",
-                TokenType::Usub32 => "usub32",
-                TokenType::Uadd64 => "uadd64",
-                TokenType::Usub64 => "usub64",
-                TokenType::Add8 => "sub8",
-                TokenType::Sub8 => "sub8",
-                TokenType::Add16 => "add16",
-                TokenType::Sub16 => "sub16",
-                TokenType::Add32 => "add32",
-                TokenType::Sub32 => "sub32",
-                TokenType::Add64 => "add64",
-                TokenType::Sub64 => "sub64",
-                TokenType::Addf64 => "addf64",
-                TokenType::Subf64 => "subf64",
-                TokenType::Addf32 => "addf32",
-                TokenType::Subf32 => "subf32",
-                TokenType::Prt => "Prt",
-                TokenType::Value => "Value",
-                TokenType::Inc => "Inc",
-                TokenType::Dup => "Dup",
-                TokenType::Label => "Label",
-                TokenType::Jmp => "Jmp",
-                TokenType::Call => "Call",
-                TokenType::Jmpp => "Jmpp",
-                TokenType::Jeq => "Jeq",
-                TokenType::Jnz => "Jnz",
-                TokenType::Cmp => "Cmp",
-                TokenType::Halt => "Halt",
-                TokenType::Ret => "Ret",
-                TokenType::Int => "Int",
-                TokenType::Swap => "Swap",
-                TokenType::Name => "Name",
-                TokenType::Err => "Err",
-            }
-        )
+        write!(f, "(TokenType: {})", self.mnemonic())
     }
 }
 
@@ -112,11 +141,30 @@ impl fmt::Display for TokenType {
 struct Token {
     kind: TokenType,
     value: String,
+    // where the token starts in the source, 0 when it was generated
+    file: u32,
+    line: u32,
+    column: u32,
+    // set on operands that depend on where the code is placed
+    target: Option<Target>,
 }
 
 impl Token {
+    fn at(text: &str, file: u32, line: u32, column: u32) -> Token {
+        Self {
+            file,
+            line,
+            column,
+            ..Token::new(text)
+        }
+    }
+
     pub fn new(text: &str) -> Token {
         Self {
+            file: 0,
+            line: 0,
+            column: 0,
+            target: None,
             value: String::from(text),
             kind: match text {
                 "push" => TokenType::Push,
@@ -152,6 +200,8 @@ impl Token {
                 "cmp" => TokenType::Cmp,
                 "halt" => TokenType::Halt,
                 "int" => TokenType::Int,
+                "load" => TokenType::Load,
+                "store" => TokenType::Store,
                 "swap" => TokenType::Swap,
                 "ret" => TokenType::Ret,
                 val => {
@@ -182,19 +232,29 @@ impl Token {
     }
 }
 
-impl<'a> fmt::Display for Token {
+impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "(value: {}, kind: {})", self.value, self.kind)
     }
 }
 
+/// Whether `text` is the name of an instruction
+pub fn is_mnemonic(text: &str) -> bool {
+    !matches!(
+        Token::new(text).kind,
+        TokenType::Value | TokenType::Name | TokenType::Label | TokenType::Err
+    )
+}
+
 #[derive(Debug)]
 enum Data {
     Token(Token),
 }
 
+// repr(C) so an aligned .bin can be used in place, see `mapped`
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
 pub struct ByteCode {
     pub opcode: u8,
     pub value: u64,
@@ -202,6 +262,16 @@ pub struct ByteCode {
 
 #[allow(dead_code)]
 impl ByteCode {
+    /// The instruction as it would be written in the source
+    pub fn describe(&self) -> String {
+        let kind = TokenType::from(self.opcode);
+        if kind.takes_operand() {
+            format!("{} {}", kind.mnemonic(), self.value)
+        } else {
+            kind.mnemonic().to_string()
+        }
+    }
+
     fn new(inst: Token, arg: Option<Data>) -> Option<ByteCode> {
         if let Some(argument) = arg {
             Some(Self {
@@ -243,14 +313,14 @@ impl ByteCode {
         }
     }
 
-    fn write_to_bin<W: Write>(&self, writer: &mut W) -> Result<()> {
+    pub fn write_to_bin<W: Write>(&self, writer: &mut W) -> Result<()> {
         // Write both opcode and value to a writer
         writer.write_all(&self.opcode.to_le_bytes())?;
         writer.write_all(&self.value.to_le_bytes())?;
         Ok(())
     }
 
-    fn read_from_bin<R: Read>(reader: &mut R) -> Result<Self> {
+    pub fn read_from_bin<R: Read>(reader: &mut R) -> Result<Self> {
         // Reads the opcode
         let mut opcode_buf = [0u8; 1];
         reader.read_exact(&mut opcode_buf)?;
@@ -265,47 +335,644 @@ impl ByteCode {
     }
 }
 
-fn parse_code<'a>(code: &'a str) -> Option<Vec<Token>> {
+// a `.equ`/`.const` definition, evaluated where it is used
+struct Constant {
+    text: String,
+    file: u32,
+    line: u32,
+}
+
+// added to label values to see which operands move with the code
+const SHIFT: i64 = 1 << 32;
+// the most words one .zero can add, 8 MiB
+const MAX_ZERO: i64 = 1 << 20;
+
+// where the lines that follow go
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Section {
+    Text,
+    Rodata,
+    Data,
+}
+
+impl Section {
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Rodata => ".rodata",
+            Section::Data => ".data",
+        }
+    }
+}
+
+// values the names in operands, constants can refer to labels and other constants
+struct Symbols<'a> {
+    labels: &'a HashMap<String, String>,
+    // labels in .rodata and .data, with their word in that section
+    data_labels: &'a HashMap<String, (Section, u64)>,
+    // .data comes after .rodata in the vm's memory
+    rodata_len: u64,
+    constants: &'a HashMap<String, Constant>,
+    // unknown names are imports instead of errors when assembling an object
+    object: bool,
+    imports: BTreeSet<String>,
+    // what is moved by SHIFT, the labels of a section or one import
+    moved: Option<Target>,
+    used_label: bool,
+}
+
+impl Symbols<'_> {
+    fn shift(&self, target: Target) -> i64 {
+        if self.moved == Some(target) { SHIFT } else { 0 }
+    }
+
+    fn resolve(
+        &mut self,
+        name: &str,
+        seen: &mut Vec<String>,
+    ) -> std::result::Result<Number, String> {
+        if let Some(constant) = self.constants.get(name) {
+            if seen.iter().any(|other| other == name) {
+                return Err(format!("constant {} refers to itself", name));
+            }
+            let text = constant.text.clone();
+            seen.push(name.to_string());
+            let value = expr::eval(&text, &mut |name: &str| self.resolve(name, seen));
+            seen.pop();
+            return value;
+        }
+        if let Some(pos) = self.labels.get(name) {
+            self.used_label = true;
+            return Ok(Number::Int(
+                pos.parse::<i64>().unwrap_or_default() + self.shift(Target::Local),
+            ));
+        }
+        if let Some((section, offset)) = self.data_labels.get(name) {
+            self.used_label = true;
+            let address = match section {
+                Section::Data => self.rodata_len + offset,
+                _ => *offset,
+            } as i64;
+            let target = match section {
+                Section::Data => Target::Data,
+                _ => Target::Rodata,
+            };
+            return Ok(Number::Int(address + self.shift(target)));
+        }
+        if self.object {
+            self.imports.insert(name.to_string());
+            return Ok(Number::Int(self.shift(Target::Symbol(name.to_string()))));
+        }
+        Err(format!("label not found: {}", name))
+    }
+
+    fn eval(&mut self, text: &str) -> std::result::Result<Number, String> {
+        expr::eval(text, &mut |name: &str| self.resolve(name, &mut Vec::new()))
+    }
+
+    /// The value of an operand, with what it has to be relocated against in an object
+    fn operand(&mut self, text: &str) -> std::result::Result<(Number, Option<Target>), String> {
+        self.imports.clear();
+        self.used_label = false;
+        let value = self.eval(text)?;
+        if !self.object || (!self.used_label && self.imports.is_empty()) {
+            return Ok((value, None));
+        }
+        let Number::Int(base) = value else {
+            return Err(String::from("labels can not be used in float expressions"));
+        };
+
+        // move the labels of each section, then each import, and see which of them
+        // the value follows
+        let mut candidates = vec![Target::Local, Target::Rodata, Target::Data];
+        candidates.extend(mem::take(&mut self.imports).into_iter().map(Target::Symbol));
+        let mut target = None;
+        for candidate in candidates {
+            self.moved = Some(candidate.clone());
+            let moved = self.eval(text);
+            self.moved = None;
+            let Ok(Number::Int(moved)) = moved else {
+                return Err(String::from("labels can not be used in float expressions"));
+            };
+            match moved.wrapping_sub(base) {
+                0 => {}
+                SHIFT if target.is_none() => target = Some(candidate),
+                _ => {
+                    return Err(format!(
+                        "{} can not be relocated, use a label plus or minus a number",
+                        text
+                    ));
+                }
+            }
+        }
+        Ok((value, target))
+    }
+}
+
+// the tokens with their operands resolved and what goes around them in the program
+struct Parsed {
+    tokens: Vec<Token>,
+    labels: HashMap<String, String>,
+    // the names made `.global`, with where
+    globals: Vec<(String, u32, u32)>,
+    entry: Option<usize>,
+    rodata: Vec<u64>,
+    data: Vec<u64>,
+    // the words of .rodata and .data that refer to labels in an object
+    relocations: Vec<Relocation>,
+}
+
+// a word of .rodata or .data, expressions are evaluated once every label is known
+enum Word {
+    Expr(String, u32, u32),
+    Value(u64),
+}
+
+// the characters of a "string" literal, with the escapes of character literals
+fn string_literal(text: &str) -> std::result::Result<Vec<u64>, String> {
+    let Some(inner) = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .filter(|_| text.len() >= 2)
+    else {
+        return Err(format!("expected a string like \"text\", got {}", text));
+    };
+    let mut out = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"' | '\'')) => c,
+                _ => return Err(String::from("unknown escape in string")),
+            },
+            c => c,
+        };
+        out.push(c as u64);
+    }
+    Ok(out)
+}
+
+// `1:` can be defined any number of times, `1b` and `1f` find the nearest one
+fn is_anonymous(label: &str) -> bool {
+    !label.is_empty() && label.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// gives local `.name` and anonymous `1:` labels the names they are stored under
+#[derive(Default)]
+struct Scope {
+    // the last label that is neither local nor anonymous, `.loop` belongs to it
+    global: Option<String>,
+    // how many times each anonymous label has been defined so far
+    anonymous: HashMap<String, usize>,
+    // where each label was defined, to report duplicates
+    defined: HashMap<String, String>,
+    // (name, reference) of the `1f` references, checked once every label is known
+    forward: Vec<(String, String, u32, u32)>,
+}
+
+impl Scope {
+    fn local(&self, name: &str) -> String {
+        match &self.global {
+            Some(global) if name.starts_with('.') => format!("{}{}", global, name),
+            _ => name.to_string(),
+        }
+    }
+
+    /// The name of a label defined at `origin`, or where it was defined before
+    fn define(&mut self, label: &str, origin: String) -> std::result::Result<String, String> {
+        let name = if is_anonymous(label) {
+            let count = self.anonymous.entry(label.to_string()).or_default();
+            *count += 1;
+            format!("_{}@{}", label, *count - 1)
+        } else {
+            // labels a macro made unique do not start a scope
+            if !label.starts_with('.') && !label.contains('@') {
+                self.global = Some(label.to_string());
+            }
+            self.local(label)
+        };
+        if let Some(first) = self.defined.get(&name) {
+            return Err(first.clone());
+        }
+        self.defined.insert(name.clone(), origin);
+        Ok(name)
+    }
+
+    /// Rewrites the label names in an operand. `1b`/`1f` are only labels in the operands
+    /// of jumps and calls, anywhere else `1f` stays the float 1.0.
+    fn operand(
+        &mut self,
+        text: &str,
+        jump: bool,
+        file: u32,
+        line: u32,
+    ) -> std::result::Result<String, String> {
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let word_char = |c: &char| c.is_alphanumeric() || "_.@".contains(*c);
+            match c {
+                // character literals are copied as they are
+                '\'' => {
+                    out.push(c);
+                    while let Some(c) = chars.next() {
+                        out.push(c);
+                        match c {
+                            '\\' => out.extend(chars.next()),
+                            '\'' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '.' if chars.peek().is_some_and(|c| !c.is_ascii_digit()) => {
+                    let mut name = String::from(c);
+                    while let Some(c) = chars.next_if(word_char) {
+                        name.push(c);
+                    }
+                    out.push_str(&self.local(&name));
+                }
+                c if c.is_ascii_digit() => {
+                    let mut word = String::from(c);
+                    while let Some(c) = chars.next_if(word_char) {
+                        word.push(c);
+                    }
+                    if jump {
+                        out.push_str(&self.anonymous_reference(&word, file, line)?);
+                    } else {
+                        out.push_str(&word);
+                    }
+                }
+                c if word_char(&c) => {
+                    out.push(c);
+                    while let Some(c) = chars.next_if(word_char) {
+                        out.push(c);
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        Ok(out)
+    }
+
+    // `1b` is the last `1:` so far, `1f` the next one, anything else is a number
+    fn anonymous_reference(
+        &mut self,
+        word: &str,
+        file: u32,
+        line: u32,
+    ) -> std::result::Result<String, String> {
+        let (label, direction) = word.split_at(word.len() - 1);
+        if !is_anonymous(label) {
+            return Ok(word.to_string());
+        }
+        let count = self.anonymous.get(label).copied().unwrap_or_default();
+        match direction {
+            "b" if count == 0 => Err(format!("{} has no {}: before it", word, label)),
+            "b" => Ok(format!("_{}@{}", label, count - 1)),
+            "f" => {
+                let name = format!("_{}@{}", label, count);
+                self.forward
+                    .push((name.clone(), word.to_string(), file, line));
+                Ok(name)
+            }
+            _ => Ok(word.to_string()),
+        }
+    }
+}
+
+// adds the words of a .word, .ascii, .string or .zero line to its section
+fn data_words(
+    directive: &str,
+    rest: &str,
+    line: &Line,
+    constants: &HashMap<String, Constant>,
+    scope: &mut Scope,
+    words: &mut Vec<Word>,
+) -> std::result::Result<(), String> {
+    match directive {
+        ".word" => {
+            for text in preprocess::split_args(rest) {
+                let text = scope.operand(text, false, line.file, line.line)?;
+                words.push(Word::Expr(text, line.file, line.line));
+            }
+        }
+        ".zero" => {
+            // the size has to be known before the labels are, only constants can be used
+            let mut symbols = Symbols {
+                labels: &HashMap::new(),
+                data_labels: &HashMap::new(),
+                rodata_len: 0,
+                constants,
+                object: false,
+                imports: BTreeSet::new(),
+                moved: None,
+                used_label: false,
+            };
+            match symbols.eval(rest)? {
+                Number::Int(count) if (0..=MAX_ZERO).contains(&count) => {
+                    words.extend((0..count).map(|_| Word::Value(0)))
+                }
+                Number::Int(count) if count > MAX_ZERO => {
+                    return Err(format!(
+                        ".zero {} is over the limit of {} words",
+                        count, MAX_ZERO
+                    ));
+                }
+                count => return Err(format!(".zero needs a count, got {}", count)),
+            }
+        }
+        _ => {
+            words.extend(string_literal(rest)?.into_iter().map(Word::Value));
+            // .string ends with a 0 like a C string
+            if directive == ".string" {
+                words.push(Word::Value(0));
+            }
+        }
+    }
+    Ok(())
+}
+
+// defines a label in `scope`, reporting a second definition of it
+fn define(scope: &mut Scope, label: &str, origin: &dyn Fn() -> String) -> Option<String> {
+    match scope.define(label, origin()) {
+        Ok(name) => Some(name),
+        Err(first) => {
+            println!(
+                "ERROR: label {} is defined twice, at {} and {}",
+                label,
+                first,
+                origin()
+            );
+            None
+        }
+    }
+}
+
+fn parse_code(lines: &[Line], files: &[String], object: bool) -> Option<Parsed> {
     let mut tokens: Vec<Token> = Vec::new();
-    let split_code = code.split_whitespace(); //.map(Token::new).collect();
     let mut labels: HashMap<String, String> = HashMap::new();
+    let mut data_labels: HashMap<String, (Section, u64)> = HashMap::new();
+    let mut constants: HashMap<String, Constant> = HashMap::new();
+    let mut globals: Vec<(String, u32, u32)> = Vec::new();
+    let mut entry: Option<(String, u32, u32)> = None;
+    let mut scope = Scope::default();
+    let mut section = Section::Text;
+    let mut rodata: Vec<Word> = Vec::new();
+    let mut data: Vec<Word> = Vec::new();
     let mut pos: u64 = 0;
-    for val in split_code {
-        if val.ends_with(':') {
-            labels.insert(
-                {
-                    let n_val = String::from(val);
-                    String::from(n_val.trim_end_matches(':'))
-                },
-                pos.to_string(),
-            );
-        } else {
-            let token = Token::new(val);
+    for line in lines {
+        let origin = || preprocess::origin(files, line.file, line.line);
+        let words = preprocess::words(&line.text);
+        if let Some((_, ".equ" | ".const")) = words.first() {
+            let name = words.get(1).map(|(_, name)| name.trim_end_matches(','));
+            let text = words.get(2).map(|(column, _)| line.text[*column..].trim());
+            let (Some(name), Some(text)) = (name, text) else {
+                println!("ERROR: expected .equ NAME value at {}", origin());
+                return None;
+            };
+            if constants.contains_key(name) || is_mnemonic(name) {
+                println!("ERROR: {} is already defined at {}", name, origin());
+                return None;
+            }
+            let constant = Constant {
+                text: text.to_string(),
+                file: line.file,
+                line: line.line,
+            };
+            constants.insert(name.to_string(), constant);
+            continue;
+        }
 
-            match token.kind {
-                TokenType::Value | TokenType::Name | TokenType::Label | TokenType::Err => {}
-                _ => {
-                    pos += 1;
+        if let Some((_, ".global" | ".globl")) = words.first() {
+            let names = words[1..]
+                .iter()
+                .flat_map(|(_, word)| word.split(','))
+                .filter(|name| !name.is_empty());
+            globals.extend(names.map(|name| (name.to_string(), line.file, line.line)));
+            continue;
+        }
+
+        // leading labels name the next instruction, or the next word of .rodata/.data
+        let start = words
+            .iter()
+            .position(|(_, word)| !word.ends_with(':'))
+            .unwrap_or(words.len());
+        for (_, label) in &words[..start] {
+            let label = label.trim_end_matches(':');
+            let name = define(&mut scope, label, &origin)?;
+            match section {
+                Section::Text => {
+                    labels.insert(name, pos.to_string());
+                }
+                Section::Rodata => {
+                    data_labels.insert(name, (section, rodata.len() as u64));
+                }
+                Section::Data => {
+                    data_labels.insert(name, (section, data.len() as u64));
                 }
             }
+        }
 
-            tokens.push(token);
+        let directive = words.get(start).map(|(_, word)| *word);
+        let rest = words
+            .get(start + 1)
+            .map_or("", |(column, _)| line.text[*column..].trim());
+        match directive {
+            Some(".text") => section = Section::Text,
+            Some(".rodata") => section = Section::Rodata,
+            Some(".data") => section = Section::Data,
+            Some(".entry") => {
+                if entry.is_some() {
+                    println!("ERROR: the entry point is already set at {}", origin());
+                    return None;
+                }
+                if rest.is_empty() {
+                    println!("ERROR: expected .entry LABEL at {}", origin());
+                    return None;
+                }
+                entry = Some((scope.local(rest), line.file, line.line));
+            }
+            Some(name @ (".word" | ".ascii" | ".string" | ".zero")) => {
+                let words = match section {
+                    Section::Rodata => &mut rodata,
+                    Section::Data => &mut data,
+                    Section::Text => {
+                        println!(
+                            "ERROR: {} goes in .data or .rodata, not .text at {}",
+                            name,
+                            origin()
+                        );
+                        return None;
+                    }
+                };
+                if let Err(message) = data_words(name, rest, line, &constants, &mut scope, words) {
+                    println!("ERROR: {} at {}", message, origin());
+                    return None;
+                }
+            }
+            Some(word) if section != Section::Text => {
+                println!(
+                    "ERROR: instructions go in .text, found {} in {} at {}",
+                    word,
+                    section.name(),
+                    origin()
+                );
+                return None;
+            }
+            // instructions, read below
+            _ => {}
+        }
+        let is_directive = matches!(
+            directive,
+            Some(
+                ".text" | ".rodata" | ".data" | ".entry" | ".word" | ".ascii" | ".string" | ".zero"
+            )
+        );
+        if is_directive || section != Section::Text {
+            continue;
+        }
+
+        let mut index = start;
+        while index < words.len() {
+            let (column, val) = words[index];
+            index += 1;
+            if let Some(label) = val.strip_suffix(':') {
+                let name = define(&mut scope, label, &origin)?;
+                labels.insert(name, pos.to_string());
+            } else {
+                let column = line.column.unwrap_or(column as u32 + 1);
+                let token = Token::at(val, line.file, line.line, column);
+
+                match token.kind {
+                    TokenType::Value | TokenType::Name | TokenType::Label | TokenType::Err => {}
+                    _ => {
+                        pos += 1;
+                    }
+                }
+
+                let takes_operand = token.kind.takes_operand();
+                let jump = matches!(
+                    token.kind,
+                    TokenType::Jmp | TokenType::Jeq | TokenType::Jnz | TokenType::Call
+                );
+                tokens.push(token);
+
+                // the operand can be an expression over several words
+                if takes_operand && index < words.len() {
+                    let start = words[index].0;
+                    let mut end = index + 1;
+                    while end < words.len()
+                        && expr::continues(&line.text[start..words[end].0], words[end].1)
+                    {
+                        end += 1;
+                    }
+                    let (last, word) = words[end - 1];
+                    let text = &line.text[start..last + word.len()];
+                    let text = match scope.operand(text, jump, line.file, line.line) {
+                        Ok(text) => text,
+                        Err(message) => {
+                            println!("ERROR: {} at {}", message, origin());
+                            return None;
+                        }
+                    };
+                    let column = line.column.unwrap_or(start as u32 + 1);
+                    tokens.push(Token::at(&text, line.file, line.line, column));
+                    index = end;
+                }
+            }
         }
     }
 
-    for i in 0..tokens.len() {
-        let token = &mut tokens[i];
-        match token.kind {
-            TokenType::Name => {
-                let found = labels.get(&token.value);
-                if let Some(val) = found {
-                    token.value = val.clone();
-                } else if !token.value.parse::<u64>().is_ok() {
-                    println!("ERROR: label not found: {}", &token.value);
+    for (name, word, file, line) in &scope.forward {
+        if !labels.contains_key(name) && !data_labels.contains_key(name) {
+            println!(
+                "ERROR: {} has no {}: after it at {}",
+                word,
+                word.trim_end_matches('f'),
+                preprocess::origin(files, *file, *line)
+            );
+            return None;
+        }
+    }
+
+    // operands would find the constant and never the label
+    for (name, constant) in &constants {
+        if labels.contains_key(name) || data_labels.contains_key(name) {
+            println!(
+                "ERROR: {} is a label and a constant at {}",
+                name,
+                preprocess::origin(files, constant.file, constant.line)
+            );
+            return None;
+        }
+    }
+
+    let mut symbols = Symbols {
+        labels: &labels,
+        data_labels: &data_labels,
+        rodata_len: rodata.len() as u64,
+        constants: &constants,
+        object,
+        imports: BTreeSet::new(),
+        moved: None,
+        used_label: false,
+    };
+    let mut operand_of = TokenType::Err;
+    for token in tokens.iter_mut() {
+        if let TokenType::Name = token.kind {
+            let value = symbols.operand(&token.value).map(|(value, target)| {
+                token.target = target;
+                value
+            });
+            let value = match value {
+                // jump targets and swap depths are indices
+                Ok(value) if !value.is_int() && !matches!(operand_of, TokenType::Push) => Err(
+                    format!("{} needs an integer, got {}", operand_of.mnemonic(), value),
+                ),
+                value => value,
+            };
+            match value {
+                Ok(value) => token.value = value.to_string(),
+                Err(message) => {
+                    println!(
+                        "ERROR: {} at {}",
+                        message,
+                        preprocess::origin(files, token.file, token.line)
+                    );
                     return None;
                 }
             }
-            _ => (),
+        }
+        if let TokenType::Value = token.kind
+            && operand_of.takes_operand()
+            && !matches!(operand_of, TokenType::Push)
+            && token.value.parse::<i64>().is_err()
+            && token.value.parse::<u64>().is_err()
+        {
+            println!(
+                "ERROR: {} needs an integer, got {} at {}",
+                operand_of.mnemonic(),
+                token.value,
+                preprocess::origin(files, token.file, token.line)
+            );
+            return None;
+        }
+        operand_of = token.kind;
+    }
+    // a constant with an error is reported even when nothing uses it
+    for (name, constant) in &constants {
+        if let Err(message) = symbols.eval(name) {
+            println!(
+                "ERROR: {} in .equ {} at {}",
+                message,
+                name,
+                preprocess::origin(files, constant.file, constant.line)
+            );
+            return None;
         }
     }
 
@@ -319,39 +986,225 @@ fn parse_code<'a>(code: &'a str) -> Option<Vec<Token>> {
         };
     }
 
-    Some(tokens)
+    // the words of .rodata and .data
+    let mut sections = [Vec::new(), Vec::new()];
+    let mut relocations = Vec::new();
+    for ((words, out), site) in [rodata, data]
+        .into_iter()
+        .zip(&mut sections)
+        .zip([Site::Rodata, Site::Data])
+    {
+        for word in words {
+            let (text, file, line) = match word {
+                Word::Value(value) => {
+                    out.push(value);
+                    continue;
+                }
+                Word::Expr(text, file, line) => (text, file, line),
+            };
+            let message = match symbols.operand(&text) {
+                Ok((value, None)) => {
+                    out.push(value.to_bits());
+                    continue;
+                }
+                Ok((value, Some(target))) => {
+                    relocations.push(Relocation {
+                        site,
+                        index: out.len(),
+                        target,
+                    });
+                    out.push(value.to_bits());
+                    continue;
+                }
+                Err(message) => message,
+            };
+            println!(
+                "ERROR: {} at {}",
+                message,
+                preprocess::origin(files, file, line)
+            );
+            return None;
+        }
+    }
+    let [rodata, data] = sections;
+
+    // `.entry` wins over a `.global main`, objects leave main to the linker
+    let entry = match entry {
+        Some((name, file, line)) => match labels.get(&name) {
+            Some(pos) => Some(pos.parse().unwrap_or_default()),
+            None => {
+                println!(
+                    "ERROR: .entry {} is not a label in .text at {}",
+                    name,
+                    preprocess::origin(files, file, line)
+                );
+                return None;
+            }
+        },
+        None if !object && globals.iter().any(|(name, _, _)| name == "main") => labels
+            .get("main")
+            .map(|pos| pos.parse().unwrap_or_default()),
+        None => None,
+    };
+
+    Some(Parsed {
+        tokens,
+        labels,
+        globals,
+        entry,
+        rodata,
+        data,
+        relocations,
+    })
+}
+
+/// An assembled program, with the debug info if it is known
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub code: Vec<ByteCode>,
+    pub debug: Option<DebugInfo>,
+    // where execution starts, the first instruction when not set
+    pub entry: Option<usize>,
+    // the words of .rodata and .data, .data is addressed after .rodata
+    pub rodata: Vec<u64>,
+    pub data: Vec<u64>,
 }
 
-fn byte_code_compiler(code: &str) -> Option<Vec<ByteCode>> {
+impl Program {
+    pub fn new(code: Vec<ByteCode>) -> Program {
+        Self {
+            code,
+            ..Program::default()
+        }
+    }
+}
+
+pub fn byte_code_compiler(code: &str) -> Option<Vec<ByteCode>> {
+    assemble(code, "<input>").map(|program| program.code)
+}
+
+/// Assembles the source of `file_name`, recording where each instruction came from
+pub fn assemble(code: &str, file_name: &str) -> Option<Program> {
+    assemble_source(code, file_name, false).map(|object| object.program)
+}
+
+/// Assembles one part of a program, labels it does not define are left to the linker
+pub fn assemble_object(code: &str, file_name: &str) -> Option<Object> {
+    assemble_source(code, file_name, true)
+}
+
+/// Assembles `(file_name, code)` parts one after the other as one program, each
+/// part keeps its name for errors and `.include`s relative to it
+pub fn assemble_parts(parts: &[(&str, &str)]) -> Option<Program> {
+    let files = parts.iter().map(|(name, _)| name.to_string()).collect();
+    let lines = parts
+        .iter()
+        .enumerate()
+        .flat_map(|(file, (_, code))| preprocess::lines_of(code, file as u32))
+        .collect();
+    assemble_files(files, lines, false).map(|object| object.program)
+}
+
+fn assemble_source(code: &str, file_name: &str, object: bool) -> Option<Object> {
+    let files = vec![String::from(file_name)];
+    assemble_files(files, preprocess::lines_of(code, 0), object)
+}
+
+fn assemble_files(mut files: Vec<String>, lines: Vec<Line>, object: bool) -> Option<Object> {
+    let lines = preprocess::include_files(lines, &mut files)?;
+    let lines = preprocess::expand_macros(lines, &files)?;
+    assemble_lines(&lines, files, object)
+}
+
+// the lines after preprocessing, `files` are the names their file indices refer to
+fn assemble_lines(lines: &[Line], files: Vec<String>, object: bool) -> Option<Object> {
     // transforms all the asm to code
     //let tokens: Vec<Token> = code.split_whitespace().map(Token::new).collect();
-    let partial_tokens: Option<Vec<Token>> = parse_code(code);
+    let partial_tokens = parse_code(lines, &files, object);
+    // starts with the ones of .rodata and .data
+    let mut relocations: Vec<Relocation>;
+    let mut symbols: Vec<(String, usize)> = Vec::new();
     // create a buffer vector for bytecodes
     let mut byts: Vec<ByteCode> = Vec::new(); // make it into a iter
+    let mut program = Program::default();
+    let mut debug = DebugInfo {
+        files,
+        ..DebugInfo::default()
+    };
+
+    if let Some(Parsed {
+        tokens,
+        labels,
+        globals,
+        entry,
+        rodata,
+        data,
+        relocations: data_relocations,
+    }) = partial_tokens
+    {
+        relocations = data_relocations;
+        program.entry = entry;
+        program.rodata = rodata;
+        program.data = data;
+        for (name, file, line) in globals {
+            match labels.get(&name) {
+                Some(pos) => symbols.push((name, pos.parse().unwrap_or_default())),
+                None => {
+                    println!(
+                        "ERROR: .global {} is not defined at {}",
+                        name,
+                        preprocess::origin(&debug.files, file, line)
+                    );
+                    return None;
+                }
+            }
+        }
+        for (label, pos) in labels {
+            debug.labels.push((label, pos.parse().unwrap_or_default()));
+        }
+        debug.labels.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
 
-    if let Some(tokens) = partial_tokens {
         let mut iter = tokens.into_iter();
 
         // Read the Tokens and transform each into a bytecode
         while let Some(current) = iter.next() {
+            let position = SourcePos {
+                file: current.file,
+                line: current.line,
+                column: current.column,
+            };
             match current.kind {
                 TokenType::Push => {
                     let arg = iter.next()?;
+                    if let Some(target) = arg.target.clone() {
+                        relocations.push(Relocation {
+                            site: Site::Code,
+                            index: byts.len(),
+                            target,
+                        });
+                    }
                     let partial_byt = ByteCode::new(current, Some(Data::Token(arg.clone())));
                     if let Some(byt) = partial_byt {
                         byts.push(byt);
                     } else {
-                        println!("Cannot push {}", arg);
+                        println!(
+                            "Cannot push {} at {}",
+                            arg,
+                            preprocess::origin(&debug.files, arg.file, arg.line)
+                        );
                         return None;
                     }
                 }
 
-                TokenType::Jmp
-                | TokenType::Jeq
-                | TokenType::Jnz
-                | TokenType::Swap
-                | TokenType::Call => {
+                kind if kind.takes_operand() => {
                     let arg = iter.next()?;
+                    if let Some(target) = arg.target.clone() {
+                        relocations.push(Relocation {
+                            site: Site::Code,
+                            index: byts.len(),
+                            target,
+                        });
+                    }
                     let partial_byt = ByteCode::new(current, Some(Data::Token(arg.clone())));
                     if let Some(byt) = partial_byt {
                         byts.push(byt);
@@ -361,11 +1214,19 @@ fn byte_code_compiler(code: &str) -> Option<Vec<ByteCode>> {
                 }
 
                 TokenType::Value => {
-                    println!("Cannot use int8 alone: {}", current);
+                    println!(
+                        "Cannot use int8 alone: {} at {}",
+                        current,
+                        preprocess::origin(&debug.files, current.file, current.line)
+                    );
                     return None;
                 }
                 TokenType::Err => {
-                    println!("Cannot use: {}", current);
+                    println!(
+                        "Cannot use: {} at {}",
+                        current,
+                        preprocess::origin(&debug.files, current.file, current.line)
+                    );
                     return None;
                 }
                 _ => {
@@ -377,20 +1238,133 @@ fn byte_code_compiler(code: &str) -> Option<Vec<ByteCode>> {
                     }
                 }
             }
+            debug.positions.push(position);
         }
+    } else {
+        // the error was already reported
+        return None;
     }
 
-    Some(byts)
+    Some(Object {
+        program: Program {
+            code: byts,
+            debug: Some(debug),
+            ..program
+        },
+        symbols,
+        relocations,
+    })
+}
+
+// optional parts of a binary after the code, each one is a tag, a length and the data
+pub const DEBUG_SECTION: &[u8; 4] = b"SMDB";
+const ENTRY_SECTION: &[u8; 4] = b"ENTR";
+const RODATA_SECTION: &[u8; 4] = b"RODA";
+const DATA_SECTION: &[u8; 4] = b"DATA";
+
+pub fn write_section<W: Write>(writer: &mut W, tag: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(tag)?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Writes the sections for the entry point, the data and the debug info, the ones
+/// that are empty are left out
+pub fn write_program_sections<W: Write>(writer: &mut W, program: &Program) -> Result<()> {
+    if let Some(entry) = program.entry {
+        write_section(writer, ENTRY_SECTION, &(entry as u64).to_le_bytes())?;
+    }
+    for (tag, words) in [
+        (RODATA_SECTION, &program.rodata),
+        (DATA_SECTION, &program.data),
+    ] {
+        if !words.is_empty() {
+            let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+            write_section(writer, tag, &data)?;
+        }
+    }
+    if let Some(debug) = &program.debug {
+        let mut data = Vec::new();
+        debug.write_to(&mut data)?;
+        write_section(writer, DEBUG_SECTION, &data)?;
+    }
+    Ok(())
+}
+
+/// Reads a section `write_program_sections` wrote, false when the tag is not one of them.
+/// `code_len` is how many instructions the program has.
+pub fn read_program_section(
+    program: &mut Program,
+    code_len: usize,
+    tag: &[u8; 4],
+    data: &[u8],
+) -> Result<bool> {
+    let words = || {
+        if !data.len().is_multiple_of(8) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a data section is not made of whole words",
+            ));
+        }
+        Ok(data
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect())
+    };
+    match tag {
+        ENTRY_SECTION => {
+            let entry = debug_info::read_u64(&mut &data[..])? as usize;
+            if entry >= code_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the entry point {} is past the code", entry),
+                ));
+            }
+            program.entry = Some(entry);
+        }
+        RODATA_SECTION => program.rodata = words()?,
+        DATA_SECTION => program.data = words()?,
+        DEBUG_SECTION => program.debug = Some(DebugInfo::read_from(&mut &data[..])?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Reads the sections after the code up to the end, unknown ones are skipped
+pub fn read_program_sections<R: Read>(
+    reader: &mut R,
+    program: &mut Program,
+    code_len: usize,
+) -> Result<()> {
+    loop {
+        // the file can only end between sections
+        let mut tag = Vec::with_capacity(4);
+        reader.by_ref().take(4).read_to_end(&mut tag)?;
+        let tag: [u8; 4] = match tag.len() {
+            0 => return Ok(()),
+            4 => [tag[0], tag[1], tag[2], tag[3]],
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "section tag is cut short",
+                ));
+            }
+        };
+        let len = debug_info::read_u64(reader)?;
+        let data = debug_info::read_bytes(reader, len)?;
+
+        read_program_section(program, code_len, &tag, &data)?;
+    }
 }
 
 #[allow(dead_code)]
-pub fn write_bin(path: &str, bin: Vec<ByteCode>) -> Result<()> {
+pub fn write_bin(path: &str, program: &Program) -> Result<()> {
     let f = fs::File::create(path)?;
     {
         let mut writer = io::BufWriter::new(f);
         // Writes the file size;
-        let _ = writer.write(&(bin.len() as u64).to_le_bytes());
-        for binary in &bin {
+        let _ = writer.write(&(program.code.len() as u64).to_le_bytes());
+        for binary in &program.code {
             let v = binary.write_to_bin(&mut writer);
             match v {
                 Ok(()) => (),
@@ -400,13 +1374,32 @@ pub fn write_bin(path: &str, bin: Vec<ByteCode>) -> Result<()> {
                 }
             }
         }
+
+        write_program_sections(&mut writer, program)?;
     }
 
     Ok(())
 }
 
+/// Like `write_bin` with the code in the compact encoding, `read_bin` reads both
+pub fn write_compact_bin(path: &str, program: &Program) -> Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    compact::write_code(&mut writer, &program.code)?;
+    write_program_sections(&mut writer, program)?;
+    writer.flush()
+}
+
+/// Like `write_bin` with the code laid out as it is in memory, so it can be run
+/// from the mapped file, see `mapped`
+pub fn write_aligned_bin(path: &str, program: &Program) -> Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    mapped::write_code(&mut writer, &program.code)?;
+    write_program_sections(&mut writer, program)?;
+    writer.flush()
+}
+
 #[allow(dead_code)]
-pub fn read_bin(path: String) -> Result<Vec<ByteCode>> {
+pub fn read_bin(path: String) -> Result<Program> {
     let mut bin: Vec<ByteCode> = Vec::new();
 
     let f = fs::File::open(path)?;
@@ -414,20 +1407,32 @@ pub fn read_bin(path: String) -> Result<Vec<ByteCode>> {
     let mut reader = io::BufReader::new(f);
     // Reads the file size so that it can know when to stop
     let mut len_buf = [0u8; 8];
-    reader.read_exact(&mut len_buf)?;
-    let len: u64 = u64::from_le_bytes(len_buf);
+    reader.read_exact(&mut len_buf[..4])?;
+    if &len_buf[..4] == compact::MAGIC {
+        bin = compact::read_code(&mut reader)?;
+    } else if &len_buf[..4] == mapped::MAGIC {
+        bin = mapped::read_code(&mut reader)?;
+    } else {
+        reader.read_exact(&mut len_buf[4..])?;
+        let len: u64 = u64::from_le_bytes(len_buf);
 
-    for _ in 0..len {
-        bin.push(ByteCode::read_from_bin(&mut reader)?);
+        for _ in 0..len {
+            bin.push(ByteCode::read_from_bin(&mut reader)?);
+        }
     }
 
-    Ok(bin)
+    let mut program = Program::new(bin);
+    // everything after the code is optional
+    let len = program.code.len();
+    read_program_sections(&mut reader, &mut program, len)?;
+
+    Ok(program)
 }
 
 #[allow(dead_code)]
-pub fn compile_file(path: &str) -> Option<Vec<ByteCode>> {
+pub fn compile_file(path: &str) -> Option<Program> {
     match fs::read_to_string(path) {
-        Ok(value) => byte_code_compiler(&value),
+        Ok(value) => assemble(&value, path),
         Err(error) => {
             println!("Error when opening file in path: {}", path);
             eprintln!("Error: {}", error);
@@ -435,3 +1440,209 @@ pub fn compile_file(path: &str) -> Option<Vec<ByteCode>> {
         }
     }
 }
+
+/// Identifies a program, stable between runs
+pub fn program_hash(code: &[ByteCode]) -> u64 {
+    code.iter().fold(jit_cache::FNV_OFFSET, |hash, binary| {
+        let hash = jit_cache::fnv1a(hash, &[binary.opcode]);
+        jit_cache::fnv1a(hash, &binary.value.to_le_bytes())
+    })
+}
+
+/// Reads a compiled `.bin` file, anything else is assembled
+pub fn load_file(path: &str) -> Option<Program> {
+    let read = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("bin") => read_bin(path.to_string()),
+        Some("bct") => text::read_file(path),
+        _ => return compile_file(path),
+    };
+    match read {
+        Ok(program) => Some(program),
+        Err(error) => {
+            eprintln!("Error when reading {}: {}", path, error);
+            None
+        }
+    }
+}
+
+/// Lists the program one instruction per line, with labels and source lines when known
+pub fn disassemble(program: &Program) -> String {
+    let debug = program.debug.as_ref();
+    let mut out = String::new();
+    if let Some(entry) = program.entry {
+        match debug.and_then(|debug| debug.label_at(entry)) {
+            Some(label) => out.push_str(&format!(".entry {}\n", label)),
+            None => out.push_str(&format!(".entry {}\n", entry)),
+        }
+    }
+    for (pc, binary) in program.code.iter().enumerate() {
+        if let Some(debug) = debug {
+            for (name, _) in debug.labels.iter().filter(|(_, pos)| *pos == pc) {
+                out.push_str(&format!("{}:\n", name));
+            }
+        }
+
+        let kind = TokenType::from(binary.opcode);
+        let instruction = match kind {
+            // jump targets are shown by name when there is one
+            TokenType::Jmp | TokenType::Jeq | TokenType::Jnz | TokenType::Call => {
+                match debug.and_then(|debug| debug.label_at(binary.value as usize)) {
+                    Some(label) => format!("{} {}", kind.mnemonic(), label),
+                    None => format!("{} {}", kind.mnemonic(), binary.value),
+                }
+            }
+            _ if kind.takes_operand() => format!("{} {}", kind.mnemonic(), binary.value),
+            _ => kind.mnemonic().to_string(),
+        };
+
+        match debug.and_then(|debug| debug.location(pc)) {
+            Some(location) => out.push_str(&format!(
+                "{:>6}    {:<24} ; {}\n",
+                pc, instruction, location
+            )),
+            None => out.push_str(&format!("{:>6}    {}\n", pc, instruction)),
+        }
+    }
+    // data addresses count on from the end of .rodata
+    let sections = [(".rodata", &program.rodata), (".data", &program.data)];
+    let mut address = 0;
+    for (name, words) in sections {
+        if !words.is_empty() {
+            out.push_str(&format!("{}\n", name));
+        }
+        for word in words.iter() {
+            out.push_str(&format!("{:>6}    .word {}\n", address, word));
+            address += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operands(source: &str) -> Vec<u64> {
+        assemble(source, "test.s")
+            .unwrap()
+            .code
+            .iter()
+            .map(|binary| binary.value)
+            .collect()
+    }
+
+    #[test]
+    fn anonymous_labels_in_jumps() {
+        let source = "1:\njmp 1b\njnz 1f\n1:\ncall 1b\njeq 1f\n1:\nhalt\n";
+        assert_eq!(&operands(source)[..4], &[0, 2, 2, 4]);
+        assert!(assemble("jmp 1b\n", "test.s").is_none());
+        assert!(assemble("jmp 1f\n", "test.s").is_none());
+    }
+
+    #[test]
+    fn section_lengths_are_bounded_by_the_input() {
+        let mut bytes = DATA_SECTION.to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        let mut program = Program::default();
+        assert!(read_program_sections(&mut bytes.as_slice(), &mut program, 0).is_err());
+    }
+
+    #[test]
+    fn sections_and_the_entry_point() {
+        let source = "
+.rodata
+table: .word 7, 8
+.data
+counter: .zero 2
+flag: .word counter
+.text
+.entry main
+helper:
+    ret
+main:
+    push counter
+    load
+    halt
+";
+        let program = assemble(source, "test.s").unwrap();
+        assert_eq!(program.rodata, vec![7, 8]);
+        // .data is addressed after .rodata
+        assert_eq!(program.data, vec![0, 0, 2]);
+        assert_eq!(program.entry, Some(1));
+        assert_eq!(program.code[1].value, 2);
+
+        let mut bytes = Vec::new();
+        write_program_sections(&mut bytes, &program).unwrap();
+        let mut read = Program::default();
+        read_program_sections(&mut bytes.as_slice(), &mut read, program.code.len()).unwrap();
+        assert_eq!(read.rodata, program.rodata);
+        assert_eq!(read.data, program.data);
+        assert_eq!(read.entry, program.entry);
+    }
+
+    #[test]
+    fn cut_short_section_tags() {
+        let mut bytes = Vec::new();
+        let program = Program {
+            data: vec![1],
+            ..Program::default()
+        };
+        write_program_sections(&mut bytes, &program).unwrap();
+        bytes.extend_from_slice(&DATA_SECTION[..2]);
+        let err = read_program_sections(&mut bytes.as_slice(), &mut Program::default(), 0);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn zero_has_a_limit() {
+        assert!(assemble(".data\n.zero 99999999999\n", "test.s").is_none());
+        assert!(assemble(".data\n.zero -1\n", "test.s").is_none());
+        let program = assemble(".data\n.zero 1048576\n", "test.s").unwrap();
+        assert_eq!(program.data.len(), 1 << 20);
+    }
+
+    #[test]
+    fn constants_can_not_shadow_labels() {
+        assert!(assemble(".equ start, 3\nstart:\npush start\n", "test.s").is_none());
+        assert!(assemble("start:\npush start\n.equ start, 3\n", "test.s").is_none());
+        assert!(assemble(".data\nstart: .word 1\n.equ start, 3\n", "test.s").is_none());
+        assert!(assemble(".equ push, 3\n", "test.s").is_none());
+    }
+
+    #[test]
+    fn anonymous_loops_in_macros() {
+        let source = "
+.macro countdown n
+    push \\n
+1:
+    push 1
+    usub64
+    dup
+    jnz 1b
+    pop
+.endm
+countdown 3
+countdown 5
+halt
+";
+        let code = assemble(source, "test.s").unwrap().code;
+        // each expansion loops back to its own 1:
+        let jumps: Vec<u64> = code
+            .iter()
+            .filter(|binary| binary.opcode == TokenType::Jnz as u8)
+            .map(|binary| binary.value)
+            .collect();
+        assert_eq!(jumps, vec![1, 7]);
+    }
+
+    #[test]
+    fn push_and_word_keep_float_suffixes() {
+        let one = 1.0f32.to_bits() as u64;
+        // a 1: after it does not turn the float into an address
+        assert_eq!(operands("push 1f\n1:\nhalt\n")[0], one);
+        assert_eq!(operands("push 1.0f\n")[0], one);
+        let program = assemble(".rodata\n.word 2f\n.text\n2:\nhalt\n", "test.s").unwrap();
+        assert_eq!(program.rodata, vec![2.0f32.to_bits() as u64]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+
+use super::compiler::{ByteCode, TokenType};
+use super::debug_info::DebugInfo;
+
+/// Which instructions ran and which way the conditional jumps went
+#[derive(Debug)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    // [taken, not taken] for jeq/jnz
+    branches: Vec<[u64; 2]>,
+}
+
+#[derive(Default)]
+struct LineCoverage {
+    hits: u64,
+    // (pc, taken, not taken) of the branches on the line, None if it never ran
+    branches: Vec<(usize, Option<[u64; 2]>)>,
+}
+
+fn is_branch(binary: &ByteCode) -> bool {
+    matches!(
+        TokenType::from(binary.opcode),
+        TokenType::Jeq | TokenType::Jnz
+    )
+}
+
+// 3, 5-8, 10
+fn ranges(lines: &[u32]) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let start = lines[i];
+        while i + 1 < lines.len() && lines[i + 1] == lines[i] + 1 {
+            i += 1;
+        }
+        if lines[i] == start {
+            out.push(start.to_string());
+        } else {
+            out.push(format!("{}-{}", start, lines[i]));
+        }
+        i += 1;
+    }
+    out.join(", ")
+}
+
+impl Coverage {
+    pub fn new(len: usize) -> Coverage {
+        Self {
+            hits: vec![0; len],
+            branches: vec![[0; 2]; len],
+        }
+    }
+
+    pub fn record(&mut self, pc: usize, binary: &ByteCode, taken: Option<bool>) {
+        if let Some(hits) = self.hits.get_mut(pc) {
+            *hits += 1;
+        }
+        if let Some(taken) = taken
+            && is_branch(binary)
+            && let Some(branch) = self.branches.get_mut(pc)
+        {
+            branch[if taken { 0 } else { 1 }] += 1;
+        }
+    }
+
+    // file -> line -> what ran there
+    fn lines(
+        &self,
+        bin: &[ByteCode],
+        debug: &DebugInfo,
+    ) -> BTreeMap<String, BTreeMap<u32, LineCoverage>> {
+        let mut files: BTreeMap<String, BTreeMap<u32, LineCoverage>> = BTreeMap::new();
+        for (pc, binary) in bin.iter().enumerate() {
+            // generated instructions like the final halt have no line
+            let Some((file, pos)) = debug.position(pc) else {
+                continue;
+            };
+            let line = files
+                .entry(file.to_string())
+                .or_default()
+                .entry(pos.line)
+                .or_default();
+            line.hits = line.hits.max(self.hits[pc]);
+            if is_branch(binary) {
+                let ran = self.hits[pc] > 0;
+                line.branches.push((pc, ran.then_some(self.branches[pc])));
+            }
+        }
+        files
+    }
+
+    /// The report in the lcov tracefile format genhtml reads
+    pub fn lcov(&self, bin: &[ByteCode], debug: &DebugInfo) -> String {
+        let mut out = String::new();
+        for (file, lines) in self.lines(bin, debug) {
+            let _ = writeln!(out, "TN:");
+            // genhtml is usually run from somewhere else
+            let path = fs::canonicalize(&file)
+                .map(|path| path.display().to_string())
+                .unwrap_or(file);
+            let _ = writeln!(out, "SF:{}", path);
+            let (mut found, mut hit) = (0, 0);
+            for (line, coverage) in &lines {
+                for (pc, counts) in &coverage.branches {
+                    // branch 0 is the jump being taken, 1 falling through
+                    for branch in 0..2 {
+                        let taken = match counts {
+                            Some(counts) => counts[branch].to_string(),
+                            None => String::from("-"),
+                        };
+                        let _ = writeln!(out, "BRDA:{},{},{},{}", line, pc, branch, taken);
+                        found += 1;
+                        hit += counts.is_some_and(|counts| counts[branch] > 0) as usize;
+                    }
+                }
+            }
+            let _ = writeln!(out, "BRF:{}", found);
+            let _ = writeln!(out, "BRH:{}", hit);
+            for (line, coverage) in &lines {
+                let _ = writeln!(out, "DA:{},{}", line, coverage.hits);
+            }
+            let _ = writeln!(out, "LF:{}", lines.len());
+            let _ = writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|line| line.hits > 0).count()
+            );
+            let _ = writeln!(out, "end_of_record");
+        }
+        out
+    }
+
+    /// Covered lines and branches per file, with the lines that never ran
+    pub fn summary(&self, bin: &[ByteCode], debug: &DebugInfo) -> String {
+        let mut out = String::new();
+        for (file, lines) in self.lines(bin, debug) {
+            let covered = lines.values().filter(|line| line.hits > 0).count();
+            let branches: Vec<(u32, [u64; 2])> = lines
+                .iter()
+                .flat_map(|(line, coverage)| {
+                    coverage
+                        .branches
+                        .iter()
+                        .map(|(_, counts)| (*line, counts.unwrap_or_default()))
+                })
+                .collect();
+            let branches_hit: usize = branches
+                .iter()
+                .map(|(_, counts)| counts.iter().filter(|count| **count > 0).count())
+                .sum();
+            let _ = writeln!(
+                out,
+                "{}: {}/{} lines ({:.1}%), {}/{} branches",
+                file,
+                covered,
+                lines.len(),
+                covered as f64 * 100.0 / lines.len().max(1) as f64,
+                branches_hit,
+                branches.len() * 2
+            );
+
+            let uncovered: Vec<u32> = lines
+                .iter()
+                .filter(|(_, line)| line.hits == 0)
+                .map(|(line, _)| *line)
+                .collect();
+            if !uncovered.is_empty() {
+                let _ = writeln!(out, "  not run: {}", ranges(&uncovered));
+            }
+            for (line, counts) in branches {
+                match counts {
+                    [0, 0] => {}
+                    [0, _] => {
+                        let _ = writeln!(out, "  line {}: the jump is never taken", line);
+                    }
+                    [_, 0] => {
+                        let _ = writeln!(out, "  line {}: the jump is always taken", line);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble;
+    use crate::smachine::vm::VM;
+
+    // line 3 never runs, the jump on line 2 is always taken and the one on 6 never
+    const SOURCE: &str = "push 1\njnz 1f\njnz 1f\n1:\npush 0\njnz 2f\npush 2\n2:\nhalt\n";
+
+    fn covered(source: &str) -> VM {
+        let mut vm = VM::from_program(assemble(source, "test.s").unwrap());
+        vm.set_output(Box::new(std::io::sink()));
+        vm.enable_coverage();
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn lcov_lines_and_branches() {
+        let vm = covered(SOURCE);
+        let lcov = vm
+            .coverage()
+            .unwrap()
+            .lcov(vm.bin(), vm.debug_info().unwrap());
+        let expected = [
+            "TN:",
+            "SF:test.s",
+            "BRDA:2,1,0,1",
+            "BRDA:2,1,1,0",
+            "BRDA:3,2,0,-",
+            "BRDA:3,2,1,-",
+            "BRDA:6,4,0,0",
+            "BRDA:6,4,1,1",
+            "BRF:6",
+            "BRH:2",
+            "DA:1,1",
+            "DA:2,1",
+            "DA:3,0",
+            "DA:5,1",
+            "DA:6,1",
+            "DA:7,1",
+            "DA:9,1",
+            "LF:7",
+            "LH:6",
+            "end_of_record",
+        ];
+        assert_eq!(lcov.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn summary_lists_what_never_ran() {
+        let vm = covered(SOURCE);
+        let summary = vm
+            .coverage()
+            .unwrap()
+            .summary(vm.bin(), vm.debug_info().unwrap());
+        let expected = "test.s: 6/7 lines (85.7%), 2/6 branches
+  not run: 3
+  line 2: the jump is always taken
+  line 6: the jump is never taken
+";
+        assert_eq!(summary, expected);
+        assert_eq!(ranges(&[1, 3, 4, 5, 7, 8]), "1, 3-5, 7-8");
+    }
+}
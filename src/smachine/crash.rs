@@ -0,0 +1,290 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use super::compiler::{self, Program};
+use super::vm::VM;
+
+const DUMP_VERSION: u64 = 1;
+const VM_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Where crash dumps go, `SMACHINE_DUMP_DIR` or a directory in the system temp dir
+pub fn dump_dir() -> PathBuf {
+    env::var_os("SMACHINE_DUMP_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("smachine-dumps"))
+}
+
+/// The state of a vm whose instruction at `pc` failed
+pub fn crash_dump(vm: &VM, pc: usize) -> Value {
+    let debug = vm.debug_info();
+    let frames: Vec<Value> = vm
+        .frames()
+        .iter()
+        .map(|frame| {
+            json!({
+                "call_pc": frame.call_pc,
+                "proc_pc": frame.proc_pc,
+                "sp": frame.sp,
+                "name": debug.and_then(|debug| debug.label_at(frame.proc_pc)),
+            })
+        })
+        .collect();
+    // with the instruction so the dump reads without the program
+    let recent: Vec<Value> = vm
+        .recent_pcs()
+        .into_iter()
+        .map(|pc| {
+            json!({
+                "pc": pc,
+                "instruction": vm.bin().get(pc).map(|binary| binary.describe()),
+            })
+        })
+        .collect();
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+
+    json!({
+        "version": DUMP_VERSION,
+        "vm_version": VM_VERSION,
+        "time": time,
+        "error": vm.last_error().unwrap_or("the vm could not execute the instruction"),
+        "pc": pc,
+        "instruction": vm.bin().get(pc).map(|binary| binary.describe()),
+        "location": vm.describe_pc(pc),
+        "sp": vm.sp(),
+        "stack": &vm.stack()[..vm.sp().min(vm.stack().len())],
+        "memory": vm.memory(),
+        "rodata_len": vm.rodata_len(),
+        "frames": frames,
+        "recent": recent,
+        "program_hash": format!("{:016x}", compiler::program_hash(vm.bin())),
+        "program_len": vm.bin().len(),
+    })
+}
+
+/// Writes the dump of a fault at `pc`, returns the file it went to
+pub fn write_crash_dump(vm: &VM, pc: usize) -> io::Result<PathBuf> {
+    let dir = dump_dir();
+    fs::create_dir_all(&dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis());
+    let path = dir.join(format!("crash-{}-{}.json", std::process::id(), millis));
+    let dump = serde_json::to_string_pretty(&crash_dump(vm, pc))?;
+    fs::write(&path, dump)?;
+    Ok(path)
+}
+
+pub fn read_dump(path: &str) -> io::Result<Value> {
+    let text = fs::read_to_string(path)?;
+    let dump: Value = serde_json::from_str(&text)?;
+    if dump["version"].as_u64() != Some(DUMP_VERSION) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a crash dump this version can read", path),
+        ));
+    }
+    Ok(dump)
+}
+
+fn number(value: &Value) -> Option<usize> {
+    value.as_u64().map(|value| value as usize)
+}
+
+/// Readable version of a dump, instructions are looked up in `program` when given
+pub fn inspect(dump: &Value, program: Option<&Program>) -> String {
+    let mut out = String::new();
+    let debug = program.and_then(|program| program.debug.as_ref());
+    let instruction = |pc: usize, fallback: &Value| -> String {
+        let code = program.and_then(|program| program.code.get(pc));
+        let text = match code {
+            Some(binary) => binary.describe(),
+            None => fallback.as_str().unwrap_or_default().to_string(),
+        };
+        match debug.and_then(|debug| debug.location(pc)) {
+            Some(location) => format!("{:>5}  {:<16} ; {}", pc, text, location),
+            None => format!("{:>5}  {}", pc, text).trim_end().to_string(),
+        }
+    };
+
+    let _ = writeln!(
+        out,
+        "Crash dump from simplestackmachine {}",
+        dump["vm_version"].as_str().unwrap_or("?")
+    );
+    if let Some(program) = program {
+        let hash = format!("{:016x}", compiler::program_hash(&program.code));
+        if dump["program_hash"].as_str() != Some(hash.as_str()) {
+            let _ = writeln!(
+                out,
+                "WARNING: the dump is from program {}, this one is {}",
+                dump["program_hash"].as_str().unwrap_or("?"),
+                hash
+            );
+        }
+    }
+    let _ = writeln!(out, "error: {}", dump["error"].as_str().unwrap_or("?"));
+    // the program's debug info may know more than the vm that crashed did
+    let location = number(&dump["pc"]).and_then(|pc| {
+        let location = debug?.location(pc)?;
+        Some(format!(
+            "{} (instruction {}: {})",
+            location,
+            pc,
+            dump["instruction"].as_str()?
+        ))
+    });
+    let _ = writeln!(
+        out,
+        "at {}",
+        location
+            .as_deref()
+            .or(dump["location"].as_str())
+            .unwrap_or("?")
+    );
+
+    let stack = dump["stack"].as_array().cloned().unwrap_or_default();
+    let _ = writeln!(out, "\nstack, sp {} (top first):", dump["sp"]);
+    if stack.is_empty() {
+        let _ = writeln!(out, "  (empty)");
+    }
+    for (slot, value) in stack.iter().enumerate().rev() {
+        let value = value.as_u64().unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "  [{}] {} ({:#x}, i64 {})",
+            slot, value, value, value as i64
+        );
+    }
+
+    // .rodata and .data, missing from dumps of programs without them
+    let memory = dump["memory"].as_array().cloned().unwrap_or_default();
+    if !memory.is_empty() {
+        let rodata_len = number(&dump["rodata_len"]).unwrap_or_default();
+        let _ = writeln!(out, "\ndata, {} words of .rodata:", rodata_len);
+        for (address, value) in memory.iter().enumerate() {
+            let value = value.as_u64().unwrap_or_default();
+            let section = if address < rodata_len {
+                ".rodata"
+            } else {
+                ".data"
+            };
+            let _ = writeln!(
+                out,
+                "  [{}] {:<7} {} ({:#x})",
+                address, section, value, value
+            );
+        }
+    }
+
+    let frames = dump["frames"].as_array().cloned().unwrap_or_default();
+    if !frames.is_empty() {
+        let _ = writeln!(out, "\ncall frames (innermost first):");
+        for (depth, frame) in frames.iter().enumerate().rev() {
+            let proc_pc = number(&frame["proc_pc"]).unwrap_or_default();
+            let name = frame["name"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or(format!("proc {}", proc_pc));
+            let call_pc = number(&frame["call_pc"]).unwrap_or_default();
+            let caller = match debug.and_then(|debug| debug.location(call_pc)) {
+                Some(location) => format!("{} (instruction {})", location, call_pc),
+                None => format!("instruction {}", call_pc),
+            };
+            let _ = writeln!(out, "  #{} {}, called from {}", depth, name, caller);
+        }
+    }
+
+    let recent = dump["recent"].as_array().cloned().unwrap_or_default();
+    if !recent.is_empty() {
+        let _ = writeln!(out, "\nlast instructions (oldest first):");
+        let last = recent.len() - 1;
+        for (index, entry) in recent.iter().enumerate() {
+            let Some(pc) = number(&entry["pc"]) else {
+                continue;
+            };
+            let marker = if index == last { "=>" } else { "  " };
+            let _ = writeln!(out, "{}{}", marker, instruction(pc, &entry["instruction"]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble;
+
+    // loads from past the end of .data inside f
+    const SOURCE: &str = ".data\nx: .word 5\n.text\npush 7\npush 1\ncall f\nhalt\nf:\npop\npop\npop\npush 99\nload\nret\n";
+
+    fn crashed() -> (Program, VM) {
+        let program = assemble(SOURCE, "test.s").unwrap();
+        let mut vm = VM::from_program(program.clone());
+        vm.set_output(Box::new(io::sink()));
+        vm.set_jit_enabled(false);
+        assert!(!vm.execute());
+        (program, vm)
+    }
+
+    #[test]
+    fn dumps_have_the_state_of_the_fault() {
+        let (_, vm) = crashed();
+        let dump = crash_dump(&vm, vm.pc());
+        assert_eq!(dump["pc"], 8);
+        assert_eq!(dump["instruction"], "load");
+        assert_eq!(dump["location"], "test.s:13:1 (instruction 8: load)");
+        // load popped the address before it failed
+        assert_eq!(dump["stack"], json!([7]));
+        assert_eq!(dump["memory"], json!([5]));
+        assert_eq!(
+            dump["frames"],
+            json!([{ "call_pc": 2, "proc_pc": 4, "sp": 2, "name": "f" }])
+        );
+        let recent: Vec<u64> = dump["recent"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["pc"].as_u64().unwrap())
+            .collect();
+        assert_eq!(recent, [0, 1, 2, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            dump["program_hash"].as_str().unwrap(),
+            format!("{:016x}", compiler::program_hash(vm.bin()))
+        );
+    }
+
+    #[test]
+    fn inspect_reads_the_dump_back() {
+        let (program, vm) = crashed();
+        let path = env::temp_dir().join(format!("smachine-crash-test-{}.json", std::process::id()));
+        fs::write(&path, crash_dump(&vm, vm.pc()).to_string()).unwrap();
+        let dump = read_dump(path.to_str().unwrap()).unwrap();
+
+        let text = inspect(&dump, Some(&program));
+        assert!(text.contains("at test.s:13:1 (instruction 8: load)"));
+        assert!(text.contains("stack, sp 1 (top first):\n  [0] 7 (0x7, i64 7)\n"));
+        assert!(text.contains("  [0] .data   5 (0x5)"));
+        assert!(text.contains("  #0 f, called from test.s:6:1 (instruction 2)"));
+        assert!(text.contains("=>    8  load             ; test.s:13:1"));
+        assert!(!text.contains("WARNING"));
+
+        // without the program the dump still has the instructions
+        let text = inspect(&dump, None);
+        assert!(text.contains("=>    8  load\n"));
+        let other = assemble("push 1\nhalt\n", "other.s").unwrap();
+        assert!(inspect(&dump, Some(&other)).contains("WARNING: the dump is from program"));
+
+        fs::write(&path, "{\"version\": 0}").unwrap();
+        let err = read_dump(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let _ = fs::remove_file(&path);
+    }
+}
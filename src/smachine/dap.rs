@@ -0,0 +1,651 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use serde_json::{Value, json};
+
+use super::compiler;
+use super::debugger::{DEFAULT_HISTORY, OutputBuffer, Session, Stop};
+use super::vm::VM;
+
+const THREAD_ID: u64 = 1;
+const STACK_REF: u64 = 1;
+const REGISTERS_REF: u64 = 2;
+const FRAMES_REF: u64 = 3;
+
+type Message = io::Result<Option<Value>>;
+
+// requests read while the program runs, checked between chunks of instructions
+#[derive(Default)]
+struct Inbox {
+    receiver: Option<Receiver<Message>>,
+    pending: VecDeque<Message>,
+    // the pause request to answer once the program stopped
+    pause: Option<Value>,
+}
+
+impl Inbox {
+    // queues what arrived so far, true if the program has to stop for it
+    fn poll(&mut self) -> bool {
+        let Some(receiver) = &self.receiver else {
+            return false;
+        };
+        let mut stop = false;
+        while let Ok(message) = receiver.try_recv() {
+            match &message {
+                Ok(Some(request)) => match request["command"].as_str() {
+                    Some("pause") => {
+                        self.pause = Some(request.clone());
+                        stop = true;
+                        continue;
+                    }
+                    Some("disconnect") | Some("terminate") => stop = true,
+                    // anything else waits until the program stopped
+                    _ => {}
+                },
+                _ => stop = true,
+            }
+            self.pending.push_back(message);
+        }
+        stop
+    }
+
+    fn next(&mut self) -> Option<Message> {
+        match self.pending.pop_front() {
+            Some(message) => Some(message),
+            None => self.receiver.as_ref()?.recv().ok(),
+        }
+    }
+}
+
+/// Debug Adapter Protocol server, talks to an editor over stdin/stdout
+/// and drives the vm through a debugger `Session`.
+pub struct DapServer<W: Write> {
+    writer: W,
+    seq: u64,
+    vm: Option<VM>,
+    session: Session,
+    // breakpoints are set per source file as (id, line),
+    // they are resolved again once a program is loaded
+    breakpoints: HashMap<String, Vec<(u64, u64)>>,
+    next_breakpoint: u64,
+    // relative source paths in the debug info are taken from here
+    cwd: PathBuf,
+    stop_on_entry: bool,
+    output: OutputBuffer,
+    inbox: Rc<RefCell<Inbox>>,
+}
+
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length",
+        ));
+    };
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(writer: W) -> DapServer<W> {
+        Self {
+            writer,
+            seq: 1,
+            vm: None,
+            session: Session::default(),
+            breakpoints: HashMap::new(),
+            next_breakpoint: 1,
+            cwd: env::current_dir().unwrap_or_default(),
+            stop_on_entry: false,
+            output: OutputBuffer::default(),
+            inbox: Rc::default(),
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        let _ = write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = self.writer.flush();
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn flush_output(&mut self) {
+        let output = self.output.take();
+        if !output.is_empty() {
+            let text = String::from_utf8_lossy(&output).to_string();
+            self.event("output", json!({ "category": "stdout", "output": text }));
+        }
+    }
+
+    fn launch(&mut self, request: &Value) {
+        let arguments = &request["arguments"];
+        let Some(path) = arguments["program"].as_str() else {
+            self.fail(request, "launch needs a program");
+            return;
+        };
+
+        let program = compiler::load_file(path);
+        let Some(program) = program else {
+            self.fail(request, &format!("could not load {}", path));
+            return;
+        };
+
+        let mut vm = VM::from_program(program);
+        // the jit would run whole procedures and loops in one step
+        vm.set_jit_enabled(false);
+        vm.set_history(Some(DEFAULT_HISTORY));
+        vm.set_output(Box::new(self.output.clone()));
+        self.vm = Some(vm);
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        if let Some(cwd) = arguments["cwd"].as_str() {
+            self.cwd = PathBuf::from(cwd);
+        }
+        self.respond(request, json!({}));
+
+        // breakpoints set before the launch could not be resolved yet
+        let paths: Vec<String> = self.breakpoints.keys().cloned().collect();
+        for path in paths {
+            for breakpoint in self.resolve_breakpoints(&path) {
+                if breakpoint["verified"] == true {
+                    self.event(
+                        "breakpoint",
+                        json!({ "reason": "changed", "breakpoint": breakpoint }),
+                    );
+                }
+            }
+        }
+        self.event("initialized", json!({}));
+    }
+
+    // updates the session with the breakpoints of a file, returns them for the client
+    fn resolve_breakpoints(&mut self, path: &str) -> Vec<Value> {
+        let debug = self.vm.as_ref().and_then(|vm| vm.debug_info());
+        let breakpoints: Vec<(Value, Option<usize>)> = self
+            .breakpoints
+            .get(path)
+            .into_iter()
+            .flatten()
+            .map(|(id, line)| {
+                let pc = debug.and_then(|debug| debug.pc_for_line(path, *line as u32));
+                let breakpoint = json!({ "id": id, "verified": pc.is_some(), "line": line });
+                (breakpoint, pc)
+            })
+            .collect();
+
+        self.session.breakpoints = self
+            .breakpoints
+            .iter()
+            .flat_map(|(path, lines)| lines.iter().map(move |(_, line)| (path, *line)))
+            .filter_map(|(path, line)| debug?.pc_for_line(path, line as u32))
+            .collect();
+        breakpoints
+            .into_iter()
+            .map(|(breakpoint, _)| breakpoint)
+            .collect()
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) {
+        let arguments = &request["arguments"];
+        let path = arguments["source"]["path"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let mut lines = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            lines.push((
+                self.next_breakpoint,
+                breakpoint["line"].as_u64().unwrap_or(0),
+            ));
+            self.next_breakpoint += 1;
+        }
+        self.breakpoints.insert(path.clone(), lines);
+
+        let breakpoints = self.resolve_breakpoints(&path);
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    // the pc a frame is at, the innermost one first
+    fn frame_pcs(&self, vm: &VM) -> Vec<usize> {
+        let top = self.session.fault().unwrap_or(vm.pc());
+        std::iter::once(top)
+            .chain(vm.frames().iter().rev().map(|frame| frame.call_pc))
+            .collect()
+    }
+
+    fn stack_trace(&mut self, request: &Value) {
+        let Some(vm) = &self.vm else {
+            self.fail(request, "no program");
+            return;
+        };
+
+        let debug = vm.debug_info();
+        let pcs = self.frame_pcs(vm);
+        let frames: Vec<Value> = pcs
+            .iter()
+            .enumerate()
+            .map(|(id, pc)| {
+                // frame i runs inside the procedure entered by the i-th newest call
+                let proc_pc = vm.frames().iter().rev().nth(id).map(|frame| frame.proc_pc);
+                let name = match proc_pc {
+                    Some(proc_pc) => debug
+                        .and_then(|debug| debug.label_at(proc_pc))
+                        .map(String::from)
+                        .unwrap_or(format!("proc {}", proc_pc)),
+                    None => String::from("main"),
+                };
+
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": pc.to_string(),
+                });
+                if let Some((file, pos)) = debug.and_then(|debug| debug.position(*pc)) {
+                    frame["source"] = json!({ "path": self.cwd.join(file) });
+                    frame["line"] = json!(pos.line);
+                    frame["column"] = json!(pos.column);
+                }
+                frame
+            })
+            .collect();
+
+        let total = frames.len();
+        self.respond(
+            request,
+            json!({ "stackFrames": frames, "totalFrames": total }),
+        );
+    }
+
+    fn variables(&mut self, request: &Value) {
+        let Some(vm) = &self.vm else {
+            self.fail(request, "no program");
+            return;
+        };
+
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match request["arguments"]["variablesReference"].as_u64() {
+            Some(STACK_REF) => vm.stack()[..vm.sp()]
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(slot, value)| {
+                    variable(
+                        format!("[{}]", slot),
+                        format!("{} ({:#x}, i64 {})", value, value, *value as i64),
+                    )
+                })
+                .collect(),
+            Some(REGISTERS_REF) => vec![
+                variable(String::from("pc"), self.frame_pcs(vm)[0].to_string()),
+                variable(String::from("sp"), vm.sp().to_string()),
+            ],
+            Some(FRAMES_REF) => vm
+                .frames()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(depth, frame)| {
+                    variable(
+                        format!("#{}", depth),
+                        format!(
+                            "proc {} called from {}, sp {}",
+                            frame.proc_pc,
+                            vm.describe_pc(frame.call_pc),
+                            frame.sp
+                        ),
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.respond(request, json!({ "variables": variables }));
+    }
+
+    fn exception_info(&mut self, request: &Value) {
+        let description = self
+            .vm
+            .as_ref()
+            .and_then(|vm| vm.last_error())
+            .unwrap_or("the vm could not execute the instruction")
+            .to_string();
+        self.respond(
+            request,
+            json!({ "exceptionId": "vm-fault", "description": description, "breakMode": "always" }),
+        );
+    }
+
+    fn report(&mut self, stop: Stop) {
+        self.flush_output();
+        match stop {
+            Stop::Breakpoint => self.event(
+                "stopped",
+                json!({ "reason": "breakpoint", "threadId": THREAD_ID }),
+            ),
+            Stop::Watchpoint(id) => {
+                let text = self
+                    .vm
+                    .as_ref()
+                    .and_then(|vm| vm.watchpoints().get(&id))
+                    .map(|watch| format!("watchpoint {}: {}", id, watch))
+                    .unwrap_or_default();
+                self.event(
+                    "stopped",
+                    json!({ "reason": "data breakpoint", "description": text, "threadId": THREAD_ID }),
+                )
+            }
+            Stop::Done => self.event(
+                "stopped",
+                json!({ "reason": "step", "threadId": THREAD_ID }),
+            ),
+            Stop::HistoryStart => self.event(
+                "stopped",
+                json!({ "reason": "step", "description": "no more history", "threadId": THREAD_ID }),
+            ),
+            Stop::Paused => {
+                let pause = self.inbox.borrow_mut().pause.take();
+                if let Some(pause) = pause {
+                    self.respond(&pause, json!({}));
+                }
+                self.event(
+                    "stopped",
+                    json!({ "reason": "pause", "threadId": THREAD_ID }),
+                );
+            }
+            Stop::Finished => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            }
+            Stop::Fault(pc) => {
+                let vm = self.vm.as_ref();
+                let text = vm
+                    .and_then(|vm| vm.last_error())
+                    .unwrap_or("vm fault")
+                    .to_string();
+                let location = vm.map(|vm| vm.describe_pc(pc)).unwrap_or_default();
+                let new = self.session.take_new_fault();
+                let dump = match vm.filter(|_| new).map(|vm| vm.write_crash_dump(pc)) {
+                    Some(Ok(path)) => format!("crash dump written to {}\n", path.display()),
+                    Some(Err(err)) => format!("could not write the crash dump: {}\n", err),
+                    None => String::new(),
+                };
+                if !dump.is_empty() {
+                    self.event("output", json!({ "category": "console", "output": dump }));
+                }
+                self.event(
+                    "stopped",
+                    json!({
+                        "reason": "exception",
+                        "description": format!("{} at {}", text, location),
+                        "text": text,
+                        "threadId": THREAD_ID,
+                    }),
+                );
+            }
+        }
+    }
+
+    // runs one of the execution requests against the vm
+    fn execute(&mut self, request: &Value, command: &str) {
+        if self.vm.is_none() {
+            self.fail(request, "no program");
+            return;
+        }
+
+        let body = if command == "continue" {
+            json!({ "allThreadsContinued": true })
+        } else {
+            json!({})
+        };
+        self.respond(request, body);
+        self.resume(command);
+    }
+
+    fn resume(&mut self, command: &str) {
+        let Some(mut vm) = self.vm.take() else {
+            return;
+        };
+        let stop = match command {
+            "continue" => self.session.cont(&mut vm),
+            "stepBack" => self.session.reverse_step(&mut vm),
+            "reverseContinue" => self.session.reverse_cont(&mut vm),
+            "next" => self.session.next(&mut vm),
+            "stepIn" => self.session.step(&mut vm),
+            // outside of a procedure finishing means running to the end
+            _ => match self.session.finish(&mut vm) {
+                Some(stop) => stop,
+                None => self.session.cont(&mut vm),
+            },
+        };
+        self.vm = Some(vm);
+        self.report(stop);
+    }
+
+    // returns false once the client is done
+    fn handle(&mut self, request: Value) -> bool {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        match command.as_str() {
+            "initialize" => {
+                self.respond(
+                    &request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsExceptionInfoRequest": true,
+                        "supportsStepBack": true,
+                    }),
+                );
+            }
+            "launch" => self.launch(&request),
+            "setBreakpoints" => self.set_breakpoints(&request),
+            "setExceptionBreakpoints" => self.respond(&request, json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.respond(&request, json!({}));
+                if self.stop_on_entry {
+                    self.event("stopped", json!({ "reason": "entry", "threadId": THREAD_ID }));
+                } else {
+                    self.resume("continue");
+                }
+            }
+            "threads" => self.respond(
+                &request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            ),
+            "stackTrace" => self.stack_trace(&request),
+            "scopes" => self.respond(
+                &request,
+                json!({ "scopes": [
+                    { "name": "Operand stack", "variablesReference": STACK_REF, "expensive": false },
+                    { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                    { "name": "Call frames", "variablesReference": FRAMES_REF, "expensive": false },
+                ] }),
+            ),
+            "variables" => self.variables(&request),
+            "exceptionInfo" => self.exception_info(&request),
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => self.execute(&request, &command),
+            // a running program is paused through the inbox, here it is already stopped
+            "pause" => self.respond(&request, json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(&request, json!({}));
+                return false;
+            }
+            _ => self.fail(&request, &format!("unsupported request {}", command)),
+        }
+        true
+    }
+
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, mut reader: R) -> io::Result<()> {
+        // requests are read on their own thread so a running program can be paused
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                let message = read_message(&mut reader);
+                let done = !matches!(message, Ok(Some(_)));
+                if sender.send(message).is_err() || done {
+                    break;
+                }
+            }
+        });
+        self.inbox.borrow_mut().receiver = Some(receiver);
+        let inbox = self.inbox.clone();
+        self.session.interrupt = Some(Box::new(move || inbox.borrow_mut().poll()));
+
+        loop {
+            let message = self.inbox.borrow_mut().next();
+            match message {
+                Some(Ok(Some(request))) => {
+                    if !self.handle(request) {
+                        break;
+                    }
+                }
+                Some(Err(err)) => return Err(err),
+                Some(Ok(None)) | None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the adapter on stdin/stdout until the client disconnects
+pub fn serve_stdio() -> io::Result<()> {
+    let mut server = DapServer::new(io::stdout());
+    server.serve(io::BufReader::new(io::stdin()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn frame(request: Value) -> Vec<u8> {
+        let body = request.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+
+    #[test]
+    fn launch_break_and_stack_trace() {
+        let dir = std::env::temp_dir().join(format!("smachine-dap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("loop.s");
+        fs::write(
+            &path,
+            "push 3\nloop:\npush 1\nusub64\ndup\njnz loop\nhalt\n",
+        )
+        .unwrap();
+        let path = path.display().to_string();
+
+        let requests = [
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+            json!({ "seq": 2, "type": "request", "command": "setBreakpoints", "arguments": {
+                "source": { "path": path }, "breakpoints": [{ "line": 4 }, { "line": 40 }] } }),
+            json!({ "seq": 3, "type": "request", "command": "launch", "arguments": {
+                "program": path, "cwd": dir } }),
+            json!({ "seq": 4, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 5, "type": "request", "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 6, "type": "request", "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 7, "type": "request", "command": "setBreakpoints", "arguments": {
+                "source": { "path": path }, "breakpoints": [] } }),
+            json!({ "seq": 8, "type": "request", "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 9, "type": "request", "command": "disconnect" }),
+        ];
+        let input: Vec<u8> = requests.into_iter().flat_map(frame).collect();
+        let mut server = DapServer::new(Vec::new());
+        server.serve(io::Cursor::new(input)).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut output = &server.writer[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        let response = |seq: u64| {
+            messages
+                .iter()
+                .find(|message| message["type"] == "response" && message["request_seq"] == seq)
+                .unwrap()
+        };
+        let events: Vec<&str> = messages
+            .iter()
+            .filter(|message| message["type"] == "event")
+            .map(|message| message["event"].as_str().unwrap())
+            .collect();
+
+        // line 40 has no code, it is never verified
+        let breakpoints = &response(2)["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], false);
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert!(response(3)["success"].as_bool().unwrap());
+        assert_eq!(
+            events,
+            [
+                "breakpoint",
+                "initialized",
+                "stopped",
+                "stopped",
+                "exited",
+                "terminated"
+            ]
+        );
+
+        let frames = &response(5)["body"]["stackFrames"];
+        assert_eq!(frames.as_array().unwrap().len(), 1);
+        assert_eq!(frames[0]["line"], 4);
+        assert_eq!(frames[0]["instructionPointerReference"], "2");
+        assert_eq!(frames[0]["source"]["path"], path);
+        assert!(response(9)["success"].as_bool().unwrap());
+    }
+}
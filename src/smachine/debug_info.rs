@@ -0,0 +1,245 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+/// Where an instruction came from, line and column start at 1, 0 means unknown
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourcePos {
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Maps instruction indices back to the assembly source.
+/// `positions` has one entry per instruction.
+#[derive(Clone, Debug, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub positions: Vec<SourcePos>,
+    pub labels: Vec<(String, usize)>,
+}
+
+pub fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads `len` bytes, the buffer only grows as far as the input goes
+/// so a corrupt length can not make it allocate more than the file holds
+pub fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, the input ends after {}", len, buf.len()),
+        ));
+    }
+    Ok(buf)
+}
+
+pub fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_u32(reader)?;
+    let buf = read_bytes(reader, len as u64)?;
+    String::from_utf8(buf).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+pub fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<()> {
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+impl DebugInfo {
+    pub fn position(&self, pc: usize) -> Option<(&str, SourcePos)> {
+        let pos = self.positions.get(pc)?;
+        if pos.line == 0 {
+            return None;
+        }
+        Some((self.files.get(pos.file as usize)?, *pos))
+    }
+
+    /// `file:line:column` of the instruction at pc
+    pub fn location(&self, pc: usize) -> Option<String> {
+        let (file, pos) = self.position(pc)?;
+        Some(format!("{}:{}:{}", file, pos.line, pos.column))
+    }
+
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, pos)| *pos == pc)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, pos)| *pos)
+    }
+
+    // the indices of the files `matches` accepts, None when they are different files
+    fn unique_files(&self, matches: impl Fn(&Path) -> bool) -> Option<Vec<u32>> {
+        let found: Vec<u32> = (0..self.files.len() as u32)
+            .filter(|index| matches(Path::new(&self.files[*index as usize])))
+            .collect();
+        let first = &self.files[*found.first()? as usize];
+        // linked objects can list the same file more than once
+        found
+            .iter()
+            .all(|index| self.files[*index as usize] == *first)
+            .then_some(found)
+    }
+
+    /// First instruction generated from a line. `file` is matched against the whole
+    /// path first, then as the end of a path, then as just the file name, the last two
+    /// only when a single file matches.
+    pub fn pc_for_line(&self, file: &str, line: u32) -> Option<usize> {
+        let wanted = Path::new(file);
+        let files = self
+            .unique_files(|name| name == wanted)
+            .or_else(|| self.unique_files(|name| wanted.ends_with(name) || name.ends_with(wanted)))
+            .or_else(|| self.unique_files(|name| name.file_name() == wanted.file_name()))?;
+        self.positions
+            .iter()
+            .position(|pos| pos.line == line && files.contains(&pos.file))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for file in &self.files {
+            write_string(writer, file)?;
+        }
+
+        writer.write_all(&(self.positions.len() as u64).to_le_bytes())?;
+        for pos in &self.positions {
+            writer.write_all(&pos.file.to_le_bytes())?;
+            writer.write_all(&pos.line.to_le_bytes())?;
+            writer.write_all(&pos.column.to_le_bytes())?;
+        }
+
+        writer.write_all(&(self.labels.len() as u32).to_le_bytes())?;
+        for (name, pos) in &self.labels {
+            write_string(writer, name)?;
+            writer.write_all(&(*pos as u64).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<DebugInfo> {
+        let mut info = DebugInfo::default();
+        for _ in 0..read_u32(reader)? {
+            info.files.push(read_string(reader)?);
+        }
+
+        for _ in 0..read_u64(reader)? {
+            info.positions.push(SourcePos {
+                file: read_u32(reader)?,
+                line: read_u32(reader)?,
+                column: read_u32(reader)?,
+            });
+        }
+
+        for _ in 0..read_u32(reader)? {
+            let name = read_string(reader)?;
+            info.labels.push((name, read_u64(reader)? as usize));
+        }
+        Ok(info)
+    }
+
+    /// Keeps the info in sync with code that was rearranged,
+    /// `origin` holds the old index of every instruction that is left
+    pub fn remap(&self, origin: &[usize]) -> DebugInfo {
+        let positions = origin
+            .iter()
+            .map(|old| self.positions.get(*old).copied().unwrap_or_default())
+            .collect();
+        // a label now points to the first instruction left at or after it
+        let labels = self
+            .labels
+            .iter()
+            .map(|(name, pos)| {
+                let new = origin
+                    .iter()
+                    .position(|old| old >= pos)
+                    .unwrap_or(origin.len());
+                (name.clone(), new)
+            })
+            .collect();
+
+        DebugInfo {
+            files: self.files.clone(),
+            positions,
+            labels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(files: &[&str]) -> DebugInfo {
+        DebugInfo {
+            files: files.iter().map(|file| file.to_string()).collect(),
+            positions: (0..files.len() as u32)
+                .map(|file| SourcePos {
+                    file,
+                    line: 3,
+                    column: 1,
+                })
+                .collect(),
+            labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn same_file_names_in_different_directories() {
+        let debug = info(&["a/main.s", "b/main.s", "lib/util.s"]);
+        assert_eq!(debug.pc_for_line("a/main.s", 3), Some(0));
+        assert_eq!(debug.pc_for_line("b/main.s", 3), Some(1));
+        assert_eq!(debug.pc_for_line("/home/me/project/b/main.s", 3), Some(1));
+        // the bare name could be either of them
+        assert_eq!(debug.pc_for_line("main.s", 3), None);
+        assert_eq!(debug.pc_for_line("util.s", 3), Some(2));
+        assert_eq!(debug.pc_for_line("other/util.s", 3), Some(2));
+        assert_eq!(debug.pc_for_line("util.s", 4), None);
+    }
+
+    #[test]
+    fn files_listed_twice_are_one_file() {
+        let debug = info(&["main.s", "defs.inc", "other.s", "defs.inc"]);
+        assert_eq!(debug.pc_for_line("defs.inc", 3), Some(1));
+        assert_eq!(debug.pc_for_line("/src/defs.inc", 3), Some(1));
+    }
+
+    #[test]
+    fn lengths_are_bounded_by_the_input() {
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"abc");
+        assert!(read_string(&mut bytes.as_slice()).is_err());
+        assert!(read_bytes(&mut [1u8, 2, 3].as_slice(), u64::MAX).is_err());
+        assert_eq!(
+            read_bytes(&mut [1u8, 2, 3].as_slice(), 2).unwrap(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut debug = info(&["a/main.s", "b/main.s"]);
+        debug.labels.push((String::from("main"), 1));
+        let mut bytes = Vec::new();
+        debug.write_to(&mut bytes).unwrap();
+        let read = DebugInfo::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.files, debug.files);
+        assert_eq!(read.positions, debug.positions);
+        assert_eq!(read.labels, debug.labels);
+    }
+}
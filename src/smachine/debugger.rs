@@ -0,0 +1,779 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use super::compiler::TokenType;
+use super::debug_info::DebugInfo;
+use super::vm::VM;
+use super::watch::{self, Slot, WatchKind, Watchpoint};
+
+const HELP: &str = "\
+commands:
+  break <pc|label|file:line>
+                         set a breakpoint (b)
+  delete <pc|label|file:line>
+                         clear a breakpoint, all of them without an argument (d)
+  breaks                 list breakpoints
+  watch <slot|mem[address]|condition>
+                         stop when a stack slot or data word is written or a condition
+                         becomes true, a slot is an index or relative to sp like sp-1,
+                         a condition is like `sp > 8`, `stack[2] == 42` or `mem[3] != 0`
+  rwatch <slot|mem[address]>
+                         stop when a stack slot or data word is read
+  awatch <slot|mem[address]>
+                         stop when a stack slot or data word is read or written
+  unwatch <n>            remove a watchpoint, all of them without an argument
+  watches                list watchpoints
+  step                   execute one instruction (s)
+  next                   like step, but runs over a call (n)
+  finish                 run until the current procedure returns
+  continue               run until a breakpoint or the end (c)
+  reverse-step           undo the last instruction (rs)
+  reverse-continue       run backwards until a breakpoint or the start of the history (rc)
+  goto <n>               move to where n instructions have run, backwards or forwards
+  history [size]         show or set how many instructions can be undone
+  stack                  print the live part of the stack
+  memory                 print the .rodata and .data words with their addresses
+  regs                   print pc and sp
+  frames                 print the call frames (bt)
+  x <slot|mem[address]> [u64|i64|f64|char|hex]
+                         examine a stack slot or a data word, negative slots count from sp
+  list                   show the code around pc (l)
+  quit                   leave the debugger (q)
+an empty line repeats the last command";
+
+/// Why execution stopped
+pub enum Stop {
+    Breakpoint,
+    // the number of the watchpoint that triggered
+    Watchpoint(usize),
+    Finished,
+    // the pc of the instruction that failed
+    Fault(usize),
+    Done,
+    // stepping backwards ran out of recorded history
+    HistoryStart,
+    // `Session::interrupt` asked for the program to stop
+    Paused,
+}
+
+/// Instructions the debuggers can step back over by default
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+/// Instructions run between two checks of `Session::interrupt`
+pub const INTERRUPT_INTERVAL: u64 = 10_000;
+
+/// Steps a vm under the control of breakpoints,
+/// shared by the command line debugger and the editor/gdb frontends
+#[derive(Default)]
+pub struct Session {
+    pub breakpoints: BTreeSet<usize>,
+    /// Polled while running, returning true pauses the program
+    pub interrupt: Option<Box<dyn FnMut() -> bool>>,
+    fault: Option<usize>,
+    // the crash dump of the current fault is written
+    dumped: bool,
+}
+
+impl Session {
+    /// The instruction that faulted, once the program can not continue
+    pub fn fault(&self) -> Option<usize> {
+        self.fault
+    }
+
+    /// True the first time it is asked after a fault, every step after it stops
+    /// at the same fault again but its crash dump only has to be written once
+    pub fn take_new_fault(&mut self) -> bool {
+        let new = self.fault.is_some() && !self.dumped;
+        self.dumped |= new;
+        new
+    }
+
+    pub fn step(&mut self, vm: &mut VM) -> Stop {
+        if let Some(pc) = self.fault {
+            return Stop::Fault(pc);
+        }
+        if !vm.is_running() {
+            return Stop::Finished;
+        }
+
+        let pc = vm.pc();
+        if vm.step().is_none() {
+            vm.take_watch_hit();
+            self.fault = Some(pc);
+            self.dumped = false;
+            return Stop::Fault(pc);
+        }
+
+        let watch_hit = vm.take_watch_hit();
+        if !vm.is_running() {
+            Stop::Finished
+        } else if let Some(id) = watch_hit {
+            Stop::Watchpoint(id)
+        } else if self.breakpoints.contains(&vm.pc()) {
+            Stop::Breakpoint
+        } else {
+            Stop::Done
+        }
+    }
+
+    fn interrupted(&mut self, steps: u64) -> bool {
+        steps.is_multiple_of(INTERRUPT_INTERVAL)
+            && self.interrupt.as_mut().is_some_and(|interrupt| interrupt())
+    }
+
+    /// Keeps stepping until `done` says so, a breakpoint is hit or the program stops
+    pub fn run_until(&mut self, vm: &mut VM, done: impl Fn(&VM) -> bool) -> Stop {
+        let mut steps = 0;
+        loop {
+            match self.step(vm) {
+                Stop::Done if !done(vm) => {
+                    steps += 1;
+                    if self.interrupted(steps) {
+                        return Stop::Paused;
+                    }
+                }
+                stop => return stop,
+            }
+        }
+    }
+
+    pub fn cont(&mut self, vm: &mut VM) -> Stop {
+        self.run_until(vm, |_| false)
+    }
+
+    /// Steps one instruction, running a `call` until it returns
+    pub fn next(&mut self, vm: &mut VM) -> Stop {
+        let depth = vm.frames().len();
+        match vm
+            .bin()
+            .get(vm.pc())
+            .map(|binary| TokenType::from(binary.opcode))
+        {
+            Some(TokenType::Call) => {
+                let after = vm.pc() + 1;
+                self.run_until(vm, |vm| vm.frames().len() <= depth && vm.pc() == after)
+            }
+            _ => self.step(vm),
+        }
+    }
+
+    pub fn reverse_step(&mut self, vm: &mut VM) -> Stop {
+        if !vm.step_back() {
+            return Stop::HistoryStart;
+        }
+        // going back over the faulting instruction makes the program usable again
+        self.fault = None;
+        if self.breakpoints.contains(&vm.pc()) {
+            Stop::Breakpoint
+        } else {
+            Stop::Done
+        }
+    }
+
+    /// Steps backwards until a breakpoint or the start of the history
+    pub fn reverse_cont(&mut self, vm: &mut VM) -> Stop {
+        let mut steps = 0;
+        loop {
+            match self.reverse_step(vm) {
+                Stop::Done => {
+                    steps += 1;
+                    if self.interrupted(steps) {
+                        return Stop::Paused;
+                    }
+                }
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Moves to the point where `count` instructions have run,
+    /// backwards through the history or forwards by running the program
+    pub fn goto(&mut self, vm: &mut VM, count: u64) -> Stop {
+        while vm.instruction_count() > count {
+            if !vm.step_back() {
+                return Stop::HistoryStart;
+            }
+            self.fault = None;
+        }
+        while vm.instruction_count() < count {
+            if let stop @ (Stop::Fault(_) | Stop::Finished) = self.step(vm) {
+                return stop;
+            }
+        }
+        Stop::Done
+    }
+
+    /// Runs until the current procedure returns, None outside of one
+    pub fn finish(&mut self, vm: &mut VM) -> Option<Stop> {
+        let depth = vm.frames().len();
+        if depth == 0 {
+            return None;
+        }
+        Some(self.run_until(vm, |vm| vm.frames().len() < depth))
+    }
+}
+
+/// Collects what the program prints while a frontend owns stdout,
+/// so it can be forwarded through the protocol instead
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Debugger {
+    debug: Option<DebugInfo>,
+    // source files already read, for showing the line of an instruction
+    sources: HashMap<String, Vec<String>>,
+    session: Session,
+    // size of the undo log
+    history: usize,
+}
+
+impl Debugger {
+    pub fn new(debug: Option<DebugInfo>) -> Debugger {
+        Self {
+            debug,
+            sources: HashMap::new(),
+            session: Session::default(),
+            history: DEFAULT_HISTORY,
+        }
+    }
+
+    pub fn set_history(&mut self, size: usize) {
+        self.history = size;
+    }
+
+    fn label_at(&self, pc: usize) -> Option<&str> {
+        self.debug.as_ref()?.label_at(pc)
+    }
+
+    // a pc, the name of a label or file:line
+    fn resolve(&self, arg: &str) -> Option<usize> {
+        if let Ok(pc) = arg.parse::<usize>() {
+            return Some(pc);
+        }
+
+        let debug = self.debug.as_ref()?;
+        if let Some((file, line)) = arg.rsplit_once(':')
+            && let Ok(line) = line.parse::<u32>()
+        {
+            return debug.pc_for_line(file, line);
+        }
+        debug.label(arg)
+    }
+
+    fn source_line(&mut self, pc: usize) -> Option<String> {
+        let (file, pos) = self.debug.as_ref()?.position(pc)?;
+        let file = file.to_string();
+        let lines = self.sources.entry(file.clone()).or_insert_with(|| {
+            fs::read_to_string(&file)
+                .map(|text| text.lines().map(String::from).collect())
+                .unwrap_or_default()
+        });
+        let text = lines.get(pos.line as usize - 1)?.trim();
+        Some(format!("{}:{}  {}", file, pos.line, text))
+    }
+
+    fn location(&mut self, vm: &VM, pc: usize) -> String {
+        let Some(binary) = vm.bin().get(pc) else {
+            return format!("{:>5}  <end of program>", pc);
+        };
+        let mut out = match self.label_at(pc) {
+            Some(label) => format!("{:>5}  {:<16} <{}>", pc, binary.describe(), label),
+            None => format!("{:>5}  {:<16}", pc, binary.describe()),
+        };
+        if let Some(line) = self.source_line(pc) {
+            out.push_str(&format!("  ; {}", line));
+        }
+        out.trim_end().to_string()
+    }
+
+    fn report(&mut self, vm: &VM, stop: Stop) {
+        match stop {
+            Stop::Breakpoint => {
+                println!("Breakpoint hit");
+                println!("=> {}", self.location(vm, vm.pc()));
+            }
+            Stop::Watchpoint(id) => {
+                if let Some(watch) = vm.watchpoints().get(&id) {
+                    println!("Watchpoint {} hit: {}", id, watch);
+                }
+                match vm.watchpoints()[&id] {
+                    Watchpoint::Slot(slot, _) => {
+                        // relative slots are shown for the current sp
+                        if let Some(value) = slot.resolve(vm.sp()).and_then(|i| vm.stack().get(i)) {
+                            println!("{} = {}", slot, value);
+                        }
+                    }
+                    Watchpoint::Address(address, _) => {
+                        if let Some(value) = vm.memory().get(address) {
+                            println!("mem[{}] = {}", address, value);
+                        }
+                    }
+                    Watchpoint::Condition { .. } => {}
+                }
+                println!("=> {}", self.location(vm, vm.pc()));
+            }
+            Stop::Done => println!("=> {}", self.location(vm, vm.pc())),
+            Stop::Finished => println!("Program finished"),
+            Stop::HistoryStart => {
+                println!("No more history");
+                println!("=> {}", self.location(vm, vm.pc()));
+            }
+            Stop::Paused => {
+                println!("Paused");
+                println!("=> {}", self.location(vm, vm.pc()));
+            }
+            Stop::Fault(pc) => {
+                println!("An error has occurred at:");
+                println!("=> {}", self.location(vm, pc));
+                if self.session.take_new_fault() {
+                    match vm.write_crash_dump(pc) {
+                        Ok(path) => println!("Core dumped to {}", path.display()),
+                        Err(err) => println!("Could not write the crash dump: {}", err),
+                    }
+                }
+            }
+        }
+    }
+
+    fn examine(&self, vm: &VM, args: &[&str]) {
+        let address = args
+            .first()
+            .and_then(|arg| arg.strip_prefix("mem["))
+            .and_then(|arg| arg.strip_suffix(']'));
+        let (name, value) = if let Some(address) = address {
+            let Some(value) = address
+                .parse::<usize>()
+                .ok()
+                .and_then(|address| vm.memory().get(address))
+            else {
+                println!("mem[{}] is outside of the data", address);
+                return;
+            };
+            (format!("mem[{}]", address), value)
+        } else {
+            let Some(slot) = args.first().and_then(|arg| arg.parse::<i64>().ok()) else {
+                println!("usage: x <slot|mem[address]> [u64|i64|f64|char|hex]");
+                return;
+            };
+            let index = if slot < 0 {
+                vm.sp() as i64 + slot
+            } else {
+                slot
+            };
+            let Some(value) = usize::try_from(index)
+                .ok()
+                .and_then(|index| vm.stack().get(index))
+            else {
+                println!("slot {} is outside of the stack", slot);
+                return;
+            };
+            (format!("stack[{}]", index), value)
+        };
+
+        let shown = match args.get(1).copied().unwrap_or("u64") {
+            "u64" => value.to_string(),
+            "i64" => (*value as i64).to_string(),
+            "f64" => f64::from_bits(*value).to_string(),
+            "char" => match char::from_u32(*value as u32) {
+                Some(c) => format!("{:?}", c),
+                None => String::from("<not a valid char>"),
+            },
+            "hex" => format!("{:#x}", value),
+            other => format!("unknown format {}", other),
+        };
+        println!("{} = {}", name, shown);
+    }
+
+    fn memory(&self, vm: &VM) {
+        if vm.memory().is_empty() {
+            println!("no .rodata or .data");
+        }
+        for (address, word) in vm.memory().iter().enumerate() {
+            let section = if address < vm.rodata_len() {
+                ".rodata"
+            } else {
+                ".data"
+            };
+            println!("{:>6}  {:<7}  {} ({:#x})", address, section, word, word);
+        }
+    }
+
+    fn list(&mut self, vm: &VM) {
+        let start = vm.pc().saturating_sub(3);
+        let end = (vm.pc() + 4).min(vm.bin().len());
+        for pc in start..end {
+            let marker = if pc == vm.pc() { "=>" } else { "  " };
+            let bp = if self.session.breakpoints.contains(&pc) {
+                "*"
+            } else {
+                " "
+            };
+            println!("{}{}{}", bp, marker, self.location(vm, pc));
+        }
+    }
+
+    // returns false when the debugger should exit
+    fn command(&mut self, vm: &mut VM, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            return true;
+        };
+
+        match *command {
+            "b" | "break" => match args.first().and_then(|arg| self.resolve(arg)) {
+                Some(pc) if pc < vm.bin().len() => {
+                    self.session.breakpoints.insert(pc);
+                    println!("Breakpoint at {}", self.location(vm, pc));
+                }
+                _ => println!("usage: break <pc|label|file:line>"),
+            },
+            "d" | "delete" => match args.first() {
+                Some(arg) => match self.resolve(arg) {
+                    Some(pc) if self.session.breakpoints.remove(&pc) => {
+                        println!("Deleted breakpoint at {}", pc)
+                    }
+                    _ => println!("No breakpoint at {}", arg),
+                },
+                None => self.session.breakpoints.clear(),
+            },
+            "breaks" => {
+                for pc in self.session.breakpoints.clone() {
+                    println!("{}", self.location(vm, pc));
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let text = args.join(" ");
+                let kind = match *command {
+                    "rwatch" => WatchKind::Read,
+                    "awatch" => WatchKind::Access,
+                    _ => WatchKind::Write,
+                };
+                let watch = match (Slot::parse(&text), watch::parse_address(&text)) {
+                    (Some(slot), _) => Some(Watchpoint::Slot(slot, kind)),
+                    (_, Some(address)) => Some(Watchpoint::Address(address, kind)),
+                    _ if kind == WatchKind::Write => Watchpoint::condition(&text),
+                    _ => None,
+                };
+                match watch {
+                    Some(watch) => {
+                        let description = watch.to_string();
+                        let id = vm.add_watchpoint(watch);
+                        println!("Watchpoint {}: {}", id, description);
+                    }
+                    None => println!("usage: {} <slot|mem[address]|condition>", command),
+                }
+            }
+            "unwatch" => match args.first() {
+                Some(arg) => match arg.parse::<usize>() {
+                    Ok(id) if vm.remove_watchpoint(id) => println!("Deleted watchpoint {}", id),
+                    _ => println!("No watchpoint {}", arg),
+                },
+                None => {
+                    let ids: Vec<usize> = vm.watchpoints().keys().copied().collect();
+                    for id in ids {
+                        vm.remove_watchpoint(id);
+                    }
+                }
+            },
+            "watches" => {
+                for (id, watch) in vm.watchpoints() {
+                    println!("{}: {}", id, watch);
+                }
+            }
+            "s" | "step" => {
+                let stop = self.session.step(vm);
+                self.report(vm, stop);
+            }
+            "n" | "next" => {
+                let stop = self.session.next(vm);
+                self.report(vm, stop);
+            }
+            "finish" => match self.session.finish(vm) {
+                Some(stop) => self.report(vm, stop),
+                None => println!("Not inside a procedure"),
+            },
+            "c" | "continue" => {
+                let stop = self.session.cont(vm);
+                self.report(vm, stop);
+            }
+            "rs" | "reverse-step" => {
+                let stop = self.session.reverse_step(vm);
+                self.report(vm, stop);
+            }
+            "rc" | "reverse-continue" => {
+                let stop = self.session.reverse_cont(vm);
+                self.report(vm, stop);
+            }
+            "goto" => match args.first().and_then(|arg| arg.parse::<u64>().ok()) {
+                Some(count) => {
+                    let stop = self.session.goto(vm, count);
+                    self.report(vm, stop);
+                }
+                None => println!("usage: goto <instruction count>"),
+            },
+            "history" => match args.first().map(|arg| arg.parse::<usize>()) {
+                Some(Ok(size)) => {
+                    self.history = size;
+                    vm.set_history(Some(size));
+                    println!(
+                        "Keeping the last {} instructions, older ones are dropped",
+                        size
+                    );
+                }
+                Some(Err(_)) => println!("usage: history [size]"),
+                None => println!(
+                    "{} instructions run, {} of the last {} can be undone",
+                    vm.instruction_count(),
+                    vm.history_len(),
+                    self.history
+                ),
+            },
+            "stack" => println!("{:?}", &vm.stack()[..vm.sp()]),
+            "memory" => self.memory(vm),
+            "regs" => println!(
+                "pc: {}, sp: {}, instructions run: {}",
+                vm.pc(),
+                vm.sp(),
+                vm.instruction_count()
+            ),
+            "bt" | "frames" => {
+                for (depth, frame) in vm.frames().iter().enumerate().rev() {
+                    let name = self.label_at(frame.proc_pc).unwrap_or("?");
+                    println!(
+                        "#{} {} ({}) called from {}, sp {}",
+                        depth,
+                        name,
+                        frame.proc_pc,
+                        vm.describe_pc(frame.call_pc),
+                        frame.sp
+                    );
+                }
+            }
+            "x" => self.examine(vm, args),
+            "l" | "list" => self.list(vm),
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return false,
+            other => println!("Unknown command {}, try help", other),
+        }
+        true
+    }
+
+    pub fn run(&mut self, vm: &mut VM) {
+        // the jit would run whole procedures and loops in one step
+        vm.set_jit_enabled(false);
+        vm.set_history(Some(self.history));
+        println!(
+            "Debugging {} instructions, type help for the commands",
+            vm.bin().len()
+        );
+        println!("=> {}", self.location(vm, vm.pc()));
+
+        let stdin = io::stdin();
+        let mut last = String::new();
+        loop {
+            print!("(smdb) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+
+            let line = if line.trim().is_empty() {
+                last.clone()
+            } else {
+                line.trim().to_string()
+            };
+            if !self.command(vm, &line) {
+                return;
+            }
+            last = line;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble;
+
+    fn vm(source: &str) -> VM {
+        let mut vm = VM::from_program(assemble(source, "test.s").unwrap());
+        vm.set_output(Box::new(io::sink()));
+        vm.set_jit_enabled(false);
+        vm.set_history(Some(DEFAULT_HISTORY));
+        vm
+    }
+
+    const STORE: &str = ".data\nx: .word 5\n.text\npush 9\npush x\nstore\npush 7\nhalt\n";
+
+    #[test]
+    fn step_back_undoes_the_stack_and_memory() {
+        let mut vm = vm(STORE);
+        let mut session = Session::default();
+        for _ in 0..4 {
+            assert!(matches!(session.step(&mut vm), Stop::Done));
+        }
+        assert_eq!((vm.pc(), vm.sp(), vm.memory()), (4, 1, &[9][..]));
+
+        assert!(matches!(session.reverse_step(&mut vm), Stop::Done));
+        assert_eq!((vm.pc(), vm.sp()), (3, 0));
+        assert!(matches!(session.reverse_cont(&mut vm), Stop::HistoryStart));
+        assert_eq!((vm.pc(), vm.sp(), vm.memory()), (0, 0, &[5][..]));
+        assert_eq!(vm.instruction_count(), 0);
+
+        // and forwards again to the same place
+        assert!(matches!(session.goto(&mut vm, 3), Stop::Done));
+        assert_eq!((vm.pc(), vm.memory()), (3, &[9][..]));
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_stop_cont() {
+        let mut vm = vm(STORE);
+        let mut session = Session::default();
+        let id = vm.add_watchpoint(Watchpoint::Address(0, WatchKind::Write));
+        session.breakpoints.insert(3);
+        // the store stops before the breakpoint after it
+        assert!(matches!(session.cont(&mut vm), Stop::Watchpoint(hit) if hit == id));
+        assert_eq!(vm.pc(), 3);
+        assert!(matches!(session.cont(&mut vm), Stop::Finished));
+    }
+
+    #[test]
+    fn next_runs_over_calls() {
+        let mut vm = vm("push 1\ncall f\npush 3\nhalt\nf:\npush 2\nret\n");
+        let mut session = Session::default();
+        session.step(&mut vm);
+        assert!(matches!(session.next(&mut vm), Stop::Done));
+        assert_eq!((vm.pc(), vm.frames().len()), (2, 0));
+
+        let mut vm = self::vm("push 1\ncall f\nhalt\nf:\npush 2\nret\n");
+        session.step(&mut vm);
+        session.step(&mut vm);
+        assert_eq!(vm.frames().len(), 1);
+        assert!(matches!(session.finish(&mut vm), Some(Stop::Done)));
+        assert_eq!(vm.pc(), 2);
+        assert!(session.finish(&mut vm).is_none());
+    }
+
+    #[test]
+    fn interrupts_pause_running() {
+        let mut vm = vm("1:\njmp 1b\n");
+        let mut session = Session {
+            interrupt: Some(Box::new(|| true)),
+            ..Session::default()
+        };
+        assert!(matches!(session.cont(&mut vm), Stop::Paused));
+        assert_eq!(vm.instruction_count(), INTERRUPT_INTERVAL);
+    }
+
+    #[test]
+    fn faults_are_new_once() {
+        let mut vm = vm("push 1\npop\npop\nhalt\n");
+        let mut session = Session::default();
+        assert!(!session.take_new_fault());
+        assert!(matches!(session.cont(&mut vm), Stop::Fault(2)));
+        assert!(session.take_new_fault());
+        // every step stops at the same fault, its dump is already written
+        assert!(matches!(session.step(&mut vm), Stop::Fault(2)));
+        assert!(matches!(session.cont(&mut vm), Stop::Fault(2)));
+        assert!(!session.take_new_fault());
+
+        // going back over it and running into it again is a new fault
+        session.reverse_step(&mut vm);
+        assert!(session.fault().is_none());
+        assert!(matches!(session.step(&mut vm), Stop::Fault(2)));
+        assert!(session.take_new_fault());
+    }
+
+    #[test]
+    fn commands_resolve_labels_and_lines() {
+        let source = "push 3\nloop:\npush 1\nusub64\ndup\njnz loop\nhalt\n";
+        let program = assemble(source, "test.s").unwrap();
+        let mut debugger = Debugger::new(program.debug.clone());
+        let mut vm = VM::from_program(program);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_jit_enabled(false);
+        vm.set_history(Some(DEFAULT_HISTORY));
+
+        assert_eq!(debugger.resolve("loop"), Some(1));
+        assert_eq!(debugger.resolve("test.s:4"), Some(2));
+        assert_eq!(debugger.resolve("4"), Some(4));
+        assert_eq!(debugger.resolve("nowhere"), None);
+
+        debugger.command(&mut vm, "break test.s:4");
+        debugger.command(&mut vm, "c");
+        assert_eq!((vm.pc(), vm.sp()), (2, 2));
+        debugger.command(&mut vm, "c");
+        assert_eq!((vm.pc(), vm.stack()[0]), (2, 2));
+        debugger.command(&mut vm, "rc");
+        assert_eq!(vm.pc(), 2);
+        assert_eq!(vm.stack()[0], 3);
+
+        debugger.command(&mut vm, "delete 2");
+        assert!(debugger.session.breakpoints.is_empty());
+        debugger.command(&mut vm, "watch stack[0] == 1");
+        debugger.command(&mut vm, "continue");
+        assert_eq!((vm.pc(), vm.stack()[0]), (3, 1));
+        debugger.command(&mut vm, "unwatch");
+        debugger.command(&mut vm, "c");
+        assert!(!vm.is_running());
+        assert!(!debugger.command(&mut vm, "quit"));
+    }
+
+    #[test]
+    fn output_is_not_printed_twice() {
+        let mut vm = vm("push 72\nprt\npush 73\nprt\npush 33\nprt\nhalt\n");
+        let output = OutputBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        let mut session = Session::default();
+        assert!(matches!(session.goto(&mut vm, 4), Stop::Done));
+        assert_eq!(output.take(), b"HI");
+
+        // the same prt run again after stepping back over it
+        assert!(matches!(session.goto(&mut vm, 1), Stop::Done));
+        assert!(matches!(session.cont(&mut vm), Stop::Finished));
+        assert_eq!(output.take(), b"!");
+    }
+
+    #[test]
+    fn history_keeps_the_last_instructions() {
+        let mut vm = vm(STORE);
+        vm.set_history(Some(2));
+        let mut session = Session::default();
+        assert!(matches!(session.goto(&mut vm, 4), Stop::Done));
+        assert_eq!(vm.history_len(), 2);
+
+        assert!(matches!(session.reverse_cont(&mut vm), Stop::HistoryStart));
+        assert_eq!((vm.instruction_count(), vm.pc(), vm.sp()), (2, 2, 2));
+        // the first two instructions fell out of the log
+        assert!(matches!(session.goto(&mut vm, 0), Stop::HistoryStart));
+        assert!(matches!(session.goto(&mut vm, 4), Stop::Done));
+        assert_eq!(vm.memory(), [9]);
+
+        vm.set_history(Some(0));
+        session.step(&mut vm);
+        assert!(!vm.step_back());
+    }
+}
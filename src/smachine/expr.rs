@@ -0,0 +1,395 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The value of an operand expression.
+/// Integers wrap like the vm's u64 arithmetic, a float anywhere makes the result a float.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Number {
+    Int(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Number {
+    pub fn is_int(&self) -> bool {
+        matches!(self, Number::Int(_))
+    }
+
+    /// The word the vm holds for this value, floats as their bits
+    pub fn to_bits(self) -> u64 {
+        match self {
+            Number::Int(value) => value as u64,
+            Number::F32(value) => value.to_bits() as u64,
+            Number::F64(value) => value.to_bits(),
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(value) => value as f64,
+            Number::F32(value) => value as f64,
+            Number::F64(value) => value,
+        }
+    }
+
+    fn to_f32(self) -> f32 {
+        match self {
+            Number::Int(value) => value as f32,
+            Number::F32(value) => value,
+            Number::F64(value) => value as f32,
+        }
+    }
+}
+
+/// Written so the operand parsing in the compiler reads back the same bits
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(value) => write!(f, "{}", value),
+            Number::F32(value) => write!(f, "{:?}f", value),
+            Number::F64(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl Op {
+    // higher binds tighter, the same order as in C
+    fn precedence(&self) -> u8 {
+        match self {
+            Op::Or => 1,
+            Op::Xor => 2,
+            Op::And => 3,
+            Op::Shl | Op::Shr => 4,
+            Op::Add | Op::Sub => 5,
+            Op::Mul | Op::Div | Op::Rem => 6,
+        }
+    }
+
+    fn apply(&self, left: Number, right: Number) -> Result<Number, String> {
+        if let (Number::Int(a), Number::Int(b)) = (left, right) {
+            return Ok(Number::Int(match self {
+                Op::Add => a.wrapping_add(b),
+                Op::Sub => a.wrapping_sub(b),
+                Op::Mul => a.wrapping_mul(b),
+                Op::Div if b == 0 => return Err(String::from("division by zero")),
+                Op::Div => a.wrapping_div(b),
+                Op::Rem if b == 0 => return Err(String::from("division by zero")),
+                Op::Rem => a.wrapping_rem(b),
+                Op::Shl => a.wrapping_shl(b as u32),
+                // logical, the stack holds unsigned values
+                Op::Shr => ((a as u64).wrapping_shr(b as u32)) as i64,
+                Op::And => a & b,
+                Op::Or => a | b,
+                Op::Xor => a ^ b,
+            }));
+        }
+
+        macro_rules! float {
+            ($variant:ident, $a:expr, $b:expr) => {
+                Number::$variant(match self {
+                    Op::Add => $a + $b,
+                    Op::Sub => $a - $b,
+                    Op::Mul => $a * $b,
+                    Op::Div => $a / $b,
+                    Op::Rem => $a % $b,
+                    _ => return Err(String::from("bitwise operators need integers")),
+                })
+            };
+        }
+        // f32 only stays f32 when nothing in the expression is f64
+        Ok(match (left, right) {
+            (Number::F64(_), _) | (_, Number::F64(_)) => {
+                float!(F64, left.to_f64(), right.to_f64())
+            }
+            _ => float!(F32, left.to_f32(), right.to_f32()),
+        })
+    }
+}
+
+/// Continues a multi word operand, `push SIZE * 2` is read as one expression
+pub fn continues(text: &str, next: &str) -> bool {
+    let text = text.trim_end();
+    let depth: i32 = text
+        .chars()
+        .map(|c| match c {
+            '(' => 1,
+            ')' => -1,
+            _ => 0,
+        })
+        .sum();
+    let operator = |c: char| "+-*/%&|^<>~(".contains(c);
+    depth > 0
+        || text.ends_with(operator)
+        || next.starts_with(|c: char| operator(c) && c != '(' && c != '~' || c == ')')
+}
+
+struct Parser<'a, F> {
+    chars: Peekable<Chars<'a>>,
+    lookup: &'a mut F,
+}
+
+impl<F: FnMut(&str) -> Result<Number, String>> Parser<'_, F> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn operator(&mut self) -> Option<Op> {
+        self.skip_spaces();
+        let op = match self.chars.peek()? {
+            '+' => Op::Add,
+            '-' => Op::Sub,
+            '*' => Op::Mul,
+            '/' => Op::Div,
+            '%' => Op::Rem,
+            '&' => Op::And,
+            '|' => Op::Or,
+            '^' => Op::Xor,
+            '<' => Op::Shl,
+            '>' => Op::Shr,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Number, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.operator() {
+            if op.precedence() < min {
+                break;
+            }
+            self.chars.next();
+            if matches!(op, Op::Shl | Op::Shr)
+                && self.chars.next() != Some(if op == Op::Shl { '<' } else { '>' })
+            {
+                return Err(String::from("expected << or >>"));
+            }
+            let right = self.binary(op.precedence() + 1)?;
+            left = op.apply(left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Number, String> {
+        self.skip_spaces();
+        match self.chars.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(match self.unary()? {
+                    Number::Int(value) => Number::Int(value.wrapping_neg()),
+                    Number::F32(value) => Number::F32(-value),
+                    Number::F64(value) => Number::F64(-value),
+                })
+            }
+            Some('+') => {
+                self.chars.next();
+                self.unary()
+            }
+            Some('~') => {
+                self.chars.next();
+                match self.unary()? {
+                    Number::Int(value) => Ok(Number::Int(!value)),
+                    _ => Err(String::from("bitwise operators need integers")),
+                }
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn take_while(&mut self, accept: impl Fn(char) -> bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.chars.next_if(|c| accept(*c)) {
+            text.push(c);
+        }
+        text
+    }
+
+    fn atom(&mut self) -> Result<Number, String> {
+        match self.chars.peek().copied() {
+            Some('(') => {
+                self.chars.next();
+                let value = self.binary(0)?;
+                self.skip_spaces();
+                if self.chars.next() != Some(')') {
+                    return Err(String::from("expected )"));
+                }
+                Ok(value)
+            }
+            Some('\'') => {
+                self.chars.next();
+                let c = match self.chars.next() {
+                    Some('\\') => match self.chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some(c @ ('\\' | '\'')) => c,
+                        _ => return Err(String::from("unknown escape in character literal")),
+                    },
+                    Some(c) => c,
+                    None => return Err(String::from("unterminated character literal")),
+                };
+                if self.chars.next() != Some('\'') {
+                    return Err(String::from("unterminated character literal"));
+                }
+                Ok(Number::Int(c as i64))
+            }
+            Some(c) if c.is_ascii_digit() => self.number(),
+            // .5 is a number, .loop a name
+            Some('.')
+                if self
+                    .chars
+                    .clone()
+                    .nth(1)
+                    .is_some_and(|c| c.is_ascii_digit()) =>
+            {
+                self.number()
+            }
+            Some(c) if c.is_alphabetic() || c == '_' || c == '.' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || "_.@".contains(c));
+                (self.lookup)(&name)
+            }
+            Some(c) => Err(format!("unexpected {}", c)),
+            None => Err(String::from("expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Number, String> {
+        let text = self.take_while(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_');
+        // exponents like 1e-3 have a sign in the middle
+        let text = if text.ends_with(['e', 'E']) && !text.starts_with("0x") {
+            match self.chars.next_if(|c| *c == '-' || *c == '+') {
+                Some(sign) => {
+                    text + &sign.to_string() + &self.take_while(|c| c.is_ascii_alphanumeric())
+                }
+                None => text,
+            }
+        } else {
+            text
+        };
+        let digits = text.replace('_', "");
+
+        let radix = |prefix: &str, radix: u32| {
+            digits
+                .strip_prefix(prefix)
+                .map(|digits| u64::from_str_radix(digits, radix))
+        };
+        if let Some(value) = radix("0x", 16)
+            .or_else(|| radix("0b", 2))
+            .or_else(|| radix("0o", 8))
+        {
+            return value
+                .map(|value| Number::Int(value as i64))
+                .map_err(|_| format!("invalid number {}", text));
+        }
+        if let Ok(value) = digits.parse::<u64>() {
+            return Ok(Number::Int(value as i64));
+        }
+        if let Some(value) = digits
+            .strip_suffix('f')
+            .and_then(|digits| digits.parse::<f32>().ok())
+        {
+            return Ok(Number::F32(value));
+        }
+        digits
+            .parse::<f64>()
+            .map(Number::F64)
+            .map_err(|_| format!("invalid number {}", text))
+    }
+}
+
+/// Evaluates an operand like `BUF_SIZE * 2 + 1`, names are resolved through `lookup`
+pub fn eval<F>(text: &str, lookup: &mut F) -> Result<Number, String>
+where
+    F: FnMut(&str) -> Result<Number, String>,
+{
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        lookup,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_spaces();
+    match parser.chars.next() {
+        Some(c) => Err(format!("unexpected {}", c)),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Result<Number, String> {
+        eval(text, &mut |name: &str| match name {
+            "SIZE" => Ok(Number::Int(8)),
+            _ => Err(format!("unknown name {}", name)),
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("1 + 2 * 3"), Ok(Number::Int(7)));
+        assert_eq!(value("(1 + 2) * 3"), Ok(Number::Int(9)));
+        assert_eq!(value("10 - 4 - 3"), Ok(Number::Int(3)));
+        assert_eq!(value("1 << 2 + 1"), Ok(Number::Int(8)));
+        assert_eq!(value("1 | 2 & 3 ^ 4"), Ok(Number::Int(7)));
+        assert_eq!(value("-SIZE * 2"), Ok(Number::Int(-16)));
+        assert_eq!(value("~0 & 0xff"), Ok(Number::Int(255)));
+        assert_eq!(value("17 % 5 * 2"), Ok(Number::Int(4)));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("0b101 + 0o7 + 0x10"), Ok(Number::Int(28)));
+        assert_eq!(value("1_000"), Ok(Number::Int(1000)));
+        assert_eq!(value("'a' + 1"), Ok(Number::Int(98)));
+        assert_eq!(value("1.5f * 2"), Ok(Number::F32(3.0)));
+        assert_eq!(value("1.5f + 1.0"), Ok(Number::F64(2.5)));
+        assert_eq!(value("1e-3"), Ok(Number::F64(0.001)));
+        // shifts are logical
+        assert_eq!(value("-1 >> 60"), Ok(Number::Int(15)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(value("1 +").is_err());
+        assert!(value("(1 + 2").is_err());
+        assert!(value("1 2").is_err());
+        assert!(value("1 < 2").is_err());
+        assert_eq!(value("4 / 0"), Err(String::from("division by zero")));
+        assert_eq!(
+            value("4 % (SIZE - 8)"),
+            Err(String::from("division by zero"))
+        );
+        assert_eq!(
+            value("MISSING + 1"),
+            Err(String::from("unknown name MISSING"))
+        );
+        assert!(value("1.5 & 1").is_err());
+        assert!(value("0xzz").is_err());
+        assert!(value("'ab'").is_err());
+    }
+
+    #[test]
+    fn continues_operands() {
+        assert!(continues("SIZE *", "2"));
+        assert!(continues("SIZE", "* 2"));
+        assert!(continues("(SIZE", "2)"));
+        assert!(!continues("SIZE", "2"));
+        assert!(!continues("SIZE", "(2)"));
+    }
+}
@@ -0,0 +1,508 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpListener;
+
+use super::debugger::{DEFAULT_HISTORY, OutputBuffer, Session, Stop};
+use super::vm::VM;
+use super::watch::{Slot, WatchKind, Watchpoint};
+
+// stop replies use the unix signal numbers gdb expects
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+const SIGSEGV: u8 = 11;
+
+const REGISTERS: [&str; 2] = ["pc", "sp"];
+
+/// Where the .rodata/.data words start in the gdb address space
+pub const DATA_BASE: usize = 0x1000_0000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target>
+  <architecture>smachine</architecture>
+  <feature name="org.smachine.core">
+    <reg name="pc" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="sp" bitsize="64" type="uint64" regnum="1"/>
+  </feature>
+</target>
+"#;
+
+/// GDB remote serial protocol stub.
+/// Registers are pc and sp, both counted in instructions/stack slots.
+/// Memory address 0 is the first operand stack slot, each slot is 8 bytes little endian.
+/// The .rodata words followed by the .data words start at `DATA_BASE`, also 8 bytes
+/// each, so `mem[N]` is at `DATA_BASE + N * 8`.
+pub struct GdbStub {
+    vm: VM,
+    session: Session,
+    output: OutputBuffer,
+    last_stop: String,
+    no_ack: bool,
+    // Z2-Z4 watchpoints as (type, address, vm watchpoint number)
+    watches: Vec<(char, usize, usize)>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    match reader.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+impl GdbStub {
+    pub fn new(mut vm: VM) -> GdbStub {
+        // the jit would run whole procedures and loops in one step
+        vm.set_jit_enabled(false);
+        vm.set_history(Some(DEFAULT_HISTORY));
+        let output = OutputBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        Self {
+            vm,
+            session: Session::default(),
+            output,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            no_ack: false,
+            watches: Vec::new(),
+        }
+    }
+
+    // the instruction the program is stopped at
+    fn pc(&self) -> usize {
+        self.session.fault().unwrap_or(self.vm.pc())
+    }
+
+    fn register(&self, index: usize) -> Option<u64> {
+        match index {
+            0 => Some(self.pc() as u64),
+            1 => Some(self.vm.sp() as u64),
+            _ => None,
+        }
+    }
+
+    fn read_memory(&self, address: usize, length: usize) -> String {
+        let (words, address) = match address.checked_sub(DATA_BASE) {
+            Some(offset) => (self.vm.memory(), offset),
+            None => (self.vm.stack(), address),
+        };
+        let memory: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        if address >= memory.len() {
+            return String::from("E01");
+        }
+        let end = address.saturating_add(length).min(memory.len());
+        hex(&memory[address..end])
+    }
+
+    fn resume(&mut self, step: bool, reverse: bool) -> Vec<String> {
+        let stop = match (step, reverse) {
+            (true, false) => self.session.step(&mut self.vm),
+            (false, false) => self.session.cont(&mut self.vm),
+            (true, true) => self.session.reverse_step(&mut self.vm),
+            (false, true) => self.session.reverse_cont(&mut self.vm),
+        };
+
+        // what the program printed goes to the gdb console
+        let mut replies = Vec::new();
+        let output = self.output.take();
+        for chunk in output.chunks(256) {
+            replies.push(format!("O{}", hex(chunk)));
+        }
+        if let Stop::Fault(pc) = stop
+            && self.session.take_new_fault()
+        {
+            let message = match self.vm.write_crash_dump(pc) {
+                Ok(path) => format!("crash dump written to {}\n", path.display()),
+                Err(err) => format!("could not write the crash dump: {}\n", err),
+            };
+            replies.push(format!("O{}", hex(message.as_bytes())));
+        }
+
+        self.last_stop = match stop {
+            Stop::Breakpoint | Stop::Done => format!("S{:02x}", SIGTRAP),
+            Stop::Watchpoint(id) => match self.watches.iter().find(|watch| watch.2 == id) {
+                Some((kind, address, _)) => {
+                    let name = match kind {
+                        '3' => "rwatch",
+                        '4' => "awatch",
+                        _ => "watch",
+                    };
+                    format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+                }
+                None => format!("S{:02x}", SIGTRAP),
+            },
+            Stop::Finished => String::from("W00"),
+            Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Stop::Paused => format!("S{:02x}", SIGINT),
+            Stop::Fault(_) => format!("S{:02x}", SIGSEGV),
+        };
+        replies.push(self.last_stop.clone());
+        replies
+    }
+
+    // Z0/z0 and Z1/z1, software and hardware breakpoints are the same thing here,
+    // Z2-Z4 watch the stack slot or data word holding the address
+    fn breakpoint(&mut self, packet: &str, insert: bool) -> String {
+        let mut fields = packet[1..].split(',');
+        let (Some(kind), Some(address)) = (fields.next(), fields.next().and_then(parse_hex)) else {
+            return String::from("E01");
+        };
+        let watch_kind = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return String::new(),
+        };
+        let kind = kind.chars().next().unwrap_or_default();
+
+        if let Some(watch_kind) = watch_kind {
+            if insert {
+                let watchpoint = match address.checked_sub(DATA_BASE) {
+                    Some(offset) => Watchpoint::Address(offset / 8, watch_kind),
+                    None => Watchpoint::Slot(Slot::Absolute(address / 8), watch_kind),
+                };
+                let id = self.vm.add_watchpoint(watchpoint);
+                self.watches.push((kind, address, id));
+            } else if let Some(index) = self
+                .watches
+                .iter()
+                .position(|watch| watch.0 == kind && watch.1 == address)
+            {
+                let (_, _, id) = self.watches.remove(index);
+                self.vm.remove_watchpoint(id);
+            }
+        } else if insert {
+            self.session.breakpoints.insert(address);
+        } else {
+            self.session.breakpoints.remove(&address);
+        }
+        String::from("OK")
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return String::from(
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+            );
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let mut fields = args.split(',');
+            let (Some(offset), Some(length)) = (
+                fields.next().and_then(parse_hex),
+                fields.next().and_then(parse_hex),
+            ) else {
+                return String::from("E01");
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = start.saturating_add(length).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &TARGET_XML[start..end]);
+        }
+        // lldb asks for the registers one by one
+        if let Some(index) = packet.strip_prefix("qRegisterInfo") {
+            return match parse_hex(index) {
+                Some(index) if index < REGISTERS.len() => format!(
+                    "name:{};bitsize:64;offset:{};encoding:uint;format:hex;set:General Purpose Registers;generic:{};",
+                    REGISTERS[index],
+                    index * 8,
+                    REGISTERS[index]
+                ),
+                _ => String::from("E45"),
+            };
+        }
+
+        match packet {
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                String::from("OK")
+            }
+            _ => String::new(),
+        }
+    }
+
+    // the replies to a packet, None once the client is done
+    fn handle(&mut self, packet: &str) -> Option<Vec<String>> {
+        let reply = match packet.chars().next() {
+            Some('?') => self.last_stop.clone(),
+            Some('g') => (0..REGISTERS.len())
+                .filter_map(|index| self.register(index))
+                .map(|value| hex(&value.to_le_bytes()))
+                .collect(),
+            Some('p') => match parse_hex(&packet[1..]).and_then(|index| self.register(index)) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => String::from("E01"),
+            },
+            Some('m') => match packet[1..].split_once(',') {
+                Some((address, length)) => match (parse_hex(address), parse_hex(length)) {
+                    (Some(address), Some(length)) => self.read_memory(address, length),
+                    _ => String::from("E01"),
+                },
+                None => String::from("E01"),
+            },
+            Some('c') => return Some(self.resume(false, false)),
+            Some('s') => return Some(self.resume(true, false)),
+            // reverse execution, bs and bc
+            Some('b') => match packet {
+                "bs" => return Some(self.resume(true, true)),
+                "bc" => return Some(self.resume(false, true)),
+                _ => String::new(),
+            },
+            Some('Z') => self.breakpoint(packet, true),
+            Some('z') => self.breakpoint(packet, false),
+            Some('H') => String::from("OK"),
+            Some('T') => String::from("OK"),
+            Some('q') | Some('Q') => self.query(packet),
+            Some('k') => return None,
+            Some('D') => {
+                self.session.breakpoints.clear();
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(vec![reply])
+    }
+
+    fn send<W: Write>(&self, writer: &mut W, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.push(b'}');
+                escaped.push(byte ^ 0x20);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        writer.write_all(b"$")?;
+        writer.write_all(&escaped)?;
+        write!(writer, "#{:02x}", checksum(&escaped))?;
+        writer.flush()
+    }
+
+    // reads up to the next packet, acking it, None at the end of the input
+    fn read_packet<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<Option<String>> {
+        loop {
+            let Some(byte) = read_byte(reader)? else {
+                return Ok(None);
+            };
+            match byte {
+                b'$' => {}
+                // ctrl-c, the program only runs while a packet is being handled
+                // so there is nothing to interrupt
+                0x03 => {
+                    self.last_stop = format!("S{:02x}", SIGINT);
+                    self.send(writer, &self.last_stop)?;
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match read_byte(reader)? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut sum = [0u8; 2];
+            reader.read_exact(&mut sum)?;
+
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+            if !self.no_ack {
+                writer.write_all(if valid { b"+" } else { b"-" })?;
+                writer.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+            }
+        }
+    }
+
+    pub fn serve<R: Read, W: Write>(&mut self, mut reader: R, mut writer: W) -> io::Result<()> {
+        while let Some(packet) = self.read_packet(&mut reader, &mut writer)? {
+            let Some(replies) = self.handle(&packet) else {
+                if packet.starts_with('D') {
+                    self.send(&mut writer, "OK")?;
+                }
+                break;
+            };
+            for reply in replies {
+                self.send(&mut writer, &reply)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for one gdb connection on `address` and serves it
+pub fn serve_tcp(vm: VM, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    eprintln!("gdb connected from {}", peer);
+    let reader = BufReader::new(stream.try_clone()?);
+    GdbStub::new(vm).serve(reader, stream)
+}
+
+/// Serves gdb over stdin/stdout, for `target remote | simplestackmachine gdb --stdio prog.s`
+pub fn serve_stdio(vm: VM) -> io::Result<()> {
+    let stdin = io::stdin();
+    GdbStub::new(vm).serve(stdin.lock(), io::stdout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble;
+
+    fn stub(source: &str) -> GdbStub {
+        GdbStub::new(VM::from_program(assemble(source, "test.s").unwrap()))
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    // the packets gdb sends, and the replies to them with their checksums checked
+    fn session(stub: &mut GdbStub, packets: &[&str]) -> Vec<String> {
+        let input: String = packets.iter().map(|data| packet(data)).collect();
+        let mut output = Vec::new();
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut replies = Vec::new();
+        let mut rest = output.as_str();
+        while let Some(start) = rest.find('$') {
+            let (data, after) = rest[start + 1..].split_once('#').unwrap();
+            assert_eq!(
+                u8::from_str_radix(&after[..2], 16),
+                Ok(checksum(data.as_bytes()))
+            );
+            replies.push(data.to_string());
+            rest = &after[2..];
+        }
+        replies
+    }
+
+    #[test]
+    fn packets_round_trip_through_serve() {
+        let source = ".data\nx: .word 5\n.text\npush 72\nprt\npush 7\nhalt\n";
+        let mut stub = stub(source);
+        let replies = session(
+            &mut stub,
+            &[
+                "qSupported:multiprocess+",
+                "?",
+                "g",
+                "Z0,2,1",
+                "c",
+                "p0",
+                "p1",
+                "m0,8",
+                "m10000000,8",
+                "m10000008,8",
+                "s",
+                "g",
+                "z0,2,1",
+                "c",
+                "D",
+            ],
+        );
+        let expected = vec![
+            String::from(
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+            ),
+            String::from("S05"),
+            format!("{}{}", hex(&0u64.to_le_bytes()), hex(&0u64.to_le_bytes())),
+            String::from("OK"),
+            // prt writes the byte it pops, the slot keeps its old value
+            format!("O{}", hex(b"H")),
+            String::from("S05"),
+            hex(&2u64.to_le_bytes()),
+            hex(&0u64.to_le_bytes()),
+            hex(&72u64.to_le_bytes()),
+            hex(&5u64.to_le_bytes()),
+            String::from("E01"),
+            String::from("S05"),
+            format!("{}{}", hex(&3u64.to_le_bytes()), hex(&1u64.to_le_bytes())),
+            String::from("OK"),
+            String::from("W00"),
+            String::from("OK"),
+        ];
+        assert_eq!(replies, expected);
+    }
+
+    #[test]
+    fn bad_checksums_are_nacked() {
+        let mut stub = stub("halt\n");
+        let mut output = Vec::new();
+        let input = format!("$g#00{}", packet("p0"));
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let expected = format!("-+{}", packet(&hex(&0u64.to_le_bytes())));
+        assert_eq!(output, expected);
+
+        // no acks at all once gdb asks for that
+        let replies = session(&mut stub, &["QStartNoAckMode", "?"]);
+        assert_eq!(replies, ["OK", "S05"]);
+    }
+
+    #[test]
+    fn watchpoints_stop_with_their_address() {
+        let source =
+            ".data\nx: .word 5\n.text\npush 9\npush x\nstore\npush x\nload\npush 1\nhalt\n";
+        let mut stub = stub(source);
+        let replies = session(
+            &mut stub,
+            &[
+                "Z2,10000000,8",
+                "Z3,0,8",
+                "c",
+                "p0",
+                "m10000000,8",
+                "z2,10000000,8",
+                "c",
+                "p0",
+                "c",
+                "c",
+                "k",
+            ],
+        );
+        let expected = [
+            "OK",
+            "OK",
+            "T05watch:10000000;",
+            &hex(&3u64.to_le_bytes()),
+            &hex(&9u64.to_le_bytes()),
+            "OK",
+            // store read slot 0 too, but stopped for the data word first
+            "T05rwatch:0;",
+            &hex(&5u64.to_le_bytes()),
+            "W00",
+            "W00",
+        ];
+        assert_eq!(replies, expected);
+    }
+}
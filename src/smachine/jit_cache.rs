@@ -0,0 +1,252 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::PathBuf;
+
+use super::compiler::ByteCode;
+use super::jit_dump::{self, OffsetMap};
+use super::vm::{CODEGEN_VERSION, MAX_SIZE};
+
+const MAGIC: &[u8; 4] = b"SMJC";
+// bumped when the layout of an entry changes
+const FORMAT: u8 = 4;
+const VM_VERSION: &str = env!("CARGO_PKG_VERSION");
+// what else the generated code depends on besides the bytecode
+const BUILD: [u64; 2] = [CODEGEN_VERSION, MAX_SIZE as u64];
+
+// FNV-1a, stable between runs and rust versions unlike DefaultHasher
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Hashes a piece of bytecode together with the vm version, the codegen version,
+/// the stack size and anything else that ends up baked into the generated code
+/// (loop heads, program length...)
+pub fn key(bin: &[ByteCode], context: &[u64]) -> u64 {
+    key_for(BUILD, bin, context)
+}
+
+fn key_for(build: [u64; 2], bin: &[ByteCode], context: &[u64]) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET, VM_VERSION.as_bytes());
+    for value in build.iter().chain(context) {
+        hash = fnv1a(hash, &value.to_le_bytes());
+    }
+    for binary in bin {
+        hash = fnv1a(hash, &[binary.opcode]);
+        hash = fnv1a(hash, &binary.value.to_le_bytes());
+    }
+    hash
+}
+
+/// Machine code as it was emitted by the assembler.
+/// `relocations` are offsets of 64 bit values holding an address relative to the
+/// start of the code, they get the final base address added when loaded.
+/// The jit only emits relative references right now, so the list is usually empty.
+/// `offsets` is where each instruction starts in the code, for `--jit-dump`, with
+/// the pcs counted from the first instruction compiled.
+#[derive(Debug)]
+pub struct CachedCode {
+    pub code: Vec<u8>,
+    pub relocations: Vec<u64>,
+    pub offsets: OffsetMap,
+}
+
+#[derive(Debug)]
+pub struct JitCache {
+    dir: PathBuf,
+}
+
+impl JitCache {
+    pub fn new(dir: &str) -> Result<JitCache> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.jit", key))
+    }
+
+    /// The code compiled from `bin` and `context`, the entry has to hold the same
+    /// bytecode so a hash collision or a stale entry is never run
+    pub fn load(&self, key: u64, bin: &[ByteCode], context: &[u64]) -> Option<CachedCode> {
+        let data = fs::read(self.path(key)).ok()?;
+        let entry = read_entry(&mut &data[..]).ok()?;
+        let same_bin = entry.bin.len() == bin.len()
+            && entry
+                .bin
+                .iter()
+                .zip(bin)
+                .all(|(a, b)| a.opcode == b.opcode && a.value == b.value);
+        (same_bin && entry.context == context).then_some(entry.code)
+    }
+
+    pub fn store(
+        &self,
+        key: u64,
+        bin: &[ByteCode],
+        context: &[u64],
+        code: &CachedCode,
+    ) -> Result<()> {
+        // written next to the final file and renamed so readers never see half an entry
+        let tmp = self
+            .path(key)
+            .with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut writer = std::io::BufWriter::new(fs::File::create(&tmp)?);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&[FORMAT])?;
+            writer.write_all(&(VM_VERSION.len() as u64).to_le_bytes())?;
+            writer.write_all(VM_VERSION.as_bytes())?;
+            for value in BUILD {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&(context.len() as u64).to_le_bytes())?;
+            for value in context {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&(bin.len() as u64).to_le_bytes())?;
+            for binary in bin {
+                writer.write_all(&[binary.opcode])?;
+                writer.write_all(&binary.value.to_le_bytes())?;
+            }
+            writer.write_all(&(code.code.len() as u64).to_le_bytes())?;
+            writer.write_all(&code.code)?;
+            writer.write_all(&(code.relocations.len() as u64).to_le_bytes())?;
+            for offset in &code.relocations {
+                writer.write_all(&offset.to_le_bytes())?;
+            }
+            writer.write_all(&(code.offsets.len() as u64).to_le_bytes())?;
+            for (pc, offset) in &code.offsets {
+                writer.write_all(&(*pc as u64).to_le_bytes())?;
+                writer.write_all(&(*offset as u64).to_le_bytes())?;
+            }
+        }
+        fs::rename(tmp, self.path(key))
+    }
+}
+
+// what an entry holds besides the code, to check it against what is being compiled
+struct Entry {
+    context: Vec<u64>,
+    bin: Vec<ByteCode>,
+    code: CachedCode,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// a count of `size` byte items, refused when the rest of the entry can not hold them
+fn read_len(reader: &mut &[u8], size: usize) -> Result<usize> {
+    let len = read_u64(reader)?;
+    usize::try_from(len)
+        .ok()
+        .filter(|len| {
+            len.checked_mul(size)
+                .is_some_and(|bytes| bytes <= reader.len())
+        })
+        .ok_or_else(|| invalid("jit cache entry is cut short"))
+}
+
+fn read_entry(reader: &mut &[u8]) -> Result<Entry> {
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic)?;
+    if &magic[..4] != MAGIC || magic[4] != FORMAT {
+        return Err(invalid("not a jit cache entry"));
+    }
+
+    let mut version = vec![0u8; read_len(reader, 1)?];
+    reader.read_exact(&mut version)?;
+    if version != VM_VERSION.as_bytes() || [read_u64(reader)?, read_u64(reader)?] != BUILD {
+        return Err(invalid("jit cache from another vm"));
+    }
+
+    let context = (0..read_len(reader, 8)?)
+        .map(|_| read_u64(reader))
+        .collect::<Result<Vec<u64>>>()?;
+    let mut bin = Vec::new();
+    for _ in 0..read_len(reader, 9)? {
+        let mut opcode = [0u8; 1];
+        reader.read_exact(&mut opcode)?;
+        bin.push(ByteCode {
+            opcode: opcode[0],
+            value: read_u64(reader)?,
+        });
+    }
+
+    let code_len = read_len(reader, 1)?;
+    let mut code = vec![0u8; code_len];
+    reader.read_exact(&mut code)?;
+
+    let mut relocations = Vec::new();
+    for _ in 0..read_len(reader, 8)? {
+        let offset = read_u64(reader)?;
+        if offset
+            .checked_add(8)
+            .is_none_or(|end| end > code_len as u64)
+        {
+            return Err(invalid("relocation out of bounds"));
+        }
+        relocations.push(offset);
+    }
+
+    let mut offsets = OffsetMap::new();
+    for _ in 0..read_len(reader, 16)? {
+        let pc = read_u64(reader)? as usize;
+        let offset = read_u64(reader)? as usize;
+        let last = offsets.last().map_or(0, |(_, last)| *last);
+        if (pc >= bin.len() && pc != jit_dump::EXITS) || offset < last || offset > code_len {
+            return Err(invalid("instruction offset out of bounds"));
+        }
+        offsets.push((pc, offset));
+    }
+
+    Ok(Entry {
+        context,
+        bin,
+        code: CachedCode {
+            code,
+            relocations,
+            offsets,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_builds_miss_the_cache() {
+        let dir = std::env::temp_dir().join(format!("smachine-jit-test-{}", std::process::id()));
+        let cache = JitCache::new(dir.to_str().unwrap()).unwrap();
+        let bin = [ByteCode {
+            opcode: 0,
+            value: 7,
+        }];
+        let code = CachedCode {
+            code: vec![0xc3],
+            relocations: Vec::new(),
+            offsets: OffsetMap::new(),
+        };
+        cache.store(key(&bin, &[1]), &bin, &[1], &code).unwrap();
+        assert!(cache.load(key(&bin, &[1]), &bin, &[1]).is_some());
+
+        let codegen = key_for([CODEGEN_VERSION + 1, MAX_SIZE as u64], &bin, &[1]);
+        let stack = key_for([CODEGEN_VERSION, MAX_SIZE as u64 + 1], &bin, &[1]);
+        assert!(cache.load(codegen, &bin, &[1]).is_none());
+        assert!(cache.load(stack, &bin, &[1]).is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Result, Write};
+use std::path::PathBuf;
+
+use super::compiler::{ByteCode, TokenType};
+
+/// Where each bytecode instruction starts inside the generated machine code
+pub type OffsetMap = Vec<(usize, usize)>;
+
+/// Marks the start of the code that leaves a compiled loop
+pub const EXITS: usize = usize::MAX;
+
+/// Writes what the jit produced so it can be inspected after the fact.
+/// Each compiled function gets `<name>.bin` with the raw machine code, which
+/// can be disassembled with `objdump -D -b binary -mi386:x86-64 <name>.bin`,
+/// and `<name>.lst` mapping every bytecode instruction to its machine code.
+/// The listing is disassembled when built with the `jit-disasm` feature and a
+/// hex dump otherwise.
+/// With a perf map, `perf report` can also put names on jit frames.
+#[derive(Debug)]
+pub struct JitDump {
+    dir: Option<PathBuf>,
+    perf_map: Option<fs::File>,
+}
+
+impl JitDump {
+    pub fn new(dir: Option<&str>, perf_map: bool) -> Result<JitDump> {
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+
+        let perf_map = if perf_map {
+            let path = format!("/tmp/perf-{}.map", std::process::id());
+            Some(fs::File::create(path)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            dir: dir.map(PathBuf::from),
+            perf_map,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        name: &str,
+        address: *const u8,
+        code: &[u8],
+        bin: &[ByteCode],
+        first_pc: usize,
+        offsets: &OffsetMap,
+    ) {
+        if let Some(perf_map) = &mut self.perf_map {
+            // START SIZE symbolname, both numbers in hex
+            let _ = writeln!(
+                perf_map,
+                "{:x} {:x} smachine::{}",
+                address as u64,
+                code.len(),
+                name
+            );
+            let _ = perf_map.flush();
+        }
+
+        if let Some(dir) = &self.dir {
+            let listing = listing(name, address, code, bin, first_pc, offsets);
+            let written = fs::write(dir.join(format!("{}.bin", name)), code)
+                .and_then(|_| fs::write(dir.join(format!("{}.lst", name)), listing));
+            if let Err(err) = written {
+                eprintln!("WARNING: could not dump {}: {}", name, err);
+            }
+        }
+    }
+}
+
+// bytes on each line of a hex dump
+#[cfg(not(feature = "jit-disasm"))]
+const HEX_ROW: usize = 16;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// the machine code from `start` to `end`, one line per x86 instruction
+#[cfg(feature = "jit-disasm")]
+fn machine_code(code: &[u8], start: usize, end: usize, address: u64) -> Vec<String> {
+    use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+    let mut decoder = Decoder::with_ip(
+        64,
+        &code[start..end],
+        address + start as u64,
+        DecoderOptions::NONE,
+    );
+    let mut formatter = IntelFormatter::new();
+    let mut lines = Vec::new();
+    for instruction in &mut decoder {
+        let offset = instruction.ip() - address;
+        let bytes = &code[offset as usize..offset as usize + instruction.len()];
+        let mut text = String::new();
+        formatter.format(&instruction, &mut text);
+        lines.push(format!("{:04x}  {:<30} {}", offset, hex(bytes), text));
+    }
+    lines
+}
+
+// the machine code from `start` to `end` as hex
+#[cfg(not(feature = "jit-disasm"))]
+fn machine_code(code: &[u8], start: usize, end: usize, _address: u64) -> Vec<String> {
+    code[start..end]
+        .chunks(HEX_ROW)
+        .enumerate()
+        .map(|(row, bytes)| format!("{:04x}  {}", start + row * HEX_ROW, hex(bytes)))
+        .collect()
+}
+
+fn listing(
+    name: &str,
+    address: *const u8,
+    code: &[u8],
+    bin: &[ByteCode],
+    first_pc: usize,
+    offsets: &OffsetMap,
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "; {} at {:#x}, {} bytes",
+        name,
+        address as u64,
+        code.len()
+    );
+    let _ = writeln!(out, "; objdump -D -b binary -mi386:x86-64 {}.bin", name);
+
+    let mut section = |label: &str, start: usize, end: usize| {
+        let _ = writeln!(out, "{}", label);
+        for line in machine_code(code, start, end, address as u64) {
+            let _ = writeln!(out, "        {}", line);
+        }
+    };
+
+    // the prologue is everything before the first instruction
+    if let Some((_, first)) = offsets.first()
+        && *first > 0
+    {
+        section(&format!("{:>6}  <prologue>", ""), 0, *first);
+    }
+
+    for (i, (pc, start)) in offsets.iter().enumerate() {
+        let end = offsets
+            .get(i + 1)
+            .map(|(_, end)| *end)
+            .unwrap_or(code.len());
+        if *pc == EXITS {
+            section(&format!("{:>6}  <exits>", ""), *start, end);
+            continue;
+        }
+
+        let binary = bin[pc - first_pc];
+        let kind = TokenType::from(binary.opcode);
+        let instruction = if kind.takes_operand() {
+            format!("{} {}", kind.mnemonic(), binary.value)
+        } else {
+            kind.mnemonic().to_string()
+        };
+        section(&format!("{:>6}  {}", pc, instruction), *start, end);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // push 7 as a mov and an add, then the exit
+    const CODE: [u8; 12] = [
+        0x55, 0x48, 0xc7, 0xc0, 0x07, 0x00, 0x00, 0x00, 0x48, 0x01, 0xc8, 0xc3,
+    ];
+
+    fn lines() -> Vec<String> {
+        let bin = [
+            ByteCode {
+                opcode: TokenType::Push as u8,
+                value: 7,
+            },
+            ByteCode {
+                opcode: TokenType::Uadd64 as u8,
+                value: 0,
+            },
+        ];
+        let offsets = vec![(4, 1), (5, 8), (EXITS, 11)];
+        listing("loop_4", 0x1000 as *const u8, &CODE, &bin, 4, &offsets)
+            .lines()
+            .map(|line| line.trim_end().to_string())
+            .collect()
+    }
+
+    #[cfg(not(feature = "jit-disasm"))]
+    #[test]
+    fn instructions_with_their_machine_code() {
+        let expected = [
+            "; loop_4 at 0x1000, 12 bytes",
+            "; objdump -D -b binary -mi386:x86-64 loop_4.bin",
+            "        <prologue>",
+            "        0000  55",
+            "     4  push 7",
+            "        0001  48 c7 c0 07 00 00 00",
+            "     5  uadd64",
+            "        0008  48 01 c8",
+            "        <exits>",
+            "        000b  c3",
+        ];
+        assert_eq!(lines(), expected);
+    }
+
+    #[cfg(feature = "jit-disasm")]
+    #[test]
+    fn instructions_with_their_machine_code() {
+        let lines = lines();
+        assert_eq!(lines[2], "        <prologue>");
+        assert!(lines[3].ends_with("push rbp"));
+        assert_eq!(lines[4], "     4  push 7");
+        assert!(lines[5].starts_with("        0001  48 c7 c0 07 00 00 00"));
+        assert!(lines[5].ends_with("mov rax,7"));
+        assert!(lines[7].ends_with("add rax,rcx"));
+        assert!(lines[9].ends_with("ret"));
+    }
+}
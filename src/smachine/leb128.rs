@@ -0,0 +1,130 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Writes `value` 7 bits at a time, small numbers take a single byte
+pub fn write_u64<W: Write>(writer: &mut W, mut value: u64) -> Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        if shift >= 64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "LEB128 value is too long",
+            ));
+        }
+        // the 10th byte only has room for bit 63
+        if shift == 63 && byte[0] & 0x7e != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "LEB128 value does not fit in 64 bits",
+            ));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Maps signed numbers so the ones close to zero stay small
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_u64(&mut out, value).unwrap();
+        out
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(encode(0), vec![0]);
+        assert_eq!(encode(127), vec![0x7f]);
+        assert_eq!(encode(128), vec![0x80, 0x01]);
+        assert_eq!(encode(16383), vec![0xff, 0x7f]);
+        assert_eq!(encode(16384), vec![0x80, 0x80, 0x01]);
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        assert_eq!(encode(u64::MAX), max);
+    }
+
+    #[test]
+    fn round_trips() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16383,
+            16384,
+            u32::MAX as u64,
+            1 << 63,
+            u64::MAX,
+        ] {
+            assert_eq!(read_u64(&mut encode(value).as_slice()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        // cut off in the middle
+        assert!(read_u64(&mut [0x80u8].as_slice()).is_err());
+        assert!(read_u64(&mut [].as_slice()).is_err());
+        // more than 10 bytes
+        assert!(read_u64(&mut [0x80u8; 11].as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_bits_above_64() {
+        let mut bytes = vec![0xffu8; 9];
+        bytes.push(0x01);
+        assert_eq!(read_u64(&mut bytes.as_slice()).unwrap(), u64::MAX);
+        for last in [0x02u8, 0x03, 0x7f] {
+            bytes[9] = last;
+            assert!(read_u64(&mut bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn zigzag_boundaries() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-64), 127);
+        assert_eq!(zigzag(64), 128);
+        assert_eq!(zigzag(i64::MAX), u64::MAX - 1);
+        assert_eq!(zigzag(i64::MIN), u64::MAX);
+        for value in [0, -1, 1, -64, 64, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+use std::ops::Deref;
+use std::slice;
+
+use memmap2::Mmap;
+
+use super::compiler::{self, ByteCode, Program};
+
+/// Starts a `.bin` whose code is laid out like `[ByteCode]` in memory
+pub const MAGIC: &[u8; 4] = b"SMMP";
+const VERSION: u8 = 1;
+// the magic, the version, 3 zero bytes and the instruction count,
+// so the records after it start 8 byte aligned in the mapping
+const HEADER_LEN: usize = 16;
+// the opcode, 7 zero bytes and the little endian operand
+const RECORD_LEN: usize = mem::size_of::<ByteCode>();
+const _: () = assert!(RECORD_LEN == 16 && mem::align_of::<ByteCode>() == 8);
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub fn write_code<W: Write>(writer: &mut W, code: &[ByteCode]) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION, 0, 0, 0])?;
+    writer.write_all(&(code.len() as u64).to_le_bytes())?;
+    for binary in code {
+        let mut record = [0u8; RECORD_LEN];
+        record[0] = binary.opcode;
+        record[8..].copy_from_slice(&binary.value.to_le_bytes());
+        writer.write_all(&record)?;
+    }
+    Ok(())
+}
+
+/// Reads the code `write_code` wrote, after the magic, into a vec
+pub fn read_code<R: Read>(reader: &mut R) -> Result<Vec<ByteCode>> {
+    let mut header = [0u8; HEADER_LEN - 4];
+    reader.read_exact(&mut header)?;
+    if header[0] != VERSION || header[1..4] != [0, 0, 0] {
+        return Err(invalid(format!(
+            "unknown aligned .bin version {}",
+            header[0]
+        )));
+    }
+    let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+    let mut code = Vec::new();
+    for index in 0..len {
+        let mut record = [0u8; RECORD_LEN];
+        reader.read_exact(&mut record)?;
+        // checked like validate does, a file must not load copied and fail mapped
+        if record[1..8] != [0; 7] {
+            return Err(invalid(format!(
+                "instruction {} has a corrupt record",
+                index
+            )));
+        }
+        code.push(ByteCode {
+            opcode: record[0],
+            value: u64::from_le_bytes(record[8..].try_into().unwrap()),
+        });
+    }
+    Ok(code)
+}
+
+/// True when the file at `path` starts with the magic of the aligned format
+pub fn is_aligned_bin(path: &str) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == MAGIC
+}
+
+// checks the mapping holds whole records that can be read as ByteCode where they are,
+// returns the instruction count
+fn validate(bytes: &[u8]) -> Result<usize> {
+    if !cfg!(target_endian = "little") {
+        return Err(invalid(String::from(
+            "aligned .bin files can only be mapped on little endian machines",
+        )));
+    }
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(invalid(String::from("not an aligned .bin file")));
+    }
+    if bytes[4] != VERSION || bytes[5..8] != [0, 0, 0] {
+        return Err(invalid(format!(
+            "unknown aligned .bin version {}",
+            bytes[4]
+        )));
+    }
+    let len = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) as usize;
+    let end = len
+        .checked_mul(RECORD_LEN)
+        .and_then(|size| size.checked_add(HEADER_LEN))
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| invalid(format!("the file is too short for {} instructions", len)))?;
+    if !(bytes.as_ptr() as usize + HEADER_LEN).is_multiple_of(mem::align_of::<ByteCode>()) {
+        return Err(invalid(String::from("the mapping is not aligned")));
+    }
+    // the padding must be zero, so the file reads the same in place and copied
+    if let Some(index) = bytes[HEADER_LEN..end]
+        .chunks_exact(RECORD_LEN)
+        .position(|record| record[1..8] != [0; 7])
+    {
+        return Err(invalid(format!(
+            "instruction {} has a corrupt record",
+            index
+        )));
+    }
+    Ok(len)
+}
+
+/// The code of an aligned `.bin` used straight from the mapped file, nothing is copied
+pub struct MappedCode {
+    map: Mmap,
+    len: usize,
+}
+
+impl MappedCode {
+    /// Maps `path` and checks its code can be used in place, returns the code and the
+    /// rest of the program. The file must not be changed while it is mapped.
+    pub fn open(path: &str) -> Result<(MappedCode, Program)> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        let len = validate(&map)?;
+
+        let mut program = Program::default();
+        let mut sections = &map[HEADER_LEN + len * RECORD_LEN..];
+        compiler::read_program_sections(&mut sections, &mut program, len)?;
+        Ok((MappedCode { map, len }, program))
+    }
+}
+
+impl Deref for MappedCode {
+    type Target = [ByteCode];
+
+    fn deref(&self) -> &[ByteCode] {
+        // validate checked the records are in the mapping and aligned, and any bytes
+        // are a valid opcode and operand
+        unsafe {
+            slice::from_raw_parts(
+                self.map.as_ptr().add(HEADER_LEN) as *const ByteCode,
+                self.len,
+            )
+        }
+    }
+}
+
+/// The instructions a vm runs, its own or those of a mapped file
+pub enum Code {
+    Owned(Vec<ByteCode>),
+    Mapped(MappedCode),
+}
+
+impl Deref for Code {
+    type Target = [ByteCode];
+
+    fn deref(&self) -> &[ByteCode] {
+        match self {
+            Code::Owned(code) => code,
+            Code::Mapped(code) => code,
+        }
+    }
+}
+
+impl fmt::Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Code::Owned(code) => write!(f, "Owned({} instructions)", code.len()),
+            Code::Mapped(code) => write!(f, "Mapped({} instructions)", code.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    const CODE: [ByteCode; 3] = [
+        ByteCode {
+            opcode: 1,
+            value: 7,
+        },
+        ByteCode {
+            opcode: 2,
+            value: u64::MAX,
+        },
+        ByteCode {
+            opcode: 3,
+            value: 0,
+        },
+    ];
+
+    fn written() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_code(&mut bytes, &CODE).unwrap();
+        bytes
+    }
+
+    // maps `bytes` from a file, the mapping is page aligned
+    fn open(name: &str, bytes: &[u8]) -> Result<Vec<ByteCode>> {
+        let path = env::temp_dir().join(format!("smachine-{}-{}.bin", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        let opened = MappedCode::open(path.to_str().unwrap()).map(|(code, _)| code.to_vec());
+        let _ = fs::remove_file(&path);
+        opened
+    }
+
+    fn read(bytes: &[u8]) -> Result<Vec<ByteCode>> {
+        read_code(&mut &bytes[4..])
+    }
+
+    fn message(result: Result<Vec<ByteCode>>) -> String {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    #[test]
+    fn mapped_and_copied_code_are_the_same() {
+        let bytes = written();
+        assert_eq!(bytes.len(), HEADER_LEN + CODE.len() * RECORD_LEN);
+        assert_eq!(open("same", &bytes).unwrap(), CODE);
+        assert_eq!(read(&bytes).unwrap(), CODE);
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut bytes = written();
+        bytes[0] = b'X';
+        assert_eq!(message(open("magic", &bytes)), "not an aligned .bin file");
+        assert_eq!(message(open("empty", b"SMMP")), "not an aligned .bin file");
+
+        let mut bytes = written();
+        bytes[4] = 9;
+        assert_eq!(
+            message(open("version", &bytes)),
+            "unknown aligned .bin version 9"
+        );
+        assert_eq!(message(read(&bytes)), "unknown aligned .bin version 9");
+        let mut bytes = written();
+        bytes[6] = 1;
+        assert!(open("header", &bytes).is_err());
+        assert!(read(&bytes).is_err());
+
+        let mut bytes = written();
+        bytes[8] = 4;
+        assert_eq!(
+            message(open("short", &bytes)),
+            "the file is too short for 4 instructions"
+        );
+        bytes[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(open("huge", &bytes).is_err());
+    }
+
+    #[test]
+    fn padding_must_be_zero() {
+        let mut bytes = written();
+        bytes[HEADER_LEN + RECORD_LEN + 3] = 1;
+        assert_eq!(
+            message(open("padding", &bytes)),
+            "instruction 1 has a corrupt record"
+        );
+        assert_eq!(message(read(&bytes)), "instruction 1 has a corrupt record");
+    }
+}
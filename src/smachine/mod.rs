@@ -1,2 +1,22 @@
+pub mod compact;
 pub mod compiler;
+pub mod coverage;
+pub mod crash;
+pub mod dap;
+pub mod debug_info;
+pub mod debugger;
+pub mod expr;
+pub mod gdbstub;
+pub mod jit_cache;
+pub mod jit_dump;
+pub mod leb128;
+pub mod mapped;
+pub mod object;
+pub mod optimizer;
+pub mod preprocess;
+pub mod profile;
+pub mod repl;
+pub mod text;
+pub mod trace;
 pub mod vm;
+pub mod watch;
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+
+use super::compiler::{self, ByteCode, Program};
+use super::debug_info::{self, DebugInfo, SourcePos};
+
+const MAGIC: &[u8; 4] = b"SMOB";
+const VERSION: u8 = 2;
+const SYMBOLS_SECTION: &[u8; 4] = b"SYMS";
+const RELOCATIONS_SECTION: &[u8; 4] = b"RELO";
+
+/// What an operand is relative to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    // a label of the same object, moves with it
+    Local,
+    // a word of the object's .rodata or .data, moves with that section
+    Rodata,
+    Data,
+    // a symbol another object exports
+    Symbol(String),
+}
+
+/// Where the value a relocation adjusts is kept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Site {
+    // the operand of an instruction
+    Code,
+    // a word of .rodata or .data
+    Rodata,
+    Data,
+}
+
+/// A value the linker has to adjust, the value holds the offset from the target.
+/// `index` is the instruction or the word of the section the value is in.
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    pub site: Site,
+    pub index: usize,
+    pub target: Target,
+}
+
+/// One assembled file that still has to be linked.
+/// The code is a `.bin` whose label operands count from the start of this file,
+/// `symbols` are its `.global` labels.
+#[derive(Clone, Debug)]
+pub struct Object {
+    pub program: Program,
+    pub symbols: Vec<(String, usize)>,
+    pub relocations: Vec<Relocation>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Object {
+    /// The symbols this object uses without defining them
+    pub fn imports(&self) -> Vec<&str> {
+        let mut imports: Vec<&str> = self
+            .relocations
+            .iter()
+            .filter_map(|relocation| match &relocation.target {
+                Target::Symbol(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        imports.sort();
+        imports.dedup();
+        imports
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(self.program.code.len() as u64).to_le_bytes())?;
+        for binary in &self.program.code {
+            binary.write_to_bin(&mut writer)?;
+        }
+
+        let mut data = Vec::new();
+        data.write_all(&(self.symbols.len() as u32).to_le_bytes())?;
+        for (name, pos) in &self.symbols {
+            debug_info::write_string(&mut data, name)?;
+            data.write_all(&(*pos as u64).to_le_bytes())?;
+        }
+        compiler::write_section(&mut writer, SYMBOLS_SECTION, &data)?;
+
+        let mut data = Vec::new();
+        data.write_all(&(self.relocations.len() as u32).to_le_bytes())?;
+        for relocation in &self.relocations {
+            data.write_all(&(relocation.index as u64).to_le_bytes())?;
+            let site = match relocation.site {
+                Site::Code => 0,
+                Site::Rodata => 1,
+                Site::Data => 2,
+            };
+            data.write_all(&[site])?;
+            match &relocation.target {
+                Target::Local => data.write_all(&[0])?,
+                Target::Symbol(name) => {
+                    data.write_all(&[1])?;
+                    debug_info::write_string(&mut data, name)?;
+                }
+                Target::Rodata => data.write_all(&[2])?,
+                Target::Data => data.write_all(&[3])?,
+            }
+        }
+        compiler::write_section(&mut writer, RELOCATIONS_SECTION, &data)?;
+
+        compiler::write_program_sections(&mut writer, &self.program)?;
+        writer.flush()
+    }
+
+    pub fn read(path: &str) -> Result<Object> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if &magic != MAGIC || version[0] != VERSION {
+            return Err(invalid(format!("{} is not an object file", path)));
+        }
+
+        let len = debug_info::read_u64(&mut reader)?;
+        let mut code = Vec::new();
+        for _ in 0..len {
+            code.push(ByteCode::read_from_bin(&mut reader)?);
+        }
+        let mut object = Object {
+            program: Program::new(code),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+        };
+
+        let mut tag = [0u8; 4];
+        while reader.read_exact(&mut tag).is_ok() {
+            let len = debug_info::read_u64(&mut reader)?;
+            let data = debug_info::read_bytes(&mut reader, len)?;
+            let data = &mut data.as_slice();
+
+            match &tag {
+                SYMBOLS_SECTION => {
+                    for _ in 0..debug_info::read_u32(data)? {
+                        let name = debug_info::read_string(data)?;
+                        let pos = debug_info::read_u64(data)? as usize;
+                        object.symbols.push((name, pos));
+                    }
+                }
+                RELOCATIONS_SECTION => {
+                    for _ in 0..debug_info::read_u32(data)? {
+                        let index = debug_info::read_u64(data)? as usize;
+                        let mut kind = [0u8; 2];
+                        data.read_exact(&mut kind)?;
+                        let site = match kind[0] {
+                            0 => Site::Code,
+                            1 => Site::Rodata,
+                            2 => Site::Data,
+                            other => {
+                                return Err(invalid(format!("unknown relocation site {}", other)));
+                            }
+                        };
+                        let target = match kind[1] {
+                            0 => Target::Local,
+                            1 => Target::Symbol(debug_info::read_string(data)?),
+                            2 => Target::Rodata,
+                            3 => Target::Data,
+                            other => {
+                                return Err(invalid(format!("unknown relocation kind {}", other)));
+                            }
+                        };
+                        object.relocations.push(Relocation {
+                            site,
+                            index,
+                            target,
+                        });
+                    }
+                }
+                _ => {
+                    let len = object.program.code.len();
+                    compiler::read_program_section(&mut object.program, len, &tag, data)?;
+                }
+            }
+        }
+
+        // the sections can come in any order, so this waits for all of them
+        for relocation in &object.relocations {
+            let len = match relocation.site {
+                Site::Code => object.program.code.len(),
+                Site::Rodata => object.program.rodata.len(),
+                Site::Data => object.program.data.len(),
+            };
+            if relocation.index >= len {
+                return Err(invalid(format!(
+                    "relocation past the end of its section at {}",
+                    relocation.index
+                )));
+            }
+        }
+        Ok(object)
+    }
+}
+
+// `name` or `name (file:line)` when the object knows where the relocated value came from
+fn place(name: &str, object: &Object, relocation: &Relocation) -> String {
+    let location = match relocation.site {
+        Site::Code => object
+            .program
+            .debug
+            .as_ref()
+            .and_then(|debug| debug.location(relocation.index)),
+        Site::Rodata => Some(format!(".rodata word {}", relocation.index)),
+        Site::Data => Some(format!(".data word {}", relocation.index)),
+    };
+    match location {
+        Some(location) => format!("{} ({})", name, location),
+        None => name.to_string(),
+    }
+}
+
+/// Places the objects one after the other and fills in the operands that refer to
+/// labels, the .rodata and .data of all of them are joined the same way.
+/// Execution starts at `entry`, else at the `.entry` of an object, else at `main` or
+/// `start` when one is exported, else at the start of the first object.
+pub fn link(objects: &[(String, Object)], entry: Option<&str>) -> Option<Program> {
+    let mut bases = Vec::new();
+    let (mut len, mut rodata_len, mut data_len) = (0, 0, 0);
+    for (_, object) in objects {
+        bases.push((len, rodata_len, data_len));
+        len += object.program.code.len();
+        rodata_len += object.program.rodata.len();
+        data_len += object.program.data.len();
+    }
+
+    let mut ok = true;
+    let mut symbols: HashMap<&str, (usize, &str)> = HashMap::new();
+    for ((name, object), (base, _, _)) in objects.iter().zip(&bases) {
+        for (symbol, pos) in &object.symbols {
+            if let Some((_, other)) = symbols.get(symbol.as_str()) {
+                println!(
+                    "ERROR: duplicate symbol {} defined in {} and {}",
+                    symbol, other, name
+                );
+                ok = false;
+                continue;
+            }
+            symbols.insert(symbol, (base + pos, name));
+        }
+    }
+
+    let mut program = Program::default();
+    for ((name, object), (base, rodata_base, data_base)) in objects.iter().zip(&bases) {
+        program.code.extend_from_slice(&object.program.code);
+        program.rodata.extend_from_slice(&object.program.rodata);
+        program.data.extend_from_slice(&object.program.data);
+        // an object's .data addresses count from the end of its own .rodata
+        let data_offset = rodata_len - object.program.rodata.len() + data_base;
+        for relocation in &object.relocations {
+            let offset = match &relocation.target {
+                Target::Local => *base,
+                Target::Rodata => *rodata_base,
+                Target::Data => data_offset,
+                Target::Symbol(symbol) => match symbols.get(symbol.as_str()) {
+                    Some((pc, _)) => *pc,
+                    None => {
+                        println!(
+                            "ERROR: undefined reference to {} in {}",
+                            symbol,
+                            place(name, object, relocation)
+                        );
+                        ok = false;
+                        continue;
+                    }
+                },
+            };
+            let value = match relocation.site {
+                Site::Code => &mut program.code[base + relocation.index].value,
+                Site::Rodata => &mut program.rodata[rodata_base + relocation.index],
+                Site::Data => &mut program.data[data_base + relocation.index],
+            };
+            *value = value.wrapping_add(offset as u64);
+        }
+    }
+
+    let declared: Vec<(&String, usize)> = objects
+        .iter()
+        .zip(&bases)
+        .filter_map(|((name, object), (base, _, _))| {
+            object.program.entry.map(|entry| (name, base + entry))
+        })
+        .collect();
+    let exported = ["main", "start"]
+        .into_iter()
+        .find(|name| symbols.contains_key(name));
+    program.entry = match (entry, declared.as_slice()) {
+        (Some(entry), _) => match symbols.get(entry) {
+            Some((pc, _)) => Some(*pc),
+            None => {
+                println!("ERROR: the entry point {} is not defined", entry);
+                ok = false;
+                None
+            }
+        },
+        (None, [(_, pc)]) => Some(*pc),
+        (None, [(first, _), (second, _), ..]) => {
+            println!(
+                "ERROR: the entry point is set in both {} and {}, pick one with --entry",
+                first, second
+            );
+            ok = false;
+            None
+        }
+        (None, []) => exported.map(|name| symbols[name].0),
+    };
+    if !ok {
+        return None;
+    }
+
+    // source positions of the objects that have them, the files one after the other
+    program.debug = objects
+        .iter()
+        .any(|(_, object)| object.program.debug.is_some())
+        .then(|| {
+            let mut merged = DebugInfo::default();
+            for ((_, object), (base, _, _)) in objects.iter().zip(&bases) {
+                let len = object.program.code.len();
+                let Some(debug) = &object.program.debug else {
+                    merged.positions.resize(base + len, SourcePos::default());
+                    continue;
+                };
+                let files = merged.files.len() as u32;
+                merged.files.extend(debug.files.iter().cloned());
+                merged
+                    .positions
+                    .extend(debug.positions.iter().map(|pos| SourcePos {
+                        file: pos.file + files,
+                        ..*pos
+                    }));
+                merged.positions.resize(base + len, SourcePos::default());
+                merged.labels.extend(
+                    debug
+                        .labels
+                        .iter()
+                        .map(|(label, pos)| (label.clone(), base + pos)),
+                );
+            }
+            merged.labels.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+            merged
+        });
+
+    Some(program)
+}
+
+/// Reads the objects and links them, reporting the files that can not be read
+pub fn link_files(paths: &[String], entry: Option<&str>) -> Option<Program> {
+    let mut objects = Vec::new();
+    for path in paths {
+        match Object::read(path) {
+            Ok(object) => objects.push((path.clone(), object)),
+            Err(err) => {
+                eprintln!("Error when reading {}: {}", path, err);
+                return None;
+            }
+        }
+    }
+    link(&objects, entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble_object;
+
+    fn relocations(object: &Object) -> Vec<(Site, usize, Target)> {
+        object
+            .relocations
+            .iter()
+            .map(|relocation| (relocation.site, relocation.index, relocation.target.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn label_plus_offset_is_relocated() {
+        let object = assemble_object("start:\npush start + 2\njmp 3 + start\n", "a.s").unwrap();
+        assert_eq!(object.program.code[0].value, 2);
+        assert_eq!(object.program.code[1].value, 3);
+        assert_eq!(
+            relocations(&object),
+            vec![
+                (Site::Code, 0, Target::Local),
+                (Site::Code, 1, Target::Local)
+            ]
+        );
+    }
+
+    #[test]
+    fn number_minus_label_is_rejected() {
+        assert!(assemble_object("start:\npush 5 - start\n", "a.s").is_none());
+        assert!(assemble_object("push 5 - other\n", "a.s").is_none());
+        assert!(assemble_object("start:\npush start + start\n", "a.s").is_none());
+    }
+
+    #[test]
+    fn label_differences_are_constants() {
+        let object = assemble_object("a:\npush 1\nb:\npush b - a\n", "a.s").unwrap();
+        assert_eq!(object.program.code[1].value, 1);
+        assert!(object.relocations.is_empty());
+    }
+
+    #[test]
+    fn imports_are_relocated_against_their_symbol() {
+        let object = assemble_object("push helper + 1\ncall helper\n", "a.s").unwrap();
+        assert_eq!(object.program.code[0].value, 1);
+        assert_eq!(object.imports(), vec!["helper"]);
+        assert_eq!(
+            relocations(&object),
+            vec![
+                (Site::Code, 0, Target::Symbol(String::from("helper"))),
+                (Site::Code, 1, Target::Symbol(String::from("helper"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn words_are_relocated() {
+        let source =
+            ".rodata\ntable: .word one, table + 1\n.data\nptr: .word helper\n.text\none:\npush 1\n";
+        let object = assemble_object(source, "a.s").unwrap();
+        assert_eq!(object.program.rodata, vec![0, 1]);
+        assert_eq!(
+            relocations(&object),
+            vec![
+                (Site::Rodata, 0, Target::Local),
+                (Site::Rodata, 1, Target::Rodata),
+                (Site::Data, 0, Target::Symbol(String::from("helper"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn relocations_are_written_and_read() {
+        let source = ".data\nptr: .word helper\n.text\none:\npush one + 1\n";
+        let object = assemble_object(source, "a.s").unwrap();
+        let path = std::env::temp_dir().join(format!("smachine-test-{}.o", std::process::id()));
+        let path = path.to_str().unwrap();
+        object.write(path).unwrap();
+        let read = Object::read(path);
+        let _ = fs::remove_file(path);
+
+        let read = read.unwrap();
+        assert_eq!(relocations(&read), relocations(&object));
+        assert_eq!(read.program.data, object.program.data);
+    }
+
+    #[test]
+    fn link_moves_code_and_words() {
+        let first = assemble_object(
+            ".rodata\n.word 7, 8\n.text\n.global helper\nhelper:\npush 1\nret\n",
+            "b.s",
+        )
+        .unwrap();
+        let second = assemble_object(
+            ".rodata\ntable: .word one, table + 1\n.data\nptr: .word helper + 1\n.text\none:\npush table\ncall helper\n",
+            "a.s",
+        )
+        .unwrap();
+        let base = first.program.code.len() as u64;
+        let program = link(
+            &[(String::from("b.o"), first), (String::from("a.o"), second)],
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(program.rodata, vec![7, 8, base, 3]);
+        // .data is addressed after all of .rodata
+        assert_eq!(program.data, vec![1]);
+        assert_eq!(program.code[base as usize].value, 2);
+        assert_eq!(program.code[base as usize + 1].value, 0);
+    }
+
+    #[test]
+    fn link_reports_undefined_symbols() {
+        let object = assemble_object(".data\n.word missing\n", "a.s").unwrap();
+        assert!(link(&[(String::from("a.o"), object)], None).is_none());
+    }
+}
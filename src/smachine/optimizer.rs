@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+
+use super::compiler::{ByteCode, Program, TokenType};
+
+fn kind(binary: &ByteCode) -> TokenType {
+    TokenType::from(binary.opcode)
+}
+
+fn is_jump(binary: &ByteCode) -> bool {
+    matches!(
+        kind(binary),
+        TokenType::Jmp | TokenType::Jeq | TokenType::Jnz | TokenType::Call
+    )
+}
+
+fn push(value: u64) -> ByteCode {
+    ByteCode {
+        opcode: TokenType::Push as u8,
+        value,
+    }
+}
+
+// `a` is pushed first, `b` ends on top of the stack. Mirrors what the vm does
+// and gives up on anything that would wrap, the vm is left to do that.
+fn fold(a: u64, b: u64, op: TokenType) -> Option<u64> {
+    let f64_of = f64::from_bits;
+    let f32_of = |bits: u64| f32::from_bits(bits as u32);
+    match op {
+        TokenType::Uadd8 => (b as u8).checked_add(a as u8).map(|v| v as u64),
+        TokenType::Usub8 => (a as u8).checked_sub(b as u8).map(|v| v as u64),
+        TokenType::Uadd16 => (b as u16).checked_add(a as u16).map(|v| v as u64),
+        TokenType::Usub16 => (a as u16).checked_sub(b as u16).map(|v| v as u64),
+        TokenType::Uadd32 => (b as u32).checked_add(a as u32).map(|v| v as u64),
+        TokenType::Usub32 => (a as u32).checked_sub(b as u32).map(|v| v as u64),
+        TokenType::Uadd64 => b.checked_add(a),
+        TokenType::Usub64 => a.checked_sub(b),
+        TokenType::Add8 => (b as i8).checked_add(a as i8).map(|v| v as u64),
+        TokenType::Add16 => (b as i16).checked_add(a as i16).map(|v| v as u64),
+        TokenType::Sub16 => (a as i16).checked_sub(b as i16).map(|v| v as u64),
+        TokenType::Add32 => (b as i32).checked_add(a as i32).map(|v| v as u64),
+        TokenType::Sub32 => (a as i32).checked_sub(b as i32).map(|v| v as u64),
+        TokenType::Add64 => (b as i64).checked_add(a as i64).map(|v| v as u64),
+        TokenType::Sub64 => (a as i64).checked_sub(b as i64).map(|v| v as u64),
+        TokenType::Addf64 => Some((f64_of(b) + f64_of(a)).to_bits()),
+        TokenType::Subf64 => Some((f64_of(a) - f64_of(b)).to_bits()),
+        TokenType::Addf32 => Some((f32_of(b) + f32_of(a)).to_bits() as u64),
+        TokenType::Subf32 => Some((f32_of(a) - f32_of(b)).to_bits() as u64),
+        TokenType::Cmp => (b as i64).checked_sub(a as i64).map(|v| v as u64),
+        _ => None,
+    }
+}
+
+// Points every jump at the end of a chain of unconditional jumps
+fn thread_jumps(bin: &mut [ByteCode]) -> bool {
+    let mut changed = false;
+    for i in 0..bin.len() {
+        if !is_jump(&bin[i]) {
+            continue;
+        }
+
+        let mut target = bin[i].value as usize;
+        // bounded so `a: jmp a` does not hang the optimizer
+        for _ in 0..bin.len() {
+            match bin.get(target) {
+                Some(next)
+                    if matches!(kind(next), TokenType::Jmp) && next.value as usize != target =>
+                {
+                    target = next.value as usize;
+                }
+                _ => break,
+            }
+        }
+
+        if target as u64 != bin[i].value {
+            bin[i].value = target as u64;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Rewrites small windows in place, removed instructions become None
+fn peephole(code: &mut [Option<ByteCode>], targets: &HashSet<usize>) -> bool {
+    let mut changed = false;
+    // nothing can be merged across an instruction somebody jumps to
+    let free = |i: usize| !targets.contains(&i);
+
+    for i in 0..code.len() {
+        let Some(first) = code[i] else { continue };
+        let second = code.get(i + 1).copied().flatten();
+        let third = code.get(i + 2).copied().flatten();
+
+        match (kind(&first), second.as_ref().map(kind)) {
+            // push x; pop and dup; pop do nothing
+            (TokenType::Push, Some(TokenType::Pop)) | (TokenType::Dup, Some(TokenType::Pop))
+                if free(i + 1) =>
+            {
+                code[i] = None;
+                code[i + 1] = None;
+                changed = true;
+            }
+            (TokenType::Push, Some(TokenType::Inc)) if free(i + 1) && first.value != u64::MAX => {
+                code[i] = Some(push(first.value + 1));
+                code[i + 1] = None;
+                changed = true;
+            }
+            (TokenType::Push, Some(TokenType::Push)) if free(i + 1) && free(i + 2) => {
+                let b = second.unwrap().value;
+                if let Some(op) = third
+                    && let Some(value) = fold(first.value, b, kind(&op))
+                {
+                    code[i] = Some(push(value));
+                    code[i + 1] = None;
+                    code[i + 2] = None;
+                    changed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    changed
+}
+
+// Drops whatever follows a halt or jmp until something jumps back in
+fn remove_unreachable(code: &mut [Option<ByteCode>], targets: &HashSet<usize>) -> bool {
+    let mut changed = false;
+    let mut reachable = true;
+    for (i, slot) in code.iter_mut().enumerate() {
+        if targets.contains(&i) {
+            reachable = true;
+        }
+
+        let Some(binary) = slot else { continue };
+        if !reachable {
+            *slot = None;
+            changed = true;
+            continue;
+        }
+
+        if matches!(kind(binary), TokenType::Halt | TokenType::Jmp) {
+            reachable = false;
+        }
+    }
+    changed
+}
+
+// Removes the deleted instructions and moves every jump to the new indices,
+// `origin` follows along so it keeps the original index of each instruction
+fn compact(code: Vec<Option<ByteCode>>, origin: &mut Vec<usize>) -> Vec<ByteCode> {
+    *origin = origin
+        .iter()
+        .zip(&code)
+        .filter(|(_, slot)| slot.is_some())
+        .map(|(old, _)| *old)
+        .collect();
+
+    // an index that got removed maps to the next instruction that survived
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for slot in &code {
+        new_index.push(kept as u64);
+        if slot.is_some() {
+            kept += 1;
+        }
+    }
+    new_index.push(kept as u64);
+
+    code.into_iter()
+        .flatten()
+        .map(|mut binary| {
+            if is_jump(&binary)
+                && let Some(index) = new_index.get(binary.value as usize)
+            {
+                binary.value = *index;
+            }
+            binary
+        })
+        .collect()
+}
+
+/// Simplifies the bytecode without changing what it does:
+/// folds constant arithmetic, drops `push x; pop` and `dup; pop`, threads jumps
+/// to jumps and removes code that can not be reached after `halt`/`jmp`.
+/// `jmpp` jumps to addresses that were pushed as plain values, which can not be
+/// told apart from other numbers, so programs using it only get their jumps threaded.
+pub fn optimize(bin: Vec<ByteCode>) -> Vec<ByteCode> {
+    optimize_with_origin(bin, 0).0
+}
+
+/// Optimizes the program and moves its debug info along with the code
+pub fn optimize_program(program: Program) -> Program {
+    let (code, origin) = optimize_with_origin(program.code, program.entry.unwrap_or(0));
+    // the entry moves to the instruction that took its place, like a jump target
+    let entry = program
+        .entry
+        .map(|entry| origin.partition_point(|old| *old < entry));
+    Program {
+        code,
+        debug: program.debug.map(|debug| debug.remap(&origin)),
+        entry,
+        ..program
+    }
+}
+
+// also returns the original index of every instruction that is left,
+// execution starts at `entry` so it counts as a jump target
+fn optimize_with_origin(mut bin: Vec<ByteCode>, entry: usize) -> (Vec<ByteCode>, Vec<usize>) {
+    let mut origin: Vec<usize> = (0..bin.len()).collect();
+    let movable = !bin
+        .iter()
+        .any(|binary| matches!(kind(binary), TokenType::Jmpp));
+
+    loop {
+        let mut changed = thread_jumps(&mut bin);
+        if movable {
+            let mut targets: HashSet<usize> = bin
+                .iter()
+                .filter(|binary| is_jump(binary))
+                .map(|binary| binary.value as usize)
+                .collect();
+            targets.insert(0);
+            // where the entry is now, instructions before it may be gone
+            targets.insert(origin.partition_point(|old| *old < entry));
+
+            let mut code: Vec<Option<ByteCode>> = bin.iter().copied().map(Some).collect();
+            changed |= peephole(&mut code, &targets);
+            changed |= remove_unreachable(&mut code, &targets);
+            bin = compact(code, &mut origin);
+        }
+
+        if !changed {
+            return (bin, origin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::{assemble, byte_code_compiler};
+
+    fn code(source: &str) -> Vec<ByteCode> {
+        byte_code_compiler(source).unwrap()
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimize(code("push 1\npush 2\nuadd64\n")), code("push 3\n"));
+        assert_eq!(optimize(code("push 7\npush 2\nsub64\n")), code("push 5\n"));
+        assert_eq!(
+            optimize(code("push 1.5\npush 2.0\naddf64\n")),
+            code("push 3.5\n")
+        );
+        // folds again with what the first fold left
+        assert_eq!(
+            optimize(code("push 1\npush 2\nuadd64\npush 3\nuadd64\n")),
+            code("push 6\n")
+        );
+        assert_eq!(optimize(code("push 4\ninc\n")), code("push 5\n"));
+    }
+
+    #[test]
+    fn keeps_what_would_overflow() {
+        let underflow = code("push 0\npush 1\nusub64\n");
+        assert_eq!(optimize(underflow.clone()), underflow);
+        let overflow = code("push 255\npush 1\nuadd8\n");
+        assert_eq!(optimize(overflow.clone()), overflow);
+    }
+
+    #[test]
+    fn removes_dead_pairs_and_unreachable_code() {
+        assert_eq!(
+            optimize(code("push 1\npop\ndup\npop\nhalt\n")),
+            code("halt\n")
+        );
+        assert_eq!(
+            optimize(code("jmp end\npush 1\nprt\nend:\nhalt\n")),
+            code("jmp end\nend:\nhalt\n")
+        );
+    }
+
+    #[test]
+    fn remaps_targets_across_removed_code() {
+        let source = "
+            push 1
+            push 2
+            uadd64
+            jnz done
+            push 7
+            pop
+            back:
+            push 8
+            prt
+            done:
+            push 9
+            jeq back
+            call back
+        ";
+        let expected = "
+            push 3
+            jnz done
+            back:
+            push 8
+            prt
+            done:
+            push 9
+            jeq back
+            call back
+        ";
+        assert_eq!(optimize(code(source)), code(expected));
+    }
+
+    #[test]
+    fn threads_jumps_to_jumps() {
+        let optimized = optimize(code("jmp a\na:\njmp b\nb:\nhalt\n"));
+        assert_eq!(optimized[0].value, optimized.len() as u64 - 1);
+    }
+
+    #[test]
+    fn jmpp_programs_are_not_moved() {
+        let bin = code("push 3\npush 1\npop\njmpp\nhalt\n");
+        assert_eq!(optimize(bin.clone()), bin);
+    }
+
+    #[test]
+    fn remaps_the_entry_and_debug_info() {
+        let source = ".entry main\npush 7\npop\nmain:\npush 1\npush 2\nuadd64\nprt\n";
+        let program = assemble(source, "test.s").unwrap();
+        assert_eq!(program.entry, Some(2));
+
+        let optimized = optimize_program(program);
+        assert_eq!(optimized.entry, Some(0));
+        assert_eq!(optimized.code[..2], code("push 3\nprt\n")[..2]);
+        let debug = optimized.debug.unwrap();
+        assert_eq!(debug.label("main"), Some(0));
+        assert_eq!(debug.positions.len(), optimized.code.len());
+        // the folded push keeps the line of its first instruction
+        assert_eq!(debug.positions[0].line, 5);
+        assert_eq!(debug.positions[1].line, 8);
+    }
+}
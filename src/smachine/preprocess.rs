@@ -0,0 +1,613 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::compiler;
+
+// macros calling macros deeper than this are reported as runaway recursion
+const MAX_MACRO_DEPTH: usize = 64;
+// the most lines all the expansions together can add, macros calling a macro
+// several times grow exponentially well before they get too deep
+const MAX_EXPANDED_LINES: usize = 1 << 18;
+
+/// A line of source after preprocessing, with where it came from
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub text: String,
+    pub file: u32,
+    pub line: u32,
+    // set for lines made by a macro, their tokens all point at the invocation
+    pub column: Option<u32>,
+}
+
+/// The lines of one file as they were written
+pub fn lines_of(code: &str, file: u32) -> Vec<Line> {
+    code.lines()
+        .enumerate()
+        .map(|(index, text)| Line {
+            text: text.to_string(),
+            file,
+            line: index as u32 + 1,
+            column: None,
+        })
+        .collect()
+}
+
+/// Splits a line into words, keeping the byte column where each one starts.
+/// A character literal stays one word even when it is a space, like `' '`.
+pub fn words(line: &str) -> Vec<(usize, &str)> {
+    let bytes = line.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+            if bytes[i] == b'\'' {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'\'' {
+                    // skips escaped quotes
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            i += 1;
+        }
+        i = i.min(bytes.len());
+        out.push((start, &line[start..i]));
+    }
+    out
+}
+
+/// Splits macro arguments and data words on commas, outside of character literals
+pub fn split_args(text: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ',' if !quoted => {
+                out.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    out.push(text[start..].trim());
+    out.retain(|arg| !arg.is_empty());
+    out
+}
+
+/// `file:line` for messages
+pub fn origin(files: &[String], file: u32, line: u32) -> String {
+    let name = files.get(file as usize).map_or("<input>", String::as_str);
+    format!("{}:{}", name, line)
+}
+
+// a file name like "<repl>" is not a path, files it includes are found from the cwd
+fn directory_of(name: &str) -> PathBuf {
+    if name.starts_with('<') {
+        return PathBuf::new();
+    }
+    Path::new(name)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+struct Includer<'a> {
+    files: &'a mut Vec<String>,
+    // canonical paths of the files being included right now, outermost first
+    stack: Vec<PathBuf>,
+    // everything read so far, `.import` skips these
+    seen: HashSet<PathBuf>,
+    out: Vec<Line>,
+}
+
+impl Includer<'_> {
+    fn error(&self, message: &str, line: &Line) {
+        println!(
+            "ERROR: {} at {}",
+            message,
+            origin(self.files, line.file, line.line)
+        );
+    }
+
+    fn include(&mut self, lines: Vec<Line>) -> bool {
+        for line in lines {
+            let words = words(&line.text);
+            let once = match words.first() {
+                Some((_, ".include")) => false,
+                Some((_, ".import")) => true,
+                _ => {
+                    self.out.push(line);
+                    continue;
+                }
+            };
+            let Some((column, _)) = words.get(1) else {
+                self.error("expected a file name like \"lib.s\"", &line);
+                return false;
+            };
+            let name = line.text[*column..].trim().trim_matches('"');
+
+            // relative to the file the directive is in
+            let including = &self.files[line.file as usize];
+            let path = directory_of(including).join(name);
+            let canonical = match fs::canonicalize(&path) {
+                Ok(canonical) => canonical,
+                Err(err) => {
+                    self.error(
+                        &format!("could not include {}: {}", path.display(), err),
+                        &line,
+                    );
+                    return false;
+                }
+            };
+            if self.stack.contains(&canonical) {
+                let chain: Vec<String> = self
+                    .stack
+                    .iter()
+                    .chain(std::iter::once(&canonical))
+                    .map(|path| path.display().to_string())
+                    .collect();
+                self.error(&format!("include cycle: {}", chain.join(" -> ")), &line);
+                return false;
+            }
+            if once && self.seen.contains(&canonical) {
+                continue;
+            }
+
+            let code = match fs::read_to_string(&canonical) {
+                Ok(code) => code,
+                Err(err) => {
+                    self.error(
+                        &format!("could not include {}: {}", path.display(), err),
+                        &line,
+                    );
+                    return false;
+                }
+            };
+            self.files.push(path.display().to_string());
+            let file = self.files.len() as u32 - 1;
+            self.seen.insert(canonical.clone());
+            self.stack.push(canonical);
+            if !self.include(lines_of(&code, file)) {
+                return false;
+            }
+            self.stack.pop();
+        }
+        true
+    }
+}
+
+/// Replaces `.include "file.s"` lines with the lines of the file, `.import` does the same
+/// once per file. Paths are relative to the file the directive is in, the files that are
+/// read are added to `files`.
+pub fn include_files(lines: Vec<Line>, files: &mut Vec<String>) -> Option<Vec<Line>> {
+    // the top file counts for cycles when it is a real file
+    let root: Vec<PathBuf> = files
+        .first()
+        .and_then(|name| fs::canonicalize(name).ok())
+        .into_iter()
+        .collect();
+    let mut includer = Includer {
+        seen: root.iter().cloned().collect(),
+        stack: root,
+        files,
+        out: Vec::new(),
+    };
+    includer.include(lines).then_some(includer.out)
+}
+
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    // labels defined in the body, renamed in every expansion
+    labels: Vec<String>,
+    // where the .macro line is, for a second definition
+    origin: String,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+// replaces whole identifiers found in `names`
+fn rename(text: &str, names: &HashMap<&str, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if is_ident(c) {
+            word.push(c);
+            continue;
+        }
+        match names.get(word.as_str()) {
+            Some(new) => out.push_str(new),
+            None => out.push_str(&word),
+        }
+        word.clear();
+        out.push(c);
+    }
+    out.pop();
+    out
+}
+
+struct Expander<'a> {
+    files: &'a [String],
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    // lines added by expansions so far
+    expanded: usize,
+    out: Vec<Line>,
+}
+
+impl Expander<'_> {
+    fn error(&self, message: &str, line: &Line) {
+        println!(
+            "ERROR: {} at {}",
+            message,
+            origin(self.files, line.file, line.line)
+        );
+    }
+
+    // `\param` becomes the argument, `\@` the number of the expansion
+    fn substitute(
+        &self,
+        text: &str,
+        args: &HashMap<&str, &str>,
+        id: usize,
+    ) -> Result<String, String> {
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            if chars.peek() == Some(&'@') {
+                chars.next();
+                out.push_str(&id.to_string());
+                continue;
+            }
+            let mut name = String::new();
+            while let Some(c) = chars.peek().copied().filter(|c| is_ident(*c)) {
+                name.push(c);
+                chars.next();
+            }
+            match args.get(name.as_str()) {
+                Some(value) => out.push_str(value),
+                None => return Err(format!("unknown macro parameter \\{}", name)),
+            }
+        }
+        Ok(out)
+    }
+
+    fn expand(&mut self, line: Line, depth: usize) -> bool {
+        let columns = words(&line.text);
+        let words: Vec<&str> = columns.iter().map(|(_, word)| *word).collect();
+        // labels in front of an invocation stay where they are
+        let start = words
+            .iter()
+            .position(|word| !word.ends_with(':'))
+            .unwrap_or(words.len());
+        let Some(name) = words
+            .get(start)
+            .filter(|word| self.macros.contains_key(**word))
+        else {
+            self.out.push(line);
+            return true;
+        };
+        if depth >= MAX_MACRO_DEPTH {
+            self.error(
+                &format!(
+                    "macro {} nests deeper than {} expansions",
+                    name, MAX_MACRO_DEPTH
+                ),
+                &line,
+            );
+            return false;
+        }
+
+        let column = line.column.unwrap_or(columns[start].0 as u32 + 1);
+        if start > 0 {
+            self.out.push(Line {
+                text: words[..start].join(" "),
+                ..line.clone()
+            });
+        }
+
+        let macro_def = &self.macros[*name];
+        // `a, b` when the arguments are separated by commas, `a b` otherwise
+        let rest = columns
+            .get(start + 1)
+            .map_or("", |(column, _)| &line.text[*column..]);
+        let mut values = split_args(rest);
+        if values.len() != macro_def.params.len() {
+            values = words[start + 1..].to_vec();
+        }
+        if values.len() != macro_def.params.len() {
+            self.error(
+                &format!(
+                    "macro {} takes {} arguments, got {}",
+                    name,
+                    macro_def.params.len(),
+                    values.len()
+                ),
+                &line,
+            );
+            return false;
+        }
+
+        self.expanded += macro_def.body.len();
+        if self.expanded > MAX_EXPANDED_LINES {
+            self.error(
+                &format!(
+                    "macros expand to more than {} lines, from macro {}",
+                    MAX_EXPANDED_LINES, name
+                ),
+                &line,
+            );
+            return false;
+        }
+        self.expansions += 1;
+        let id = self.expansions;
+        let args: HashMap<&str, &str> = macro_def
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(values)
+            .collect();
+        let labels: HashMap<&str, String> = macro_def
+            .labels
+            .iter()
+            .map(|label| (label.as_str(), format!("{}@{}", label, id)))
+            .collect();
+
+        let mut body = Vec::new();
+        for text in &macro_def.body {
+            match self.substitute(text, &args, id) {
+                Ok(text) => body.push(Line {
+                    text: rename(&text, &labels),
+                    column: Some(column),
+                    ..line.clone()
+                }),
+                Err(message) => {
+                    self.error(&format!("{} in macro {}", message, name), &line);
+                    return false;
+                }
+            }
+        }
+        body.into_iter().all(|line| self.expand(line, depth + 1))
+    }
+}
+
+/// Collects the `.macro name params ... .endm` definitions and replaces every use of
+/// them with the body, with `\param` substituted and the body's labels made unique
+pub fn expand_macros(lines: Vec<Line>, files: &[String]) -> Option<Vec<Line>> {
+    let mut expander = Expander {
+        files,
+        macros: HashMap::new(),
+        expansions: 0,
+        expanded: 0,
+        out: Vec::new(),
+    };
+
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        let mut words = line.text.split_whitespace();
+        match words.next() {
+            Some(".macro") => {
+                let Some(name) = words.next() else {
+                    expander.error(".macro needs a name", &line);
+                    return None;
+                };
+                if compiler::is_mnemonic(name) {
+                    expander.error(
+                        &format!("macro {} has the name of an instruction", name),
+                        &line,
+                    );
+                    return None;
+                }
+                if let Some(first) = expander.macros.get(name) {
+                    expander.error(
+                        &format!("macro {} is already defined at {}", name, first.origin),
+                        &line,
+                    );
+                    return None;
+                }
+                let params = words
+                    .flat_map(|word| word.split(','))
+                    .filter(|param| !param.is_empty())
+                    .map(String::from)
+                    .collect();
+
+                let mut body = Vec::new();
+                loop {
+                    let Some(next) = lines.next() else {
+                        expander.error(&format!(".macro {} is missing its .endm", name), &line);
+                        return None;
+                    };
+                    match next.text.split_whitespace().next() {
+                        Some(".endm") => break,
+                        Some(".macro") => {
+                            expander.error("macros can not be defined inside a macro", &next);
+                            return None;
+                        }
+                        _ => body.push(next.text),
+                    }
+                }
+                // `1:` is already found by the nearest `1b`/`1f`, it keeps its name
+                let labels = body
+                    .iter()
+                    .flat_map(|text| text.split_whitespace())
+                    .filter_map(|word| word.strip_suffix(':'))
+                    .filter(|label| !label.bytes().all(|byte| byte.is_ascii_digit()))
+                    .map(String::from)
+                    .collect();
+                expander.macros.insert(
+                    name.to_string(),
+                    Macro {
+                        params,
+                        body,
+                        labels,
+                        origin: origin(files, line.file, line.line),
+                    },
+                );
+            }
+            Some(".endm") => {
+                expander.error(".endm without a .macro", &line);
+                return None;
+            }
+            _ => {
+                if !expander.expand(line, 0) {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(expander.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    fn expand(code: &str) -> Option<Vec<Line>> {
+        expand_macros(lines_of(code, 0), &[String::from("test.s")])
+    }
+
+    // writes `files` to a new directory and preprocesses the first one
+    fn include(name: &str, files: &[(&str, &str)]) -> Option<Vec<Line>> {
+        let dir = std::env::temp_dir().join(format!("smachine-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, code) in files {
+            fs::write(dir.join(file), code).unwrap();
+        }
+        let mut names = vec![dir.join(files[0].0).display().to_string()];
+        let lines = include_files(lines_of(files[0].1, 0), &mut names);
+        let _ = fs::remove_dir_all(&dir);
+        lines
+    }
+
+    #[test]
+    fn include_cycles() {
+        let files = [
+            ("a.s", "push 1\n.include \"b.s\"\n"),
+            ("b.s", ".include \"a.s\"\n"),
+        ];
+        assert!(include("cycle", &files).is_none());
+        // the same file twice in a row is no cycle
+        let files = [
+            ("a.s", ".include \"b.s\"\n.include \"b.s\"\n"),
+            ("b.s", "pop\n"),
+        ];
+        assert_eq!(texts(&include("twice", &files).unwrap()), ["pop", "pop"]);
+    }
+
+    #[test]
+    fn imports_are_read_once() {
+        let files = [
+            (
+                "main.s",
+                ".import \"lib.s\"\n.import \"two.s\"\n.import \"lib.s\"\nhalt\n",
+            ),
+            ("lib.s", "dup\n"),
+            ("two.s", ".import \"lib.s\"\npop\n"),
+        ];
+        assert_eq!(
+            texts(&include("import", &files).unwrap()),
+            ["dup", "pop", "halt"]
+        );
+        // a file importing itself is still a cycle
+        let files = [
+            ("main.s", ".import \"lib.s\"\n"),
+            ("lib.s", ".import \"lib.s\"\n"),
+        ];
+        assert!(include("self-import", &files).is_none());
+    }
+
+    #[test]
+    fn macro_arguments() {
+        let code = ".macro pair a, b\npush \\a\npush \\b\n.endm\npair 1, 2\nl: pair 3 4\n";
+        let lines = expand(code).unwrap();
+        assert_eq!(
+            texts(&lines),
+            ["push 1", "push 2", "l:", "push 3", "push 4"]
+        );
+        assert!(expand(".macro pair a, b\n.endm\npair 1\n").is_none());
+        assert!(expand(".macro one\npush \\x\n.endm\none\n").is_none());
+    }
+
+    #[test]
+    fn macros_are_defined_once() {
+        assert!(expand(".macro one\npush 1\n.endm\n.macro one\npush 2\n.endm\n").is_none());
+    }
+
+    #[test]
+    fn expansion_limits() {
+        assert!(expand(".macro again\nagain\n.endm\nagain\n").is_none());
+
+        // each level doubles, 2^30 lines from only 30 levels
+        let mut code = String::from(".macro m0\npop\n.endm\n");
+        for level in 1..=30 {
+            code.push_str(&format!(
+                ".macro m{}\nm{}\nm{}\n.endm\n",
+                level,
+                level - 1,
+                level - 1
+            ));
+        }
+        code.push_str("m30\n");
+        assert!(expand(&code).is_none());
+    }
+
+    #[test]
+    fn included_lines_know_their_file() {
+        let dir = std::env::temp_dir().join(format!("smachine-origin-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let main = dir.join("main.s");
+        fs::write(&main, "push 1\n.include \"lib/a.s\"\nhalt\n").unwrap();
+        // found next to lib/a.s, not next to main.s
+        fs::write(dir.join("lib/a.s"), "dup\n.include \"b.s\"\n").unwrap();
+        fs::write(dir.join("lib/b.s"), "\nbadop\n").unwrap();
+
+        let mut names = vec![main.display().to_string()];
+        let code = fs::read_to_string(&main).unwrap();
+        let lines = include_files(lines_of(&code, 0), &mut names).unwrap();
+        let origins: Vec<String> = lines
+            .iter()
+            .filter(|line| !line.text.is_empty())
+            .map(|line| origin(&names, line.file, line.line))
+            .collect();
+        let path = |file: &str| dir.join(file).display().to_string();
+        assert_eq!(
+            origins,
+            [
+                format!("{}:1", path("main.s")),
+                format!("{}:1", path("lib/a.s")),
+                format!("{}:2", path("lib/b.s")),
+                format!("{}:3", path("main.s")),
+            ]
+        );
+        assert_eq!(origin(&names, 9, 4), "<input>:4");
+
+        // and the assembler reports the error at the included line
+        assert!(compiler::compile_file(&main.display().to_string()).is_none());
+        fs::write(dir.join("lib/b.s"), "\npop\n").unwrap();
+        let program = compiler::compile_file(&main.display().to_string()).unwrap();
+        let debug = program.debug.unwrap();
+        assert_eq!(debug.location(2), Some(format!("{}:2:1", path("lib/b.s"))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
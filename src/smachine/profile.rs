@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use super::compiler::ByteCode;
+use super::debug_info::DebugInfo;
+use super::vm::CallFrame;
+
+// rows shown in each table of the report
+const REPORT_ROWS: usize = 20;
+
+/// Instruction counts collected while the vm runs.
+/// Instructions run inside jit compiled code are only seen as the jump or call
+/// that entered it, their time is kept separately in `native`.
+#[derive(Debug, Default)]
+pub struct Profile {
+    counts: Vec<u64>,
+    // procedures on the call stack, outermost first -> instructions run there
+    stacks: HashMap<Vec<usize>, u64>,
+    current: Vec<usize>,
+    native: Duration,
+    native_entries: u64,
+    total: Duration,
+}
+
+fn name(debug: Option<&DebugInfo>, proc_pc: usize) -> String {
+    match debug.and_then(|debug| debug.label_at(proc_pc)) {
+        Some(label) => label.to_string(),
+        None => format!("proc_{}", proc_pc),
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Profile {
+        Self {
+            counts: vec![0; len],
+            ..Profile::default()
+        }
+    }
+
+    /// Counts the instruction at pc, `frames` are the calls it runs inside of
+    pub fn count(&mut self, pc: usize, frames: &[CallFrame]) {
+        if let Some(count) = self.counts.get_mut(pc) {
+            *count += 1;
+        }
+        self.current.clear();
+        self.current
+            .extend(frames.iter().map(|frame| frame.proc_pc));
+        match self.stacks.get_mut(self.current.as_slice()) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.current.clone(), 1);
+            }
+        }
+    }
+
+    pub fn add_native(&mut self, time: Duration) {
+        self.native += time;
+        self.native_entries += 1;
+    }
+
+    pub fn set_total(&mut self, time: Duration) {
+        self.total = time;
+    }
+
+    fn instructions(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// One line per call stack, `main;f;g count`, the input flamegraph.pl expects
+    pub fn folded(&self, debug: Option<&DebugInfo>) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let mut line = String::from("main");
+                for proc_pc in stack {
+                    line.push(';');
+                    line.push_str(&name(debug, *proc_pc));
+                }
+                format!("{} {}", line, count)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    pub fn report(&self, bin: &[ByteCode], debug: Option<&DebugInfo>) -> String {
+        let total = self.instructions();
+        let mut out = String::new();
+        let interpreted = self.total.saturating_sub(self.native);
+        let _ = writeln!(
+            out,
+            "Profile: {} instructions interpreted in {:.3?}, {:.3?} in jit code ({} entries)",
+            total, interpreted, self.native, self.native_entries
+        );
+
+        // (self, total) per procedure, None is the top level
+        let mut functions: HashMap<Option<usize>, (u64, u64)> = HashMap::new();
+        for (stack, count) in &self.stacks {
+            functions.entry(stack.last().copied()).or_default().0 += count;
+            let mut seen: Vec<Option<usize>> = vec![None];
+            seen.extend(stack.iter().map(|proc_pc| Some(*proc_pc)));
+            seen.sort();
+            // recursion counts once towards the total
+            seen.dedup();
+            for function in seen {
+                functions.entry(function).or_default().1 += count;
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let _ = writeln!(out, "\nFunctions:");
+        let _ = writeln!(
+            out,
+            "  {:>12} {:>7}  {:>12} {:>7}  name",
+            "self", "%", "total", "%"
+        );
+        for (function, (own, inclusive)) in functions.iter().take(REPORT_ROWS) {
+            let function = match function {
+                Some(proc_pc) => name(debug, *proc_pc),
+                None => String::from("main"),
+            };
+            let _ = writeln!(
+                out,
+                "  {:>12} {:>6.2}%  {:>12} {:>6.2}%  {}",
+                own,
+                percent(*own, total),
+                inclusive,
+                percent(*inclusive, total),
+                function
+            );
+        }
+
+        let mut pcs: Vec<usize> = (0..self.counts.len())
+            .filter(|pc| self.counts[*pc] > 0)
+            .collect();
+        pcs.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        let _ = writeln!(out, "\nInstructions:");
+        for pc in pcs.iter().take(REPORT_ROWS) {
+            let location = debug
+                .and_then(|debug| debug.location(*pc))
+                .unwrap_or_default();
+            let instruction = bin.get(*pc).map(ByteCode::describe).unwrap_or_default();
+            let row = format!(
+                "  {:>12} {:>6.2}%  {:>5}  {:<16} {}",
+                self.counts[*pc],
+                percent(self.counts[*pc], total),
+                pc,
+                instruction,
+                location
+            );
+            let _ = writeln!(out, "{}", row.trim_end());
+        }
+
+        if let Some(debug) = debug {
+            let mut lines: HashMap<(&str, u32), u64> = HashMap::new();
+            for (pc, count) in self.counts.iter().enumerate() {
+                if let Some((file, pos)) = debug.position(pc)
+                    && *count > 0
+                {
+                    *lines.entry((file, pos.line)).or_default() += count;
+                }
+            }
+            let mut lines: Vec<_> = lines.into_iter().collect();
+            lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let _ = writeln!(out, "\nLines:");
+            for ((file, line), count) in lines.iter().take(REPORT_ROWS) {
+                let _ = writeln!(
+                    out,
+                    "  {:>12} {:>6.2}%  {}:{}",
+                    count,
+                    percent(*count, total),
+                    file,
+                    line
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::smachine::compiler::assemble;
+    use crate::smachine::vm::VM;
+
+    fn profile(source: &str, jit: bool) -> (VM, String) {
+        let mut vm = VM::from_program(assemble(source, "test.s").unwrap());
+        vm.set_output(Box::new(std::io::sink()));
+        vm.set_jit_enabled(jit);
+        vm.enable_profile();
+        vm.run();
+        let report = vm.profile().unwrap().report(vm.bin(), vm.debug_info());
+        (vm, report)
+    }
+
+    #[test]
+    fn functions_lines_and_folded_stacks() {
+        let source =
+            "push 3\n1:\ncall f\npop\npush 1\nusub64\ndup\njnz 1b\nhalt\nf:\npush 2\nret\n";
+        let (vm, report) = profile(source, false);
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.folded(vm.debug_info()), "main 20\nmain;f 6\n");
+
+        assert!(report.starts_with("Profile: 26 instructions interpreted"));
+        let rows: Vec<Vec<&str>> = report
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        let row = |name: &str| rows.iter().find(|row| row.last() == Some(&name)).cloned();
+        assert_eq!(row("main").unwrap()[..4], ["20", "76.92%", "26", "100.00%"]);
+        assert_eq!(row("f").unwrap()[..4], ["6", "23.08%", "6", "23.08%"]);
+        assert_eq!(
+            row("test.s:12:1").unwrap()[..5],
+            ["3", "11.54%", "9", "ret", "test.s:12:1"]
+        );
+        assert_eq!(row("test.s:1:1").unwrap()[..3], ["1", "3.85%", "0"]);
+        assert_eq!(row("test.s:11").unwrap()[..2], ["3", "11.54%"]);
+    }
+
+    #[test]
+    fn jit_code_is_timed_apart() {
+        let source = "push 0\npush 200\n1:\nswap 1\npush 3\nuadd64\nswap 1\npush 1\nusub64\ndup\njnz 1b\nhalt\n";
+        let (vm, _) = profile(source, true);
+        let profile = vm.profile().unwrap();
+        assert!(profile.native_entries > 0);
+        // the loop stops being counted once it runs natively
+        assert!(profile.instructions() < 2 + 200 * 8);
+        assert_eq!(vm.stack()[..2], [600, 0]);
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use super::compiler::{self, Program};
+use super::vm::VM;
+
+const HELP: &str = "\
+each line is assembled and run on the same vm, the stack and .data are kept
+between lines.
+a line starting with a label like `f:` starts a definition, it is not run and
+ends at a line with ret or an empty line, later lines can call or jump to it.
+commands:
+  :stack         print the live part of the stack
+  :disasm        disassemble the last program, with the definitions
+  :load <file>   run a file, its labels stay defined
+  :reset         clear the stack and the definitions
+  :help          show this
+  :quit          leave (or end of input)";
+
+// the name instructions typed in are reported under
+const SOURCE_NAME: &str = "<repl>";
+
+// a definition or a loaded file, assembled after every line
+#[derive(Clone)]
+struct Chunk {
+    name: String,
+    // what errors and `.include`s in it are relative to
+    file: String,
+    source: String,
+}
+
+// a definition with the same name replaces the old one
+fn define(chunks: &mut Vec<Chunk>, chunk: Chunk) {
+    match chunks.iter_mut().find(|old| old.name == chunk.name) {
+        Some(old) => *old = chunk,
+        None => chunks.push(chunk),
+    }
+}
+
+/// Assembles and runs one line at a time on a persistent vm
+pub struct Repl {
+    vm: VM,
+    // the definitions and loaded files, in the order they came
+    chunks: Vec<Chunk>,
+    // the definition being typed in
+    pending: Option<(String, String)>,
+    last: Option<Program>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Self {
+            vm: VM::new(Vec::new()),
+            chunks: Vec::new(),
+            pending: None,
+            last: None,
+        }
+    }
+
+    // assembles `code` with the definitions and runs it from the start, keeping the stack.
+    // The code that runs comes first, the definitions follow after a halt so they
+    // only run when called.
+    fn execute(&mut self, code: &str, file: &str, skip: Option<&str>) -> bool {
+        let code = format!("{}\nhalt\n", code);
+        let mut parts = vec![(file, code.as_str())];
+        parts.extend(
+            self.chunks
+                .iter()
+                .filter(|chunk| Some(chunk.name.as_str()) != skip)
+                .map(|chunk| (chunk.file.as_str(), chunk.source.as_str())),
+        );
+        let Some(mut program) = compiler::assemble_parts(&parts) else {
+            return false;
+        };
+        self.last = Some(program.clone());
+        // .data keeps what earlier lines stored, new chunks only add to its end
+        let data = &self.vm.memory()[self.vm.rodata_len()..];
+        if program.data.len() >= data.len() {
+            program.data[..data.len()].copy_from_slice(data);
+        }
+        self.vm.load(program, 0);
+
+        let ok = self.vm.execute();
+        let _ = io::stdout().flush();
+        if !ok {
+            println!(
+                "error: {} at {}",
+                self.vm
+                    .last_error()
+                    .unwrap_or("the instruction could not run"),
+                self.vm.describe_pc(self.vm.pc())
+            );
+        }
+        println!("{:?}", &self.vm.stack()[..self.vm.sp()]);
+        true
+    }
+
+    fn load(&mut self, path: &str) {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                println!("Could not read {}: {}", path, err);
+                return;
+            }
+        };
+        if self.execute(&source, path, Some(path)) {
+            let chunk = Chunk {
+                name: path.to_string(),
+                file: path.to_string(),
+                source,
+            };
+            define(&mut self.chunks, chunk);
+        }
+    }
+
+    fn finish_definition(&mut self) {
+        let Some((name, source)) = self.pending.take() else {
+            return;
+        };
+        // only kept if everything still assembles with it
+        let mut chunks = self.chunks.clone();
+        let chunk = Chunk {
+            name: name.clone(),
+            file: SOURCE_NAME.to_string(),
+            source,
+        };
+        define(&mut chunks, chunk);
+        let parts: Vec<(&str, &str)> = chunks
+            .iter()
+            .map(|chunk| (chunk.file.as_str(), chunk.source.as_str()))
+            .collect();
+        if compiler::assemble_parts(&parts).is_some() {
+            self.chunks = chunks;
+            println!("defined {}", name);
+        }
+    }
+
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        match command {
+            ":stack" | ":s" => {
+                for (slot, value) in self.vm.stack()[..self.vm.sp()].iter().enumerate().rev() {
+                    println!("  [{}] {} ({:#x})", slot, value, value);
+                }
+            }
+            ":disasm" | ":d" => match &self.last {
+                Some(program) => print!("{}", compiler::disassemble(program)),
+                None => println!("Nothing has been assembled yet"),
+            },
+            ":load" | ":l" => match words.next() {
+                Some(path) => self.load(path),
+                None => println!("usage: :load <file.s>"),
+            },
+            ":reset" => {
+                *self = Repl::new();
+                println!("The stack and the definitions are cleared");
+            }
+            ":help" | ":h" => println!("{}", HELP),
+            ":quit" | ":q" => return false,
+            other => println!("Unknown command {}, try :help", other),
+        }
+        true
+    }
+
+    /// Handles one line of input, returns false to leave
+    pub fn line(&mut self, line: &str) -> bool {
+        let line = line.trim();
+
+        if let Some((_, chunk)) = &mut self.pending {
+            if line.is_empty() {
+                self.finish_definition();
+                return true;
+            }
+            chunk.push('\n');
+            chunk.push_str(line);
+            if line.split_whitespace().any(|word| word == "ret") {
+                self.finish_definition();
+            }
+            return true;
+        }
+
+        if line.is_empty() {
+            return true;
+        }
+        if line.starts_with(':') {
+            return self.command(line);
+        }
+
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if let Some(name) = first.strip_suffix(':') {
+            self.pending = Some((name.to_string(), line.to_string()));
+            if line.split_whitespace().any(|word| word == "ret") {
+                self.finish_definition();
+            }
+            return true;
+        }
+
+        self.execute(line, SOURCE_NAME, None);
+        true
+    }
+
+    pub fn run(&mut self) {
+        println!("simplestackmachine repl, type :help for the commands");
+        let stdin = io::stdin();
+        loop {
+            print!("{}", if self.pending.is_some() { "... " } else { "> " });
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            if !self.line(&line) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(repl: &Repl) -> Vec<u64> {
+        repl.vm.stack()[..repl.vm.sp()].to_vec()
+    }
+
+    #[test]
+    fn stack_and_data_stay_between_lines() {
+        let mut repl = Repl::new();
+        repl.line("double:");
+        repl.line("push 2");
+        repl.line("ret");
+        assert_eq!(repl.chunks.len(), 1);
+
+        let dir = std::env::temp_dir().join(format!("smachine-repl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("consts.s"), ".equ START, 40\n").unwrap();
+        let lib = ".include \"consts.s\"\n.data\ncounter: .word START\n.text\n";
+        fs::write(dir.join("lib.s"), lib).unwrap();
+
+        // the include is found next to lib.s on every line, not in the cwd
+        repl.line(&format!(":load {}", dir.join("lib.s").display()));
+        repl.line("push counter load");
+        assert_eq!(stack(&repl), vec![40]);
+        repl.line("push 2 uadd64 push counter store");
+        repl.line("push counter load");
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(stack(&repl), vec![42]);
+        assert_eq!(repl.chunks.len(), 2);
+    }
+}
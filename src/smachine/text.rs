@@ -0,0 +1,264 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use super::compiler::{ByteCode, Program, TokenType};
+use super::debug_info::{DebugInfo, SourcePos};
+
+/// The first line of every file `write` makes
+pub const HEADER: &str = "; smachine bytecode text 1";
+
+// the opcode by the name `write` gives it, unknown opcodes are written as numbers
+fn opcode(name: &str) -> Option<u8> {
+    if let Ok(opcode) = name.parse::<u8>() {
+        return Some(opcode);
+    }
+    (0..TokenType::Err as u8).find(|opcode| TokenType::from(*opcode).mnemonic() == name)
+}
+
+fn opcode_name(opcode: u8) -> String {
+    match TokenType::from(opcode) {
+        TokenType::Err => opcode.to_string(),
+        kind => kind.mnemonic().to_string(),
+    }
+}
+
+// operands are written as unsigned numbers, -1 and 0xff are taken too for hand edits
+fn number(text: &str) -> Option<u64> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if text.starts_with('-') {
+        text.parse::<i64>().ok().map(|value| value as u64)
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Writes the program one instruction per line as `index opcode operand`, followed by
+/// where it came from when there is debug info. Every bit of the program is kept,
+/// `parse` gives back the same program.
+pub fn write(program: &Program) -> String {
+    let mut out = format!("{}\n", HEADER);
+    if let Some(entry) = program.entry {
+        out.push_str(&format!(".entry {}\n", entry));
+    }
+    if let Some(debug) = &program.debug {
+        out.push_str(".debug\n");
+        for file in &debug.files {
+            out.push_str(&format!(".file {}\n", file));
+        }
+        for (name, pc) in &debug.labels {
+            out.push_str(&format!(".label {} {}\n", name, pc));
+        }
+    }
+    for (pc, binary) in program.code.iter().enumerate() {
+        out.push_str(&format!("{} {}", pc, opcode_name(binary.opcode)));
+        // an operand of 0 is left out unless the instruction always has one
+        if binary.value != 0 || TokenType::from(binary.opcode).takes_operand() {
+            out.push_str(&format!(" {}", binary.value));
+        }
+        if let Some(pos) = program
+            .debug
+            .as_ref()
+            .and_then(|debug| debug.positions.get(pc))
+        {
+            out.push_str(&format!(" @ {}:{}:{}", pos.file, pos.line, pos.column));
+        }
+        out.push('\n');
+    }
+    for word in &program.rodata {
+        out.push_str(&format!(".rodata {}\n", word));
+    }
+    for word in &program.data {
+        out.push_str(&format!(".data {}\n", word));
+    }
+    out
+}
+
+fn position(text: &str) -> Option<SourcePos> {
+    let mut parts = text.split(':').map(|part| part.parse::<u32>().ok());
+    let pos = SourcePos {
+        file: parts.next()??,
+        line: parts.next()??,
+        column: parts.next()??,
+    };
+    parts.next().is_none().then_some(pos)
+}
+
+// one line of the file, without its comment
+fn parse_line(program: &mut Program, line: &str) -> std::result::Result<(), String> {
+    // file names are the rest of the line, so they can hold spaces and ;
+    if let Some(file) = line.trim_start().strip_prefix(".file ") {
+        let debug = program.debug.get_or_insert_with(DebugInfo::default);
+        debug.files.push(file.trim().to_string());
+        return Ok(());
+    }
+    let line = line.split(';').next().unwrap_or_default();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let number_at = |index: usize| {
+        let text = words.get(index).copied().unwrap_or_default();
+        number(text).ok_or_else(|| format!("expected a number, found `{}`", text))
+    };
+    match words.first().copied() {
+        None => {}
+        Some(".debug") => {
+            program.debug.get_or_insert_with(DebugInfo::default);
+        }
+        Some(".entry") => program.entry = Some(number_at(1)? as usize),
+        Some(".rodata") => program.rodata.push(number_at(1)?),
+        Some(".data") => program.data.push(number_at(1)?),
+        Some(".label") => {
+            let Some(name) = words.get(1) else {
+                return Err(String::from(".label needs a name and an index"));
+            };
+            let pc = number_at(2)? as usize;
+            let debug = program.debug.get_or_insert_with(DebugInfo::default);
+            debug.labels.push((name.to_string(), pc));
+        }
+        Some(directive) if directive.starts_with('.') => {
+            return Err(format!("unknown directive {}", directive));
+        }
+        Some(_) => {
+            let pc = number_at(0)? as usize;
+            if pc != program.code.len() {
+                return Err(format!(
+                    "expected instruction {}, found {}",
+                    program.code.len(),
+                    pc
+                ));
+            }
+            let name = words.get(1).copied().unwrap_or_default();
+            let Some(opcode) = opcode(name) else {
+                return Err(format!("unknown opcode `{}`", name));
+            };
+            let (operand, rest) = match words.get(2) {
+                Some(&"@") | None => (0, &words[2.min(words.len())..]),
+                Some(_) => (number_at(2)?, &words[3..]),
+            };
+            match rest {
+                [] => {}
+                ["@", pos] => {
+                    let Some(pos) = position(pos) else {
+                        return Err(format!("expected file:line:column, found `{}`", pos));
+                    };
+                    let debug = program.debug.get_or_insert_with(DebugInfo::default);
+                    debug.positions.resize(pc, SourcePos::default());
+                    debug.positions.push(pos);
+                }
+                _ => return Err(format!("unexpected `{}`", rest.join(" "))),
+            }
+            program.code.push(ByteCode {
+                opcode,
+                value: operand,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads a program `write` wrote, or one made or edited by hand
+pub fn parse(source: &str) -> Result<Program> {
+    let mut program = Program::default();
+    for (index, line) in source.lines().enumerate() {
+        parse_line(&mut program, line).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: {}", index + 1, err),
+            )
+        })?;
+    }
+    if let Some(entry) = program.entry
+        && entry >= program.code.len()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("the entry point {} is past the code", entry),
+        ));
+    }
+    // every instruction has a position once there is debug info
+    let len = program.code.len();
+    if let Some(debug) = &mut program.debug {
+        debug.positions.resize(len, SourcePos::default());
+    }
+    Ok(program)
+}
+
+pub fn write_file(path: &str, program: &Program) -> Result<()> {
+    fs::write(path, write(program))
+}
+
+pub fn read_file(path: &str) -> Result<Program> {
+    parse(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::{
+        assemble, read_bin, write_aligned_bin, write_bin, write_compact_bin,
+    };
+
+    const SOURCE: &str = "
+.rodata
+msg: .string \"hi\"
+.data
+count: .word -1
+.text
+.entry main
+helper:
+    push 1
+    ret
+main:
+    push msg
+    load
+    call helper
+    push 1.5f
+    push count
+    swap 1
+    halt
+";
+
+    #[test]
+    fn parses_what_it_writes() {
+        let text = write(&assemble(SOURCE, "test.s").unwrap());
+        assert!(text.starts_with(HEADER));
+        assert_eq!(write(&parse(&text).unwrap()), text);
+    }
+
+    #[test]
+    fn round_trips_through_every_bin_format() {
+        let text = write(&assemble(SOURCE, "test.s").unwrap());
+        let program = parse(&text).unwrap();
+        let writers: [fn(&str, &Program) -> Result<()>; 3] =
+            [write_bin, write_compact_bin, write_aligned_bin];
+        for (index, write_to) in writers.into_iter().enumerate() {
+            let path = std::env::temp_dir().join(format!(
+                "smachine-text-test-{}-{}.bin",
+                std::process::id(),
+                index
+            ));
+            let path = path.to_str().unwrap().to_string();
+            write_to(&path, &program).unwrap();
+            let read = read_bin(path.clone());
+            let _ = fs::remove_file(&path);
+            assert_eq!(write(&read.unwrap()), text);
+        }
+    }
+
+    #[test]
+    fn reads_hand_edits() {
+        let program = parse("; comment\n0 push -1\n1 push 0xff ; top\n2 halt\n.data 3\n").unwrap();
+        assert_eq!(program.code[0].value, u64::MAX);
+        assert_eq!(program.code[1].value, 255);
+        assert_eq!(program.data, vec![3]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(parse("1 push 1\n").is_err());
+        assert!(parse("0 nosuchop\n").is_err());
+        assert!(parse("0 push x\n").is_err());
+        assert!(parse(".entry 5\n0 halt\n").is_err());
+        assert!(parse(".bogus\n").is_err());
+        assert!(parse("0 push 1 @ 1:2\n").is_err());
+    }
+}
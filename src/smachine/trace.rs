@@ -0,0 +1,409 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+
+use super::compiler::TokenType;
+use super::leb128;
+
+const MAGIC: &[u8; 4] = b"SMTR";
+const VERSION: u8 = 2;
+// set on the opcode byte of the instruction that faulted
+const FAULT_BIT: u8 = 0x80;
+// set on the opcode byte of a jump or call that went on into compiled code
+const NATIVE_BIT: u8 = 0x40;
+
+/// One executed instruction, `sp` and `top` are the state after it ran.
+/// A `native` record also ran a compiled loop or procedure, the state is the
+/// one the compiled code left and the next record is where it gave back control.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub opcode: u8,
+    pub operand: u64,
+    pub sp: usize,
+    pub top: Option<u64>,
+    pub fault: bool,
+    pub native: bool,
+}
+
+/// Writes records as they are executed.
+/// The file is the magic, a version byte and the program hash, then one record per
+/// instruction: opcode with a bit for faults and one for native code, pc relative to the previous one, operand for the instructions
+/// that take one, sp and the top of the stack when sp > 0, all LEB128.
+#[derive(Debug)]
+pub struct TraceWriter {
+    writer: BufWriter<fs::File>,
+    next_pc: usize,
+}
+
+pub struct Trace {
+    pub program_hash: u64,
+    pub records: Vec<TraceRecord>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str, program_hash: u64) -> Result<TraceWriter> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&program_hash.to_le_bytes())?;
+        Ok(Self { writer, next_pc: 0 })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> Result<()> {
+        let mut opcode = record.opcode;
+        if record.fault {
+            opcode |= FAULT_BIT;
+        }
+        if record.native {
+            opcode |= NATIVE_BIT;
+        }
+        self.writer.write_all(&[opcode])?;
+        // straight line code makes this 0
+        let delta = record.pc as i64 - self.next_pc as i64;
+        leb128::write_u64(&mut self.writer, leb128::zigzag(delta))?;
+        self.next_pc = record.pc + 1;
+
+        if TokenType::from(record.opcode).takes_operand() {
+            leb128::write_u64(&mut self.writer, record.operand)?;
+        }
+        leb128::write_u64(&mut self.writer, record.sp as u64)?;
+        if let Some(top) = record.top {
+            leb128::write_u64(&mut self.writer, top)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Trace {
+    pub fn read(path: &str) -> Result<Trace> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if &magic != MAGIC || version[0] != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a trace file", path),
+            ));
+        }
+        let mut hash = [0u8; 8];
+        reader.read_exact(&mut hash)?;
+
+        let mut records = Vec::new();
+        let mut next_pc = 0usize;
+        let mut opcode = [0u8; 1];
+        loop {
+            match reader.read_exact(&mut opcode) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            let fault = opcode[0] & FAULT_BIT != 0;
+            let native = opcode[0] & NATIVE_BIT != 0;
+            let opcode = opcode[0] & !(FAULT_BIT | NATIVE_BIT);
+
+            let delta = leb128::unzigzag(leb128::read_u64(&mut reader)?);
+            let pc = i64::try_from(next_pc)
+                .ok()
+                .and_then(|next_pc| next_pc.checked_add(delta))
+                .and_then(|pc| usize::try_from(pc).ok())
+                .filter(|pc| *pc < usize::MAX)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} has a pc out of range", path),
+                    )
+                })?;
+            next_pc = pc + 1;
+
+            let operand = if TokenType::from(opcode).takes_operand() {
+                leb128::read_u64(&mut reader)?
+            } else {
+                0
+            };
+            let sp = leb128::read_u64(&mut reader)? as usize;
+            let top = if sp > 0 {
+                Some(leb128::read_u64(&mut reader)?)
+            } else {
+                None
+            };
+            records.push(TraceRecord {
+                pc,
+                opcode,
+                operand,
+                sp,
+                top,
+                fault,
+                native,
+            });
+        }
+
+        Ok(Trace {
+            program_hash: u64::from_le_bytes(hash),
+            records,
+        })
+    }
+}
+
+impl TraceRecord {
+    pub fn describe(&self) -> String {
+        let kind = TokenType::from(self.opcode);
+        let instruction = if kind.takes_operand() {
+            format!("{} {}", kind.mnemonic(), self.operand)
+        } else {
+            kind.mnemonic().to_string()
+        };
+        let top = match self.top {
+            Some(top) => top.to_string(),
+            None => String::from("-"),
+        };
+        let fault = if self.fault { "  FAULT" } else { "" };
+        let native = if self.native { "  NATIVE" } else { "" };
+        format!(
+            "{:>5}  {:<16} sp {:<3} top {}{}{}",
+            self.pc, instruction, self.sp, top, fault, native
+        )
+    }
+}
+
+/// Which records `print` shows
+#[derive(Default)]
+pub struct Filter {
+    // start and end, the end is not included
+    pub pcs: Option<(usize, usize)>,
+    pub opcodes: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, record: &TraceRecord) -> bool {
+        let in_range = self
+            .pcs
+            .is_none_or(|(start, end)| record.pc >= start && record.pc < end);
+        let opcode = TokenType::from(record.opcode).mnemonic();
+        in_range && (self.opcodes.is_empty() || self.opcodes.iter().any(|name| name == opcode))
+    }
+}
+
+pub fn print(trace: &Trace, filter: &Filter) {
+    // written by hand so piping into head does not panic on a closed stdout
+    let mut out = io::stdout().lock();
+    let _ = writeln!(
+        out,
+        "program {:016x}, {} instructions",
+        trace.program_hash,
+        trace.records.len()
+    );
+    for (index, record) in trace.records.iter().enumerate() {
+        if filter.matches(record) && writeln!(out, "{:>8}  {}", index, record.describe()).is_err() {
+            return;
+        }
+    }
+}
+
+// A native record in one trace stands for the instructions the other trace ran
+// one at a time until the compiled code gave back control, this finds the last
+// of those in `stepped` from `from` on. None when the state never lines up.
+fn catch_up(
+    native: &[TraceRecord],
+    at: usize,
+    stepped: &[TraceRecord],
+    from: usize,
+) -> Option<usize> {
+    let left = native[at];
+    let resume = native.get(at + 1).map(|record| record.pc);
+    (from..stepped.len()).find(|&index| {
+        let right = stepped[index];
+        right.sp == left.sp
+            && right.top == left.top
+            && right.fault == left.fault
+            && stepped.get(index + 1).map(|record| record.pc) == resume
+    })
+}
+
+/// Prints where two traces stop agreeing, returns whether they are the same.
+/// Compiled code in either trace is matched against the instructions the other
+/// one ran in its place, so an interpreter trace can be diffed against a jit one.
+pub fn diff(a: &Trace, b: &Trace) -> bool {
+    if a.program_hash != b.program_hash {
+        println!(
+            "the traces come from different programs ({:016x} and {:016x})",
+            a.program_hash, b.program_hash
+        );
+    }
+
+    let (mut i, mut j) = (0, 0);
+    loop {
+        let (left, right) = match (a.records.get(i), b.records.get(j)) {
+            (None, None) => {
+                println!(
+                    "the traces are the same, {} and {} instructions",
+                    a.records.len(),
+                    b.records.len()
+                );
+                return true;
+            }
+            (Some(left), Some(right)) => (left, right),
+            _ => break,
+        };
+        if left == right {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let same_instruction =
+            left.pc == right.pc && left.opcode == right.opcode && left.operand == right.operand;
+        let caught_up = match (left.native, right.native) {
+            (true, false) if same_instruction => {
+                catch_up(&a.records, i, &b.records, j).map(|end| (i, end))
+            }
+            (false, true) if same_instruction => {
+                catch_up(&b.records, j, &a.records, i).map(|end| (end, j))
+            }
+            _ => None,
+        };
+        let Some((end_a, end_b)) = caught_up else {
+            break;
+        };
+        i = end_a + 1;
+        j = end_b + 1;
+    }
+
+    println!("the traces differ at instruction {} of a and {} of b", i, j);
+    let show = |record: Option<&TraceRecord>| match record {
+        Some(record) => record.describe(),
+        None => String::from("<end of trace>"),
+    };
+    for (name, records, index) in [("a", &a.records, i), ("b", &b.records, j)] {
+        for k in index.saturating_sub(3)..=index {
+            let marker = if k == index { ">" } else { " " };
+            println!("{}{:>7} {} {}", marker, k, name, show(records.get(k)));
+        }
+    }
+    println!(
+        "a has {} instructions, b has {}",
+        a.records.len(),
+        b.records.len()
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("smachine-trace-{}-{}", std::process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn record(pc: usize, opcode: TokenType, sp: usize, top: u64) -> TraceRecord {
+        TraceRecord {
+            pc,
+            opcode: opcode as u8,
+            operand: 0,
+            sp,
+            top: Some(top),
+            fault: false,
+            native: false,
+        }
+    }
+
+    fn trace(records: Vec<TraceRecord>) -> Trace {
+        Trace {
+            program_hash: 1,
+            records,
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let path = temp_path("round-trip");
+        let records = vec![
+            TraceRecord {
+                operand: 4,
+                ..record(0, TokenType::Push, 1, 4)
+            },
+            TraceRecord {
+                operand: 0,
+                native: true,
+                ..record(1, TokenType::Jnz, 1, 9)
+            },
+            TraceRecord {
+                top: None,
+                fault: true,
+                ..record(0, TokenType::Pop, 0, 0)
+            },
+        ];
+        let mut writer = TraceWriter::create(&path, 7).unwrap();
+        for record in &records {
+            writer.record(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let read = Trace::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(read.program_hash, 7);
+        assert_eq!(read.records, records);
+    }
+
+    #[test]
+    fn pcs_out_of_range_are_invalid_data() {
+        let header = [&MAGIC[..], &[VERSION], &[0; 8]].concat();
+        let pop = TokenType::Pop as u8;
+        let mut before_start = header.clone();
+        before_start.push(pop);
+        leb128::write_u64(&mut before_start, leb128::zigzag(-5)).unwrap();
+        before_start.push(0);
+        let mut past_the_end = header;
+        for delta in [i64::MAX, i64::MAX] {
+            past_the_end.push(pop);
+            leb128::write_u64(&mut past_the_end, leb128::zigzag(delta)).unwrap();
+            past_the_end.push(0);
+        }
+
+        for (name, bytes) in [("before", before_start), ("past", past_the_end)] {
+            let path = temp_path(name);
+            fs::write(&path, bytes).unwrap();
+            let err = Trace::read(&path).err().unwrap();
+            let _ = fs::remove_file(&path);
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn native_records_match_what_the_interpreter_ran() {
+        // a loop at 1..=3 counting 2 down to 0, then halt at 4
+        let interpreted = trace(vec![
+            record(0, TokenType::Push, 1, 2),
+            record(1, TokenType::Push, 2, 1),
+            record(2, TokenType::Usub64, 1, 1),
+            record(3, TokenType::Dup, 2, 1),
+            record(4, TokenType::Jnz, 1, 1),
+            record(1, TokenType::Push, 2, 1),
+            record(2, TokenType::Usub64, 1, 0),
+            record(3, TokenType::Dup, 2, 0),
+            record(4, TokenType::Jnz, 1, 0),
+            record(5, TokenType::Halt, 1, 0),
+        ]);
+        let mut records = interpreted.records[..5].to_vec();
+        records[4].native = true;
+        records[4].top = Some(0);
+        records.push(record(5, TokenType::Halt, 1, 0));
+        let compiled = trace(records);
+        assert!(diff(&interpreted, &compiled));
+        assert!(diff(&compiled, &interpreted));
+
+        // compiled code that left another state behind
+        let mut wrong = compiled;
+        wrong.records[4].top = Some(1);
+        assert!(!diff(&interpreted, &wrong));
+    }
+}
@@ -1,23 +1,266 @@
 use crate::smachine::compiler::TokenType;
 
-use dynasmrt::{DynasmApi, dynasm};
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
 use memmap2::MmapOptions;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
-use std::fs;
+use std::io::{self, Write};
 use std::mem;
-use std::thread::sleep;
-use std::time::Duration;
-
-use super::compiler::ByteCode;
-const MAX_SIZE: usize = 10; //524288;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use super::compiler::{ByteCode, Program};
+use super::coverage::Coverage;
+use super::crash;
+use super::debug_info::DebugInfo;
+use super::jit_cache::{self, CachedCode, JitCache};
+use super::jit_dump::{self, JitDump, OffsetMap};
+use super::mapped::{Code, MappedCode};
+use super::profile::Profile;
+use super::trace::{TraceRecord, TraceWriter};
+use super::watch::{Access, Watchpoint};
+pub const MAX_SIZE: usize = 10; //524288;
+// bumped whenever the jit emits different code for the same bytecode
+pub const CODEGEN_VERSION: u64 = 1;
 const INTERPRETED_EXECUTIONS: u64 = 1;
+// how many times a back-edge has to be taken before its loop gets compiled
+const HOT_LOOP_THRESHOLD: u64 = 50;
+
+// stack base and a pointer to sp, returns the pc the interpreter resumes at
+type LoopFn = extern "C" fn(*mut u64, *mut usize) -> u64;
+
+// A pre-decoded instruction for `run`, the handler executes it and returns the
+// next pc, or FAULT when the program can not continue.
+type Handler = fn(&mut VM, u64) -> usize;
+const FAULT: usize = usize::MAX;
+// pcs kept for crash dumps, a power of two so the ring index is a mask
+const RECENT_PCS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Inst {
+    handler: Handler,
+    operand: u64,
+}
+
+// handlers for instructions that simply continue with the next one
+macro_rules! handlers {
+    ($($name:ident => |$vm:ident, $operand:ident| $body:expr;)+) => {
+        $(
+        #[allow(unused_variables)]
+        fn $name($vm: &mut VM, $operand: u64) -> usize {
+            match $body {
+                Some(_) => $vm.pc + 1,
+                None => FAULT,
+            }
+        }
+        )+
+    };
+}
+
+handlers! {
+    op_push => |vm, value| vm.push(value);
+    op_pop => |vm, value| vm.pop();
+    op_uadd8 => |vm, value| vm.add::<u8>();
+    op_usub8 => |vm, value| vm.sub::<u8>();
+    op_uadd16 => |vm, value| vm.add::<u16>();
+    op_usub16 => |vm, value| vm.sub::<u16>();
+    op_uadd32 => |vm, value| vm.add::<u32>();
+    op_usub32 => |vm, value| vm.sub::<u32>();
+    op_uadd64 => |vm, value| vm.add::<u64>();
+    op_usub64 => |vm, value| vm.sub::<u64>();
+    op_add8 => |vm, value| vm.add::<i8>();
+    op_add16 => |vm, value| vm.add::<i16>();
+    op_sub16 => |vm, value| vm.sub::<i16>();
+    op_add32 => |vm, value| vm.add::<i32>();
+    op_sub32 => |vm, value| vm.sub::<i32>();
+    op_add64 => |vm, value| vm.add::<i64>();
+    op_sub64 => |vm, value| vm.sub::<i64>();
+    op_addf64 => |vm, value| vm.addf::<f64>();
+    op_subf64 => |vm, value| vm.subf::<f64>();
+    op_addf32 => |vm, value| vm.addf::<f32>();
+    op_subf32 => |vm, value| vm.subf::<f32>();
+    op_prt => |vm, value| vm.prt();
+    op_inc => |vm, value| vm.inc::<u64>();
+    op_dup => |vm, value| vm.dup();
+    op_swap => |vm, value| vm.swap(value);
+    op_cmp => |vm, value| vm.cmp();
+    op_int => |vm, value| vm.int();
+    op_load => |vm, value| vm.load_word();
+    op_store => |vm, value| vm.store_word();
+    op_ret => |vm, value| vm.ret();
+}
 
-fn core_dump(stack: &[u64; MAX_SIZE]) -> std::io::Result<()> {
-    let data = format!("{:?}", stack);
-    fs::write("./coredump.txt", data)?;
-    Ok(())
+fn op_jmp(vm: &mut VM, target: u64) -> usize {
+    let from = vm.pc;
+    match vm.jmp(target as usize) {
+        Some(_) => vm.after_jump(from),
+        None => FAULT,
+    }
+}
+
+fn op_jeq(vm: &mut VM, target: u64) -> usize {
+    match vm.pop() {
+        Some(0) => op_jmp(vm, target),
+        Some(_) => vm.pc + 1,
+        None => FAULT,
+    }
+}
+
+fn op_jnz(vm: &mut VM, target: u64) -> usize {
+    match vm.pop() {
+        Some(0) => vm.pc + 1,
+        Some(_) => op_jmp(vm, target),
+        None => FAULT,
+    }
+}
+
+fn op_call(vm: &mut VM, target: u64) -> usize {
+    // a compiled procedure returns right away, anything else jumps
+    vm.should_increment_pc = true;
+    match vm.call(target as usize) {
+        Some(_) if vm.should_increment_pc => vm.pc + 1,
+        Some(_) => vm.pc,
+        None => FAULT,
+    }
+}
+
+fn op_jmpp(vm: &mut VM, _value: u64) -> usize {
+    match vm.jmpp() {
+        Some(_) => vm.pc,
+        None => FAULT,
+    }
+}
+
+fn op_halt(vm: &mut VM, _value: u64) -> usize {
+    vm.halt();
+    vm.pc
+}
+
+fn op_invalid(vm: &mut VM, _value: u64) -> usize {
+    vm.error(format!("Invalid opcode at {}", vm.pc));
+    FAULT
+}
+
+impl Inst {
+    fn decode(binary: ByteCode) -> Inst {
+        let handler: Handler = match TokenType::from(binary.opcode) {
+            TokenType::Push => op_push,
+            TokenType::Pop => op_pop,
+            TokenType::Uadd8 => op_uadd8,
+            TokenType::Usub8 => op_usub8,
+            TokenType::Uadd16 => op_uadd16,
+            TokenType::Usub16 => op_usub16,
+            TokenType::Uadd32 => op_uadd32,
+            TokenType::Usub32 => op_usub32,
+            TokenType::Uadd64 => op_uadd64,
+            TokenType::Usub64 => op_usub64,
+            // sub8 has always been evaluated as an add
+            TokenType::Add8 | TokenType::Sub8 => op_add8,
+            TokenType::Add16 => op_add16,
+            TokenType::Sub16 => op_sub16,
+            TokenType::Add32 => op_add32,
+            TokenType::Sub32 => op_sub32,
+            TokenType::Add64 => op_add64,
+            TokenType::Sub64 => op_sub64,
+            TokenType::Addf64 => op_addf64,
+            TokenType::Subf64 => op_subf64,
+            TokenType::Addf32 => op_addf32,
+            TokenType::Subf32 => op_subf32,
+            TokenType::Prt => op_prt,
+            TokenType::Inc => op_inc,
+            TokenType::Dup => op_dup,
+            TokenType::Swap => op_swap,
+            TokenType::Jmp => op_jmp,
+            TokenType::Call => op_call,
+            TokenType::Jmpp => op_jmpp,
+            TokenType::Cmp => op_cmp,
+            TokenType::Halt => op_halt,
+            TokenType::Ret => op_ret,
+            TokenType::Jeq => op_jeq,
+            TokenType::Jnz => op_jnz,
+            TokenType::Int => op_int,
+            TokenType::Load => op_load,
+            TokenType::Store => op_store,
+            _ => op_invalid,
+        };
+
+        Inst {
+            handler,
+            operand: binary.value,
+        }
+    }
+}
+
+/// Where the program's output and the vm's error messages go
+pub struct Output {
+    writer: Box<dyn Write>,
+    // bytes written so far
+    written: u64,
+    // bytes already shown once, skipped when a reversed step runs again
+    replay: u64,
+}
+
+impl Output {
+    fn new(writer: Box<dyn Write>) -> Output {
+        Self {
+            writer,
+            written: 0,
+            replay: 0,
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skip = (self.replay as usize).min(buf.len());
+        self.replay -= skip as u64;
+        self.writer.write_all(&buf[skip..])?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// What one instruction changed, so it can be undone
+#[derive(Debug)]
+struct UndoEntry {
+    pc: usize,
+    sp: usize,
+    proc_pc: usize,
+    // (slot, old value), newest last
+    writes: Vec<(usize, u64)>,
+    // (address, old value) of the .data words stored to
+    stores: Vec<(usize, u64)>,
+    depth: usize,
+    // the innermost frame before, for instructions that return
+    top_frame: Option<CallFrame>,
+    output: u64,
+}
+
+/// Undo log of the last `cap` instructions run through `step`
+#[derive(Debug)]
+struct History {
+    entries: VecDeque<UndoEntry>,
+    cap: usize,
+}
+
+/// A procedure entered through `call` that has not returned yet
+#[derive(Clone, Copy, Debug)]
+pub struct CallFrame {
+    pub call_pc: usize,
+    pub proc_pc: usize,
+    // sp before the call pushed its bookkeeping
+    pub sp: usize,
 }
 
 #[derive(Debug)]
@@ -41,6 +284,8 @@ trait NumberBits:
     fn from_bits(bits: u64) -> Self;
     fn into_bits(self) -> u64;
     fn max() -> u64;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
 }
 
 trait NumberBitsFloat:
@@ -52,6 +297,7 @@ trait NumberBitsFloat:
 {
     fn from_bits(bits: u64) -> Self;
     fn into_bits(self) -> u64;
+    #[allow(dead_code)]
     fn max() -> f64;
 }
 
@@ -88,6 +334,14 @@ macro_rules! impl_bits_int {
             fn max() -> u64 {
                 <$type>::MAX as u64
             }
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$type>::wrapping_add(self, other)
+            }
+
+            fn wrapping_sub(self, other: Self) -> Self {
+                <$type>::wrapping_sub(self, other)
+            }
         }
         )+
     };
@@ -100,7 +354,7 @@ impl_bits_int!(u8; u16; u32; u64; i8; i16; i32; i64);
 #[derive(Debug)]
 pub struct VM {
     pc: usize,
-    bin: Vec<ByteCode>,
+    bin: Code,
     stack: Box<[u64; MAX_SIZE]>,
     sp: usize,
     proc_pc: usize,
@@ -109,6 +363,38 @@ pub struct VM {
     jit_memory_store: Vec<memmap2::Mmap>,
     funcs_used: HashMap<usize, u64>,
     compiled_procs: HashMap<usize, extern "C" fn(*const u64, usize) -> u64>,
+    // back-edge pc -> times taken
+    back_edges: HashMap<usize, u64>,
+    // loop head pc -> compiled loop body
+    compiled_loops: HashMap<usize, LoopFn>,
+    jit_enabled: bool,
+    jit_cache: Option<JitCache>,
+    jit_dump: Option<JitDump>,
+    frames: Vec<CallFrame>,
+    debug: Option<DebugInfo>,
+    output: Output,
+    last_error: Option<String>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    // slots the current instruction touched, only recorded while watching
+    accesses: Option<Vec<Access>>,
+    watch_hit: Option<usize>,
+    history: Option<History>,
+    // instructions run through step, less the ones stepped back over
+    steps: u64,
+    trace: Option<TraceWriter>,
+    // set when the last instruction went on into compiled code, for the trace
+    ran_native: bool,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    // set when anything above needs to see every instruction
+    instrumented: bool,
+    // ring of the last pcs executed, recent_count is the total written
+    recent: [usize; RECENT_PCS],
+    recent_count: u64,
+    // .rodata followed by .data, stores below rodata_len are refused
+    memory: Vec<u64>,
+    rodata_len: usize,
 }
 
 #[allow(dead_code)]
@@ -116,7 +402,7 @@ impl VM {
     pub fn new(bin: Vec<ByteCode>) -> VM {
         Self {
             stack: Box::new([0u64; MAX_SIZE]),
-            bin,
+            bin: Code::Owned(bin),
             pc: 0,
             sp: 0,
             proc_pc: 0,
@@ -124,12 +410,400 @@ impl VM {
             funcs_used: HashMap::new(),
             compiled_procs: HashMap::new(),
             jit_memory_store: Vec::new(),
+            back_edges: HashMap::new(),
+            compiled_loops: HashMap::new(),
+            jit_enabled: true,
+            jit_cache: None,
+            jit_dump: None,
+            frames: Vec::new(),
+            debug: None,
+            output: Output::new(Box::new(io::stdout())),
+            last_error: None,
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 1,
+            accesses: None,
+            watch_hit: None,
+            history: None,
+            steps: 0,
+            trace: None,
+            ran_native: false,
+            profile: None,
+            coverage: None,
+            instrumented: false,
+            recent: [0; RECENT_PCS],
+            recent_count: 0,
+            memory: Vec::new(),
+            rodata_len: 0,
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Output::new(output);
+    }
+
+    /// The message of the last error the vm reported
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    fn error(&mut self, message: String) -> Option<u64> {
+        let _ = writeln!(self.output, "ERROR: {}", message);
+        self.last_error = Some(message);
+        None
+    }
+
+    pub fn from_program(program: Program) -> VM {
+        let mut vm = VM::new(Vec::new());
+        let entry = program.entry.unwrap_or(0);
+        vm.load(program, entry);
+        vm
+    }
+
+    /// Runs the code of a mapped `.bin` in place, `program` has the rest of the file
+    pub fn from_mapped(code: MappedCode, program: Program) -> VM {
+        let mut vm = VM::from_program(program);
+        vm.bin = Code::Mapped(code);
+        vm
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug.as_ref()
+    }
+
+    // the source location of pc, or just the index when there is no debug info
+    pub fn describe_pc(&self, pc: usize) -> String {
+        let instruction = match self.bin.get(pc) {
+            Some(binary) => TokenType::from(binary.opcode).mnemonic(),
+            None => "<end>",
+        };
+        match self.debug.as_ref().and_then(|debug| debug.location(pc)) {
+            Some(location) => format!("{} (instruction {}: {})", location, pc, instruction),
+            None => format!("instruction {}: {}", pc, instruction),
         }
     }
 
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn stack(&self) -> &[u64] {
+        &self.stack[..]
+    }
+
+    /// The words of .rodata followed by those of .data, load and store addresses
+    /// index into it
+    pub fn memory(&self) -> &[u64] {
+        &self.memory
+    }
+
+    /// How many words at the start of `memory` are .rodata
+    pub fn rodata_len(&self) -> usize {
+        self.rodata_len
+    }
+
+    pub fn bin(&self) -> &[ByteCode] {
+        &self.bin[..]
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    /// The last pcs executed, oldest first
+    pub fn recent_pcs(&self) -> Vec<usize> {
+        let len = (self.recent_count as usize).min(RECENT_PCS);
+        let start = self.recent_count as usize - len;
+        (start..start + len)
+            .map(|index| self.recent[index & (RECENT_PCS - 1)])
+            .collect()
+    }
+
+    /// Writes a crash dump of the fault at pc, returns the file it went to
+    pub fn write_crash_dump(&self, pc: usize) -> io::Result<PathBuf> {
+        crash::write_crash_dump(self, pc)
+    }
+
+    /// Returns the number of the new watchpoint
+    pub fn add_watchpoint(&mut self, mut watch: Watchpoint) -> usize {
+        // a condition that already holds only stops once it became false again
+        watch.triggered(
+            self.sp,
+            &[],
+            self.pc,
+            self.sp,
+            &self.stack[..],
+            &self.memory,
+        );
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(id, watch);
+        self.update_recording();
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let removed = self.watchpoints.remove(&id).is_some();
+        self.update_recording();
+        removed
+    }
+
+    pub fn watchpoints(&self) -> &BTreeMap<usize, Watchpoint> {
+        &self.watchpoints
+    }
+
+    /// The watchpoint the last instruction triggered
+    pub fn take_watch_hit(&mut self) -> Option<usize> {
+        self.watch_hit.take()
+    }
+
+    // stack accesses are only recorded while something needs them
+    fn update_recording(&mut self) {
+        if self.watchpoints.is_empty() && self.history.is_none() {
+            self.accesses = None;
+        } else if self.accesses.is_none() {
+            self.accesses = Some(Vec::new());
+        }
+        self.instrumented = self.accesses.is_some()
+            || self.trace.is_some()
+            || self.profile.is_some()
+            || self.coverage.is_some();
+    }
+
+    /// Records which instructions and branch directions run from now on
+    pub fn enable_coverage(&mut self) {
+        // loops running as native code would not be seen
+        self.jit_enabled = false;
+        self.coverage = Some(Coverage::new(self.bin.len()));
+        self.update_recording();
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Counts every instruction run from now on, see `profile`
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new(self.bin.len()));
+        self.update_recording();
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Records every instruction run from now on. Compiled loops and procedures
+    /// are one record, the jump or call that ran them with the state they left.
+    pub fn set_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+        self.update_recording();
+    }
+
+    /// Keeps an undo log of up to `cap` instructions for stepping backwards,
+    /// None turns it off
+    pub fn set_history(&mut self, cap: Option<usize>) {
+        self.history = cap.map(|cap| History {
+            entries: VecDeque::new(),
+            cap,
+        });
+        self.update_recording();
+    }
+
+    /// How many instructions can be stepped back over
+    pub fn history_len(&self) -> usize {
+        self.history
+            .as_ref()
+            .map_or(0, |history| history.entries.len())
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.steps
+    }
+
+    /// Undoes the last instruction, false when the undo log is empty
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self
+            .history
+            .as_mut()
+            .and_then(|history| history.entries.pop_back())
+        else {
+            return false;
+        };
+
+        for (slot, old) in entry.writes.iter().rev() {
+            self.stack[*slot] = *old;
+        }
+        for (address, old) in entry.stores.iter().rev() {
+            self.memory[*address] = *old;
+        }
+        self.pc = entry.pc;
+        self.sp = entry.sp;
+        self.proc_pc = entry.proc_pc;
+        if self.frames.len() > entry.depth {
+            self.frames.truncate(entry.depth);
+        } else if self.frames.len() < entry.depth
+            && let Some(frame) = entry.top_frame
+        {
+            self.frames.push(frame);
+        }
+        self.output.written -= entry.output;
+        self.output.replay += entry.output;
+        self.last_error = None;
+        self.steps -= 1;
+        true
+    }
+
+    fn record(&mut self, access: Access) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(access);
+        }
+    }
+
+    fn check_watchpoints(&mut self, sp_before: usize) {
+        let accesses = self.accesses.as_deref().unwrap_or_default();
+        for (id, watch) in self.watchpoints.iter_mut() {
+            // every watchpoint runs so conditions keep their last value
+            if watch.triggered(
+                sp_before,
+                accesses,
+                self.pc,
+                self.sp,
+                &self.stack[..],
+                &self.memory,
+            ) && self.watch_hit.is_none()
+            {
+                self.watch_hit = Some(*id);
+            }
+        }
+    }
+
+    /// Replaces the code and the data, keeping the stack, and continues at `pc`
+    pub fn load(&mut self, program: Program, pc: usize) {
+        self.bin = Code::Owned(program.code);
+        self.debug = program.debug;
+        self.rodata_len = program.rodata.len();
+        self.memory = program.rodata;
+        self.memory.extend(program.data);
+        self.pc = pc;
+        self.proc_pc = 0;
+        self.frames.clear();
+        self.last_error = None;
+        // counters and compiled code are keyed by the old pcs
+        self.funcs_used.clear();
+        self.compiled_procs.clear();
+        self.back_edges.clear();
+        self.compiled_loops.clear();
+    }
+
+    pub fn set_jit_enabled(&mut self, enabled: bool) {
+        self.jit_enabled = enabled;
+    }
+
+    pub fn set_jit_cache(&mut self, cache: JitCache) {
+        self.jit_cache = Some(cache);
+    }
+
+    pub fn set_jit_dump(&mut self, dump: JitDump) {
+        self.jit_dump = Some(dump);
+    }
+
     #[inline(always)]
     fn eval(&mut self, binary: ByteCode) -> Option<u64> {
-        let result = match TokenType::from(binary.opcode) {
+        // watchpoints, the undo log and tracing only cost anything while in use
+        if self.instrumented {
+            return self.eval_recorded(binary);
+        }
+        self.exec(binary)
+    }
+
+    fn eval_recorded(&mut self, binary: ByteCode) -> Option<u64> {
+        let (pc, sp, proc_pc) = (self.pc, self.sp, self.proc_pc);
+        let depth = self.frames.len();
+        let top_frame = self.frames.last().copied();
+        let written = self.output.written;
+        if let Some(accesses) = &mut self.accesses {
+            accesses.clear();
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.count(pc, &self.frames);
+        }
+
+        self.ran_native = false;
+        let result = self.exec(binary);
+
+        if let Some(history) = &mut self.history {
+            let writes = self
+                .accesses
+                .iter()
+                .flatten()
+                .filter_map(|access| match *access {
+                    Access::Write { slot, old } => Some((slot, old)),
+                    _ => None,
+                })
+                .collect();
+            let stores = self
+                .accesses
+                .iter()
+                .flatten()
+                .filter_map(|access| match *access {
+                    Access::Store { address, old } => Some((address, old)),
+                    _ => None,
+                })
+                .collect();
+            if history.entries.len() == history.cap {
+                history.entries.pop_front();
+            }
+            if history.cap > 0 {
+                history.entries.push_back(UndoEntry {
+                    pc,
+                    sp,
+                    proc_pc,
+                    writes,
+                    stores,
+                    depth,
+                    top_frame,
+                    output: self.output.written - written,
+                });
+            }
+        }
+
+        if let Some(coverage) = &mut self.coverage {
+            // a jump that was taken leaves pc where it jumped to
+            let taken = result.map(|_| !self.should_increment_pc);
+            coverage.record(pc, &binary, taken);
+        }
+
+        if let Some(trace) = &mut self.trace {
+            let record = TraceRecord {
+                pc,
+                opcode: binary.opcode,
+                operand: binary.value,
+                sp: self.sp,
+                top: self.sp.checked_sub(1).map(|top| self.stack[top]),
+                fault: result.is_none(),
+                native: self.ran_native,
+            };
+            if let Err(err) = trace.record(&record) {
+                eprintln!("WARNING: stopped tracing: {}", err);
+                self.trace = None;
+                self.update_recording();
+            }
+        }
+
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(sp);
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn exec(&mut self, binary: ByteCode) -> Option<u64> {
+        let from = self.pc;
+        let opcode = TokenType::from(binary.opcode);
+        let result = match opcode {
             TokenType::Push => self.push(binary.value),
             TokenType::Pop => self.pop(),
             TokenType::Uadd8 => self.add::<u8>(),
@@ -165,22 +839,161 @@ impl VM {
             TokenType::Jeq => self.jeq(binary.value as usize),
             TokenType::Jnz => self.jnz(binary.value as usize),
             TokenType::Int => self.int(),
-            _ => None,
+            TokenType::Load => self.load_word(),
+            TokenType::Store => self.store_word(),
+            _ => self.error(format!("Invalid opcode at {}", self.pc)),
         };
 
         if self.should_increment_pc {
             self.pc += 1;
+        } else if result.is_some()
+            && matches!(opcode, TokenType::Jmp | TokenType::Jeq | TokenType::Jnz)
+        {
+            self.after_jump(from);
         }
 
         result
     }
 
+    // Returns the pc to continue at after a taken jump from `from`
+    #[inline(always)]
+    fn after_jump(&mut self, from: usize) -> usize {
+        if self.pc <= from && self.jit_enabled {
+            self.back_edge(from);
+        }
+        self.pc
+    }
+
+    // Counts a taken backward jump and, once the loop is hot, runs it natively
+    fn back_edge(&mut self, from: usize) {
+        let head = self.pc;
+        let count = self.back_edges.entry(from).or_insert(0);
+        *count += 1;
+
+        if *count == HOT_LOOP_THRESHOLD && !self.compiled_loops.contains_key(&head) {
+            let _ = self.jit_loop(head, from);
+        }
+
+        if let Some(native) = self.compiled_loops.get(&head) {
+            let mut sp = self.sp;
+            let start = self.profile.is_some().then(Instant::now);
+            let exit = native(self.stack.as_mut_ptr(), &mut sp);
+            if let (Some(profile), Some(start)) = (&mut self.profile, start) {
+                profile.add_native(start.elapsed());
+            }
+            self.sp = sp;
+            self.pc = exit as usize;
+            self.ran_native = true;
+        }
+    }
+
+    fn load_native(&mut self, machine_code: &[u8], relocations: &[u64]) -> Option<*const u8> {
+        // check if can create the memory_map;
+        let mut mmap = MmapOptions::new().len(machine_code.len()).map_anon().ok()?;
+        mmap.copy_from_slice(machine_code);
+        let base = mmap.as_ptr() as u64;
+        for offset in relocations {
+            let slot = &mut mmap[*offset as usize..*offset as usize + 8];
+            let value = u64::from_le_bytes(slot.try_into().ok()?).wrapping_add(base);
+            slot.copy_from_slice(&value.to_le_bytes());
+        }
+        let memory_map = mmap.make_exec().ok()?;
+        let code_ptr = memory_map.as_ptr();
+        self.jit_memory_store.push(memory_map);
+        Some(code_ptr)
+    }
+
+    // Looks the code up in the on-disk cache, if there is one
+    fn cached_native(
+        &mut self,
+        key: u64,
+        bin: &[ByteCode],
+        context: &[u64],
+        name: &str,
+        first_pc: usize,
+    ) -> Option<*const u8> {
+        let cached = self.jit_cache.as_ref()?.load(key, bin, context)?;
+        let code_ptr = self.load_native(&cached.code, &cached.relocations)?;
+        let offsets: OffsetMap = cached
+            .offsets
+            .iter()
+            .map(|(pc, offset)| match *pc {
+                jit_dump::EXITS => (*pc, *offset),
+                pc => (pc + first_pc, *offset),
+            })
+            .collect();
+        self.dump_native(name, code_ptr, &cached.code, first_pc, &offsets);
+        Some(code_ptr)
+    }
+
+    fn dump_native(
+        &mut self,
+        name: &str,
+        code_ptr: *const u8,
+        machine_code: &[u8],
+        first_pc: usize,
+        offsets: &OffsetMap,
+    ) {
+        if let Some(dump) = &mut self.jit_dump {
+            dump.record(
+                name,
+                code_ptr,
+                machine_code,
+                &self.bin[first_pc..],
+                first_pc,
+                offsets,
+            );
+        }
+    }
+
+    fn store_native(
+        &self,
+        key: u64,
+        bin: &[ByteCode],
+        context: &[u64],
+        machine_code: &[u8],
+        first_pc: usize,
+        offsets: &OffsetMap,
+    ) {
+        // the same code can be compiled at another pc next time
+        let code = CachedCode {
+            code: machine_code.to_vec(),
+            relocations: Vec::new(),
+            offsets: offsets
+                .iter()
+                .map(|(pc, offset)| match *pc {
+                    jit_dump::EXITS => (*pc, *offset),
+                    pc => (pc - first_pc, *offset),
+                })
+                .collect(),
+        };
+        if let Some(cache) = &self.jit_cache
+            && let Err(err) = cache.store(key, bin, context, &code)
+        {
+            eprintln!("WARNING: could not write jit cache: {}", err);
+        }
+    }
+
+    fn install_proc(&mut self, code_ptr: *const u8) {
+        let jit_fn: extern "C" fn(*const u64, usize) -> u64 = unsafe { mem::transmute(code_ptr) };
+        self.compiled_procs.insert(self.proc_pc, jit_fn);
+    }
+
     fn jit(&mut self, bin: Vec<ByteCode>) -> Result<(), CompileError> {
+        let key = jit_cache::key(&bin, &[]);
+        let name = format!("proc_{}", self.proc_pc);
+        if let Some(code_ptr) = self.cached_native(key, &bin, &[], &name, self.proc_pc) {
+            self.install_proc(code_ptr);
+            return Ok(());
+        }
+
         // check if can open the writer
         if let Ok(mut ops) = dynasmrt::x64::Assembler::new() {
+            let mut offsets = OffsetMap::new();
             // rdi (first argument) rsi (second argument)
             // compile each instruction
-            for binary in bin {
+            for (i, binary) in bin.iter().copied().enumerate() {
+                offsets.push((self.proc_pc + i, ops.offset().0));
                 match TokenType::from(binary.opcode) {
                     TokenType::Push => {
                         dynasm!(ops
@@ -228,80 +1041,348 @@ impl VM {
             }
 
             let code_buffer = ops.finalize().unwrap();
-            let machine_code = code_buffer.to_vec();
-            // check if can create the memory_map;
-            if let Ok(mut mmap) = MmapOptions::new().len(machine_code.len()).map_anon() {
-                mmap.copy_from_slice(&machine_code);
-                let exec_mmap = mmap.make_exec();
-
-                if let Ok(memory_map) = exec_mmap {
-                    let code_ptr = memory_map.as_ptr();
-                    self.jit_memory_store.push(memory_map);
-
-                    let jit_fn: extern "C" fn(*const u64, usize) -> u64 =
-                        unsafe { mem::transmute(code_ptr) };
-                    self.compiled_procs.insert(self.proc_pc, jit_fn);
-                    // Only returns Ok if can execute all the if blocks
-                    return Ok(());
-                }
+            if let Some(code_ptr) = self.load_native(&code_buffer, &[]) {
+                self.store_native(key, &bin, &[], &code_buffer, self.proc_pc, &offsets);
+                self.dump_native(&name, code_ptr, &code_buffer, self.proc_pc, &offsets);
+                self.install_proc(code_ptr);
+                // Only returns Ok if can execute all the if blocks
+                return Ok(());
             }
         }
 
         Err(CompileError)
     }
 
-    pub fn run(&mut self) {
-        let mut ret = Some(0);
-        while self.pc < self.bin.len() {
-            self.should_increment_pc = true;
-            let binary = self.bin[self.pc];
+    // Compiles bin[head..=end] so it works directly on the vm stack.
+    // rdi holds the stack base, rsi points to sp and rdx caches sp while running.
+    // Anything that would fault or is not supported leaves the native code with
+    // the pc of that instruction, so the interpreter executes it (and reports
+    // the error) with the exact same state.
+    fn jit_loop(&mut self, head: usize, end: usize) -> Result<(), CompileError> {
+        // exit pcs and the bounds check on jump targets are baked into the code
+        let context = [head as u64, end as u64, self.bin.len() as u64];
+        let body = self.bin[head..=end].to_vec();
+        let key = jit_cache::key(&body, &context);
+        let name = format!("loop_{}_{}", head, end);
+        if let Some(code_ptr) = self.cached_native(key, &body, &context, &name, head) {
+            let loop_fn: LoopFn = unsafe { mem::transmute(code_ptr) };
+            self.compiled_loops.insert(head, loop_fn);
+            return Ok(());
+        }
+
+        let mut ops = dynasmrt::x64::Assembler::new().map_err(|_| CompileError)?;
+        let labels: Vec<DynamicLabel> = (head..=end).map(|_| ops.new_dynamic_label()).collect();
+        let exit = ops.new_dynamic_label();
+        // labels that leave the loop, each one returns its pc
+        let mut stubs: Vec<(DynamicLabel, usize)> = Vec::new();
+        let mut offsets = OffsetMap::new();
+        let mut stub = |ops: &mut dynasmrt::x64::Assembler, pc: usize| {
+            let label = ops.new_dynamic_label();
+            stubs.push((label, pc));
+            label
+        };
 
-            ret = self.eval(binary);
-            if ret.is_none() {
-                break;
+        dynasm!(ops
+            ; .arch x64
+            ; mov rdx, [rsi]
+        );
+
+        for (i, pc) in (head..=end).enumerate() {
+            let binary = self.bin[pc];
+            let deopt = stub(&mut ops, pc);
+            offsets.push((pc, ops.offset().0));
+            dynasm!(ops
+                ; .arch x64
+                ; =>labels[i]
+            );
+
+            match TokenType::from(binary.opcode) {
+                TokenType::Push => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, MAX_SIZE as i32
+                        ; jae =>deopt
+                        ; mov rax, QWORD binary.value as i64
+                        ; mov [rdi + rdx * 8], rax
+                        ; add rdx, 1
+                    )
+                }
+                TokenType::Pop => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 1
+                        ; jb =>deopt
+                        ; sub rdx, 1
+                    )
+                }
+                TokenType::Dup => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 1
+                        ; jb =>deopt
+                        ; cmp rdx, MAX_SIZE as i32
+                        ; jae =>deopt
+                        ; mov rax, [rdi + rdx * 8 - 8]
+                        ; mov [rdi + rdx * 8], rax
+                        ; add rdx, 1
+                    )
+                }
+                TokenType::Swap if (binary.value as usize) < MAX_SIZE => {
+                    let dist = binary.value as i32;
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, dist
+                        ; jbe =>deopt
+                        ; mov rcx, rdx
+                        ; sub rcx, dist + 1
+                        ; mov rax, [rdi + rdx * 8 - 8]
+                        ; mov r8, [rdi + rcx * 8]
+                        ; mov [rdi + rcx * 8], rax
+                        ; mov [rdi + rdx * 8 - 8], r8
+                    )
+                }
+                // wraps like the interpreter and the procedure jit
+                TokenType::Uadd64 | TokenType::Add64 => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 2
+                        ; jb =>deopt
+                        ; mov rax, [rdi + rdx * 8 - 16]
+                        ; add rax, [rdi + rdx * 8 - 8]
+                        ; mov [rdi + rdx * 8 - 16], rax
+                        ; sub rdx, 1
+                    )
+                }
+                TokenType::Usub64 | TokenType::Sub64 => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 2
+                        ; jb =>deopt
+                        ; mov rax, [rdi + rdx * 8 - 16]
+                        ; sub rax, [rdi + rdx * 8 - 8]
+                        ; mov [rdi + rdx * 8 - 16], rax
+                        ; sub rdx, 1
+                    )
+                }
+                TokenType::Cmp => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 2
+                        ; jb =>deopt
+                        ; mov rax, [rdi + rdx * 8 - 8]
+                        ; sub rax, [rdi + rdx * 8 - 16]
+                        ; mov [rdi + rdx * 8 - 16], rax
+                        ; sub rdx, 1
+                    )
+                }
+                TokenType::Inc => {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; cmp rdx, 1
+                        ; jb =>deopt
+                        ; cmp QWORD [rdi + rdx * 8 - 8], -1
+                        ; je =>deopt
+                        ; add QWORD [rdi + rdx * 8 - 8], 1
+                    )
+                }
+                TokenType::Jmp | TokenType::Jeq | TokenType::Jnz => {
+                    let target = binary.value as usize;
+                    if target >= self.bin.len() {
+                        // left to the interpreter before anything is popped, it reports the error
+                        dynasm!(ops
+                            ; .arch x64
+                            ; jmp =>deopt
+                        );
+                        continue;
+                    }
+                    let dest = if (head..=end).contains(&target) {
+                        labels[target - head]
+                    } else {
+                        stub(&mut ops, target)
+                    };
+
+                    match TokenType::from(binary.opcode) {
+                        TokenType::Jeq => dynasm!(ops
+                            ; .arch x64
+                            ; cmp rdx, 1
+                            ; jb =>deopt
+                            ; sub rdx, 1
+                            ; cmp QWORD [rdi + rdx * 8], 0
+                            ; je =>dest
+                        ),
+                        TokenType::Jnz => dynasm!(ops
+                            ; .arch x64
+                            ; cmp rdx, 1
+                            ; jb =>deopt
+                            ; sub rdx, 1
+                            ; cmp QWORD [rdi + rdx * 8], 0
+                            ; jne =>dest
+                        ),
+                        _ => dynasm!(ops
+                            ; .arch x64
+                            ; jmp =>dest
+                        ),
+                    }
+                }
+                _ => {
+                    // nothing would be executed natively
+                    if pc == head {
+                        return Err(CompileError);
+                    }
+                    dynasm!(ops
+                        ; .arch x64
+                        ; jmp =>deopt
+                    )
+                }
             }
         }
-        println!("Stack state: {:?}", self.stack);
-        if ret.is_none() {
-            println!("Segmentation fault (core dumped)");
-            return;
+
+        offsets.push((jit_dump::EXITS, ops.offset().0));
+        // falling through the last instruction leaves the loop
+        let fallthrough = stub(&mut ops, end + 1);
+        dynasm!(ops
+            ; .arch x64
+            ; jmp =>fallthrough
+        );
+
+        for (label, pc) in stubs {
+            dynasm!(ops
+                ; .arch x64
+                ; =>label
+                ; mov rax, QWORD pc as i64
+                ; jmp =>exit
+            );
         }
+
+        dynasm!(ops
+            ; .arch x64
+            ; =>exit
+            ; mov [rsi], rdx
+            ; ret
+        );
+
+        let code_buffer = ops.finalize().map_err(|_| CompileError)?;
+        let code_ptr = self.load_native(&code_buffer, &[]).ok_or(CompileError)?;
+        self.store_native(key, &body, &context, &code_buffer, head, &offsets);
+        self.dump_native(&name, code_ptr, &code_buffer, head, &offsets);
+        let loop_fn: LoopFn = unsafe { mem::transmute(code_ptr) };
+        self.compiled_loops.insert(head, loop_fn);
+        Ok(())
     }
 
-    pub fn debug_run(&mut self, flag: bool) {
-        while self.pc < self.bin.len() {
-            self.should_increment_pc = true;
-            let binary = self.bin[self.pc];
+    pub fn is_running(&self) -> bool {
+        self.pc < self.bin.len()
+    }
+
+    /// Evaluates the instruction at pc and moves to the next one
+    pub fn step(&mut self) -> Option<u64> {
+        self.should_increment_pc = true;
+        self.last_error = None;
+        self.steps += 1;
+        self.recent[self.recent_count as usize & (RECENT_PCS - 1)] = self.pc;
+        self.recent_count += 1;
+        let binary = self.bin[self.pc];
+        self.eval(binary)
+    }
 
-            if flag == true {
-                println!("{}, {}", TokenType::from(binary.opcode), binary.value);
-                sleep(Duration::from_millis(100));
+    /// Runs the program until it halts, returns false if it faulted.
+    /// The program is decoded once into handlers so the loop does no decoding,
+    /// mapped code is not copied, its opcodes index a table of handlers instead.
+    pub fn execute(&mut self) -> bool {
+        let pc = match &self.bin {
+            Code::Owned(bin) => {
+                let code: Vec<Inst> = bin.iter().map(|binary| Inst::decode(*binary)).collect();
+                self.dispatch(|_, pc| code.get(pc).copied())
             }
+            Code::Mapped(_) => {
+                let table: Vec<Handler> = (0..=u8::MAX)
+                    .map(|opcode| Inst::decode(ByteCode { opcode, value: 0 }).handler)
+                    .collect();
+                self.dispatch(|vm, pc| {
+                    let binary = vm.bin.get(pc)?;
+                    Some(Inst {
+                        handler: table[binary.opcode as usize],
+                        operand: binary.value,
+                    })
+                })
+            }
+        };
 
-            let result = self.eval(binary);
+        if pc == FAULT {
+            return false;
+        }
+        self.pc = self.bin.len();
+        true
+    }
 
-            if result.is_none() {
-                println!(
-                    "An error has occurred and was at instruction: {}",
-                    TokenType::from(binary.opcode)
-                );
-                println!("Core dumped.");
-                let _ = core_dump(&self.stack);
-                return;
+    // runs handlers until fetch has no instruction at the pc, returns that pc
+    #[inline(always)]
+    fn dispatch(&mut self, fetch: impl Fn(&VM, usize) -> Option<Inst>) -> usize {
+        let mut pc = self.pc;
+        // kept local so the loop does not go through self for it
+        let mut recent = self.recent;
+        let mut count = self.recent_count;
+        while let Some(inst) = fetch(self, pc) {
+            self.pc = pc;
+            recent[count as usize & (RECENT_PCS - 1)] = pc;
+            count += 1;
+            pc = (inst.handler)(self, inst.operand);
+        }
+        self.recent = recent;
+        self.recent_count = count;
+        pc
+    }
+
+    // like execute, but through eval so every instruction can be looked at
+    fn execute_stepped(&mut self) -> bool {
+        while self.is_running() {
+            let pc = self.pc;
+            if self.step().is_none() {
+                self.pc = pc;
+                return false;
             }
+        }
+        // halt leaves pc one past the end here
+        self.pc = self.bin.len();
+        true
+    }
 
-            if flag == true {
-                println!("stack state: {:?}, sp: {}", self.stack, self.sp);
+    pub fn run(&mut self) {
+        let start = Instant::now();
+        let ok = if self.instrumented {
+            self.execute_stepped()
+        } else {
+            self.execute()
+        };
+        if let Some(profile) = &mut self.profile {
+            profile.set_total(start.elapsed());
+        }
+        if let Some(trace) = &mut self.trace
+            && let Err(err) = trace.finish()
+        {
+            eprintln!("WARNING: could not write the trace: {}", err);
+        }
+        let _ = self.output.flush();
+        println!("Stack state: {:?}", self.stack);
+        if !ok {
+            println!("Segmentation fault (core dumped)");
+            println!("at {}", self.describe_pc(self.pc));
+            match self.write_crash_dump(self.pc) {
+                Ok(path) => println!("crash dump written to {}", path.display()),
+                Err(err) => eprintln!("WARNING: could not write the crash dump: {}", err),
             }
         }
     }
 
     fn push(&mut self, value: u64) -> Option<u64> {
         if self.sp == MAX_SIZE {
-            println!("ERROR: STACK OVERFLOW!");
-            return None;
+            return self.error(String::from("STACK OVERFLOW!"));
         }
 
+        self.record(Access::Write {
+            slot: self.sp,
+            old: self.stack[self.sp],
+        });
         self.stack[self.sp] = value;
         self.sp += 1;
 
@@ -314,6 +1395,7 @@ impl VM {
         }
 
         self.sp -= 1;
+        self.record(Access::Read(self.sp));
         let value = self.stack[self.sp];
 
         Some(value)
@@ -326,14 +1408,14 @@ impl VM {
         if let Some(value1) = v1
             && let Some(value2) = v2
         {
-            return self.push((T::from_bits(value1) + T::from_bits(value2)).into_bits());
+            let (a, b) = (T::from_bits(value1), T::from_bits(value2));
+            return self.push(a.wrapping_add(b).into_bits());
         }
 
-        println!(
-            "ERROR: Stack underflow, requires 2 values, but stack has {}",
+        self.error(format!(
+            "Stack underflow, requires 2 values, but stack has {}",
             self.sp
-        );
-        None
+        ))
     }
 
     fn addf<T: NumberBitsFloat>(&mut self) -> Option<u64> {
@@ -354,15 +1436,14 @@ impl VM {
         if let Some(value1) = v1
             && let Some(value2) = v2
         {
-            return self.push((T::from_bits(value2) - T::from_bits(value1)).into_bits());
+            let (a, b) = (T::from_bits(value2), T::from_bits(value1));
+            return self.push(a.wrapping_sub(b).into_bits());
         }
 
-        println!(
-            "ERROR: Stack underflow, requires 2 values, but stack has {}",
+        self.error(format!(
+            "Stack underflow, requires 2 values, but stack has {}",
             self.sp
-        );
-
-        None
+        ))
     }
 
     fn subf<T: NumberBitsFloat>(&mut self) -> Option<u64> {
@@ -381,10 +1462,10 @@ impl VM {
 
         if let Some(value1) = v1 {
             if let Some(valid) = char::from_u32(value1 as u32) {
-                print!("{}", valid);
+                let _ = write!(self.output, "{}", valid);
                 return v1;
             } else {
-                println!("{} is not a valid unicode!", value1)
+                self.error(format!("{} is not a valid unicode!", value1));
             }
         }
 
@@ -394,8 +1475,7 @@ impl VM {
     fn inc<T: NumberBits>(&mut self) -> Option<u64> {
         if let Some(value) = self.pop() {
             if T::from_bits(value) == T::from_bits(T::max()) {
-                println!("ERROR: Arithmetic error, trying to add 1 to {}", T::max());
-                return None;
+                return self.error(format!("Arithmetic error, trying to add 1 to {}", T::max()));
             }
 
             self.push(value + 1);
@@ -418,7 +1498,12 @@ impl VM {
             && let Some(sf) = self.pop()
         {
             let pos = self.sp - (swap_value as usize);
-            let val = self.stack[pos].clone();
+            self.record(Access::Read(pos));
+            self.record(Access::Write {
+                slot: pos,
+                old: self.stack[pos],
+            });
+            let val = self.stack[pos];
             self.stack[pos] = sf;
             self.push(val);
 
@@ -431,12 +1516,11 @@ impl VM {
     fn jmp(&mut self, pc: usize) -> Option<u64> {
         self.should_increment_pc = false;
         if pc >= self.bin.len() {
-            println!(
-                "ERROR: Out of bounds jump to index {}, program length is {}",
+            return self.error(format!(
+                "Out of bounds jump to index {}, program length is {}",
                 pc,
                 self.bin.len()
-            );
-            return None;
+            ));
         }
 
         self.pc = pc;
@@ -446,7 +1530,12 @@ impl VM {
 
     fn call(&mut self, pc: usize) -> Option<u64> {
         if let Some(func) = self.compiled_procs.get(&pc) {
-            let res = func(self.stack.as_ptr(), pc.clone());
+            let start = self.profile.is_some().then(Instant::now);
+            let res = func(self.stack.as_ptr(), pc);
+            if let (Some(profile), Some(start)) = (&mut self.profile, start) {
+                profile.add_native(start.elapsed());
+            }
+            self.ran_native = true;
             self.push(res);
             return Some(0);
         }
@@ -457,6 +1546,11 @@ impl VM {
             self.funcs_used.insert(pc, 1);
         }
 
+        self.frames.push(CallFrame {
+            call_pc: self.pc,
+            proc_pc: pc,
+            sp: self.sp,
+        });
         self.push(self.sp as u64);
         self.push(self.pc as u64);
 
@@ -472,10 +1566,10 @@ impl VM {
         if let Some(pc) = self.pop() {
             let pc = pc as usize;
             if pc >= self.bin.len() {
-                println!("ERROR: Out of bounds jump: {}", pc);
-                return None;
+                return self.error(format!("Out of bounds jump: {}", pc));
             }
 
+            self.pc = pc;
             return Some(0);
         }
 
@@ -485,7 +1579,7 @@ impl VM {
     fn jeq(&mut self, address: usize) -> Option<u64> {
         if let Some(value) = self.pop() {
             if value == 0 {
-                self.jmp(address);
+                return self.jmp(address);
             }
             return Some(0);
         }
@@ -495,7 +1589,7 @@ impl VM {
     fn jnz(&mut self, address: usize) -> Option<u64> {
         if let Some(value) = self.pop() {
             if value != 0 {
-                self.jmp(address);
+                return self.jmp(address);
             }
             return Some(0);
         }
@@ -508,18 +1602,18 @@ impl VM {
         if let Some(value1) = v1
             && let Some(value2) = v2
         {
-            self.push((value1 as i64 - value2 as i64) as u64);
-            return Some(0);
+            return self.push((value1 as i64).wrapping_sub(value2 as i64) as u64);
         }
 
         None
     }
 
     fn ret(&mut self) -> Option<u64> {
-        if let Some(func_value) = self.funcs_used.get(&self.proc_pc) {
-            if *func_value == INTERPRETED_EXECUTIONS {
-                let _ = self.jit(self.bin[self.proc_pc..self.pc + 1].to_vec());
-            }
+        if let Some(func_value) = self.funcs_used.get(&self.proc_pc)
+            && *func_value == INTERPRETED_EXECUTIONS
+            && self.jit_enabled
+        {
+            let _ = self.jit(self.bin[self.proc_pc..self.pc + 1].to_vec());
         }
 
         // Ret always takes the last value on the stack
@@ -542,6 +1636,12 @@ impl VM {
             }
             self.pc = pc as usize;
             self.push(ret);
+
+            self.frames.pop();
+            // back in the caller, which may be a procedure itself
+            if let Some(frame) = self.frames.last() {
+                self.proc_pc = frame.proc_pc;
+            }
             return Some(0);
         }
 
@@ -550,19 +1650,272 @@ impl VM {
 
     fn int(&mut self) -> Option<u64> {
         if let Some(int_value) = self.pop() {
-            match int_value {
-                0 => {
-                    self.halt();
-                }
-                _ => {}
+            if int_value == 0 {
+                self.halt();
             }
             return Some(0);
         }
         None
     }
 
+    // pops an address and pushes the word of .rodata/.data there
+    fn load_word(&mut self) -> Option<u64> {
+        let address = self.pop()?;
+        match self.memory.get(address as usize) {
+            Some(&value) => {
+                self.record(Access::Load(address as usize));
+                self.push(value)
+            }
+            None => self.error(format!(
+                "load from address {} outside of the data ({} words)",
+                address,
+                self.memory.len()
+            )),
+        }
+    }
+
+    // pops an address, then the value to write there
+    fn store_word(&mut self) -> Option<u64> {
+        let address = self.pop()?;
+        let value = self.pop()?;
+        let address = address as usize;
+        if address < self.rodata_len {
+            return self.error(format!("store to address {} in .rodata", address));
+        }
+        let Some(&old) = self.memory.get(address) else {
+            return self.error(format!(
+                "store to address {} outside of the data ({} words)",
+                address,
+                self.memory.len()
+            ));
+        };
+        self.record(Access::Store { address, old });
+        self.memory[address] = value;
+        Some(0)
+    }
+
     fn halt(&mut self) -> Option<u64> {
         self.pc = self.bin.len();
         Some(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smachine::compiler::assemble;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Run {
+        ok: bool,
+        stack: Vec<u64>,
+        output: String,
+        loops: usize,
+    }
+
+    fn run(source: &str, jit: bool) -> Run {
+        let mut vm = VM::from_program(assemble(source, "test.s").unwrap());
+        let sink = Sink::default();
+        vm.set_output(Box::new(sink.clone()));
+        vm.set_jit_enabled(jit);
+        let ok = vm.execute();
+        let output = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        Run {
+            ok,
+            stack: vm.stack().to_vec(),
+            output,
+            loops: vm.compiled_loops.len(),
+        }
+    }
+
+    // runs the source in both tiers and checks they end the same way
+    fn same_in_both_tiers(source: &str) -> Run {
+        let interpreted = run(source, false);
+        let compiled = run(source, true);
+        assert_eq!(interpreted.loops, 0);
+        assert_eq!(compiled.loops, 1);
+        assert_eq!(interpreted.ok, compiled.ok);
+        assert_eq!(interpreted.stack, compiled.stack);
+        assert_eq!(interpreted.output, compiled.output);
+        compiled
+    }
+
+    #[test]
+    fn hot_loops_match_the_interpreter() {
+        let source = "push 0\npush 200\n1:\nswap 1\npush 3\nuadd64\nswap 1\npush 1\nusub64\ndup\njnz 1b\nhalt\n";
+        let run = same_in_both_tiers(source);
+        assert!(run.ok);
+        assert_eq!(run.stack[..2], [600, 0]);
+    }
+
+    #[test]
+    fn unsupported_instructions_deopt_every_iteration() {
+        // prt is left to the interpreter, the loop resumes natively after it
+        let source = "push 100\n1:\npush 97\nprt\npush 1\nusub64\ndup\njnz 1b\nhalt\n";
+        let run = same_in_both_tiers(source);
+        assert!(run.ok);
+        assert_eq!(run.output, "a".repeat(100));
+    }
+
+    #[test]
+    fn native_arithmetic_wraps_like_the_interpreter() {
+        let source = "push 1\npush 101\n1:\nswap 1\npush 9223372036854775808\nuadd64\npush 3\nusub64\nswap 1\npush 1\nusub64\ndup\njnz 1b\nhalt\n";
+        let run = same_in_both_tiers(source);
+        assert!(run.ok);
+        // both the add and the sub go past the ends of u64 on the way
+        assert_eq!(run.stack[0], (1u64 << 63) - 302);
+    }
+
+    #[test]
+    fn faults_inside_a_loop_are_reported_by_the_interpreter() {
+        // after 150 iterations the counter runs into a jump past the end
+        let source = "push 150\n1:\npush 1\nusub64\ndup\njnz 1b\njmp 100\n";
+        let run = same_in_both_tiers(source);
+        assert!(!run.ok);
+    }
+
+    // runs `vm` through the decoded handlers and a copy through eval, one step at a time
+    fn both_dispatchers(make: impl Fn() -> VM) {
+        let mut results = Vec::new();
+        for stepped in [false, true] {
+            let mut vm = make();
+            let sink = Sink::default();
+            vm.set_output(Box::new(sink.clone()));
+            vm.set_jit_enabled(false);
+            let ok = if stepped {
+                vm.execute_stepped()
+            } else {
+                vm.execute()
+            };
+            let output = String::from_utf8(sink.0.borrow().clone()).unwrap();
+            results.push((
+                ok,
+                vm.pc,
+                vm.stack().to_vec(),
+                vm.sp,
+                vm.memory().to_vec(),
+                output,
+            ));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn handlers_match_eval() {
+        let source = "
+.data
+word: .word 0
+.text
+    push 3
+    push 4
+    uadd8
+    push 250
+    push 10
+    uadd8
+    push 1.5
+    push 2.25
+    addf64
+    dup
+    swap 1
+    cmp
+    push 0
+    jeq skip
+    push 99
+skip:
+    inc
+    push word
+    store
+    call f
+    push after
+    jmpp
+    push 77
+after:
+    push word
+    load
+    push 98
+    prt
+    halt
+f:
+    push 5
+    ret
+";
+        both_dispatchers(|| VM::from_program(assemble(source, "test.s").unwrap()));
+        for fault in [
+            "pop\n",
+            "push 1\njmp 100\n",
+            "1:\npush 1\njmp 1b\n",
+            "push 3\nload\n",
+        ] {
+            both_dispatchers(|| VM::from_program(assemble(fault, "test.s").unwrap()));
+        }
+        let invalid = ByteCode {
+            opcode: 200,
+            value: 0,
+        };
+        both_dispatchers(|| VM::new(vec![invalid]));
+    }
+
+    #[test]
+    fn load_and_store() {
+        let source = "
+.rodata
+seven: .word 7
+.data
+slot: .zero 1
+.text
+.entry main
+main:
+    push seven
+    load
+    push slot
+    store
+    push slot
+    load
+    push 1
+    push seven
+    store
+";
+        let run = run(source, false);
+        assert!(!run.ok);
+        assert_eq!(run.stack[0], 7);
+        assert_eq!(run.output, "ERROR: store to address 0 in .rodata\n");
+
+        let mut vm = VM::from_program(assemble(source, "test.s").unwrap());
+        vm.set_output(Box::new(Sink::default()));
+        vm.execute();
+        assert_eq!(vm.memory(), &[7, 7]);
+    }
+
+    #[test]
+    fn stack_bounds_leave_native_code() {
+        let mut vm = VM::from_program(assemble("1:\npush 1\njmp 1b\n", "test.s").unwrap());
+        vm.jit_loop(0, 1).unwrap();
+        let native = vm.compiled_loops[&0];
+        let mut sp = 0;
+        // the push that would overflow is left for the interpreter
+        assert_eq!(native(vm.stack.as_mut_ptr(), &mut sp), 0);
+        assert_eq!(sp, MAX_SIZE);
+
+        let mut vm = VM::from_program(assemble("1:\npop\njmp 1b\n", "test.s").unwrap());
+        vm.jit_loop(0, 1).unwrap();
+        let native = vm.compiled_loops[&0];
+        let mut sp = 3;
+        assert_eq!(native(vm.stack.as_mut_ptr(), &mut sp), 0);
+        assert_eq!(sp, 0);
+    }
+}
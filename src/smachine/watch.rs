@@ -0,0 +1,422 @@
+use std::fmt;
+
+/// A stack slot or data word touched by an instruction, recorded while watchpoints
+/// or the undo log are on
+#[derive(Clone, Copy, Debug)]
+pub enum Access {
+    Read(usize),
+    Write { slot: usize, old: u64 },
+    // a word of .rodata or .data read by load
+    Load(usize),
+    // a word of .data written by store
+    Store { address: usize, old: u64 },
+}
+
+/// Which stack slot a watchpoint looks at
+#[derive(Clone, Copy, Debug)]
+pub enum Slot {
+    Absolute(usize),
+    // relative to sp before the instruction runs, sp-1 is the top of the stack
+    FromSp(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+/// Condition over the vm state, like `sp > 8`, `stack[2] == 42` or `mem[3] != 0`
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(i64),
+    Pc,
+    Sp,
+    Stack(Box<Expr>),
+    Memory(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Watchpoint {
+    Slot(Slot, WatchKind),
+    // a word of .rodata/.data, read by load and written by store
+    Address(usize, WatchKind),
+    // stops when the condition goes from false to true
+    Condition {
+        expr: Expr,
+        text: String,
+        last: bool,
+    },
+}
+
+impl Slot {
+    /// `3`, `stack[3]`, `sp-1` or `stack[sp-1]`
+    pub fn parse(text: &str) -> Option<Slot> {
+        let text = text.trim();
+        let text = match text.strip_prefix("stack[") {
+            Some(inner) => inner.strip_suffix(']')?.trim(),
+            None => text,
+        };
+
+        match text.strip_prefix("sp") {
+            Some("") => Some(Slot::FromSp(0)),
+            Some(offset) => {
+                let offset = offset.replace(' ', "");
+                let offset = offset.strip_prefix('+').unwrap_or(&offset);
+                offset.parse::<i64>().ok().map(Slot::FromSp)
+            }
+            None => text.parse::<usize>().ok().map(Slot::Absolute),
+        }
+    }
+
+    pub fn resolve(&self, sp: usize) -> Option<usize> {
+        match *self {
+            Slot::Absolute(slot) => Some(slot),
+            Slot::FromSp(offset) => usize::try_from(sp as i64 + offset).ok(),
+        }
+    }
+}
+
+/// `mem[5]`, the address of a data word to watch
+pub fn parse_address(text: &str) -> Option<usize> {
+    text.trim()
+        .strip_prefix("mem[")?
+        .strip_suffix(']')?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Slot::Absolute(slot) => write!(f, "stack[{}]", slot),
+            Slot::FromSp(0) => write!(f, "stack[sp]"),
+            Slot::FromSp(offset) => write!(f, "stack[sp{:+}]", offset),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Option<Vec<String>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if matches!(pair.as_str(), "==" | "!=" | "<=" | ">=" | "&&" | "||") {
+                tokens.push(pair);
+                i += 2;
+            } else if "+-<>[]()".contains(c) {
+                tokens.push(c.to_string());
+                i += 1;
+            } else {
+                return None;
+            }
+        }
+    }
+    Some(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, token: &str) -> Option<()> {
+        (self.next()? == token).then_some(())
+    }
+
+    // each level takes the operators it handles and the next, tighter, level
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        next: fn(&mut Parser) -> Option<Expr>,
+    ) -> Option<Expr> {
+        let mut left = next(self)?;
+        while let Some(op) = self
+            .peek()
+            .and_then(|token| ops.iter().find(|(text, _)| *text == token))
+            .map(|(_, op)| *op)
+        {
+            self.pos += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(next(self)?));
+        }
+        Some(left)
+    }
+
+    fn or(&mut self) -> Option<Expr> {
+        self.binary(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Option<Expr> {
+        self.binary(&[("&&", Op::And)], Parser::compare)
+    }
+
+    fn compare(&mut self) -> Option<Expr> {
+        self.binary(
+            &[
+                ("==", Op::Eq),
+                ("!=", Op::Ne),
+                ("<=", Op::Le),
+                (">=", Op::Ge),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            Parser::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Option<Expr> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::atom)
+    }
+
+    fn atom(&mut self) -> Option<Expr> {
+        let token = self.next()?;
+        match token.as_str() {
+            "pc" => Some(Expr::Pc),
+            "sp" => Some(Expr::Sp),
+            "stack" | "mem" => {
+                self.expect("[")?;
+                let index = Box::new(self.sum()?);
+                self.expect("]")?;
+                match token.as_str() {
+                    "stack" => Some(Expr::Stack(index)),
+                    _ => Some(Expr::Memory(index)),
+                }
+            }
+            "(" => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Some(expr)
+            }
+            "-" => match self.atom()? {
+                Expr::Number(value) => Some(Expr::Number(value.wrapping_neg())),
+                expr => Some(Expr::Binary(
+                    Op::Sub,
+                    Box::new(Expr::Number(0)),
+                    Box::new(expr),
+                )),
+            },
+            number => {
+                let value = match number.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                    None => number.parse::<u64>().ok()?,
+                };
+                Some(Expr::Number(value as i64))
+            }
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Option<Expr> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        (parser.pos == parser.tokens.len()).then_some(expr)
+    }
+
+    /// None when it reads outside of the live stack or the data,
+    /// slots at sp and above hold whatever was popped last
+    pub fn eval(&self, pc: usize, sp: usize, stack: &[u64], memory: &[u64]) -> Option<i64> {
+        match self {
+            Expr::Number(value) => Some(*value),
+            Expr::Pc => Some(pc as i64),
+            Expr::Sp => Some(sp as i64),
+            Expr::Stack(index) => {
+                let index = usize::try_from(index.eval(pc, sp, stack, memory)?).ok()?;
+                stack[..sp.min(stack.len())]
+                    .get(index)
+                    .map(|value| *value as i64)
+            }
+            Expr::Memory(address) => {
+                let address = usize::try_from(address.eval(pc, sp, stack, memory)?).ok()?;
+                memory.get(address).map(|value| *value as i64)
+            }
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (
+                    left.eval(pc, sp, stack, memory)?,
+                    right.eval(pc, sp, stack, memory)?,
+                );
+                Some(match op {
+                    Op::Add => left.wrapping_add(right),
+                    Op::Sub => left.wrapping_sub(right),
+                    Op::Eq => (left == right) as i64,
+                    Op::Ne => (left != right) as i64,
+                    Op::Lt => (left < right) as i64,
+                    Op::Le => (left <= right) as i64,
+                    Op::Gt => (left > right) as i64,
+                    Op::Ge => (left >= right) as i64,
+                    Op::And => (left != 0 && right != 0) as i64,
+                    Op::Or => (left != 0 || right != 0) as i64,
+                })
+            }
+        }
+    }
+}
+
+impl Watchpoint {
+    pub fn condition(text: &str) -> Option<Watchpoint> {
+        Some(Watchpoint::Condition {
+            expr: Expr::parse(text)?,
+            text: text.trim().to_string(),
+            last: false,
+        })
+    }
+
+    /// Whether the instruction that just ran should stop execution,
+    /// `sp_before` is sp before it ran and `accesses` the slots it touched
+    pub fn triggered(
+        &mut self,
+        sp_before: usize,
+        accesses: &[Access],
+        pc: usize,
+        sp: usize,
+        stack: &[u64],
+        memory: &[u64],
+    ) -> bool {
+        match self {
+            Watchpoint::Slot(slot, kind) => {
+                let Some(watched) = slot.resolve(sp_before) else {
+                    return false;
+                };
+                accesses.iter().any(|access| match *access {
+                    Access::Read(slot) => slot == watched && *kind != WatchKind::Write,
+                    Access::Write { slot, .. } => slot == watched && *kind != WatchKind::Read,
+                    Access::Load(_) | Access::Store { .. } => false,
+                })
+            }
+            Watchpoint::Address(watched, kind) => accesses.iter().any(|access| match *access {
+                Access::Load(address) => address == *watched && *kind != WatchKind::Write,
+                Access::Store { address, .. } => address == *watched && *kind != WatchKind::Read,
+                Access::Read(_) | Access::Write { .. } => false,
+            }),
+            Watchpoint::Condition { expr, last, .. } => {
+                let now = expr
+                    .eval(pc, sp, stack, memory)
+                    .is_some_and(|value| value != 0);
+                let hit = now && !*last;
+                *last = now;
+                hit
+            }
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watchpoint::Slot(slot, WatchKind::Write) => write!(f, "write {}", slot),
+            Watchpoint::Slot(slot, WatchKind::Read) => write!(f, "read {}", slot),
+            Watchpoint::Slot(slot, WatchKind::Access) => write!(f, "access {}", slot),
+            Watchpoint::Address(address, WatchKind::Write) => write!(f, "write mem[{}]", address),
+            Watchpoint::Address(address, WatchKind::Read) => write!(f, "read mem[{}]", address),
+            Watchpoint::Address(address, WatchKind::Access) => {
+                write!(f, "access mem[{}]", address)
+            }
+            Watchpoint::Condition { text, .. } => write!(f, "{}", text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, sp: usize, stack: &[u64], memory: &[u64]) -> Option<i64> {
+        Expr::parse(text).unwrap().eval(7, sp, stack, memory)
+    }
+
+    #[test]
+    fn expressions() {
+        let stack = [1, 42, 9];
+        assert_eq!(eval("sp > 1 && stack[sp-1] == 42", 2, &stack, &[]), Some(1));
+        assert_eq!(eval("pc + 0x10 - -1", 0, &stack, &[]), Some(24));
+        assert_eq!(eval("(1 || 0) && mem[1] != 5", 0, &stack, &[5, 6]), Some(1));
+        // slots at sp and above and words past the data can not be read
+        assert_eq!(eval("stack[2]", 2, &stack, &[]), None);
+        assert_eq!(eval("mem[2]", 0, &stack, &[5, 6]), None);
+
+        for text in ["sp >", "stack[1", "1 $ 2", "1 2", "", "mem 1"] {
+            assert!(Expr::parse(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn slots() {
+        assert!(matches!(Slot::parse("3"), Some(Slot::Absolute(3))));
+        assert!(matches!(
+            Slot::parse("stack[sp - 1]"),
+            Some(Slot::FromSp(-1))
+        ));
+        assert!(matches!(Slot::parse("sp+2"), Some(Slot::FromSp(2))));
+        assert!(Slot::parse("stack[x]").is_none());
+        assert_eq!(Slot::FromSp(-3).resolve(2), None);
+        assert_eq!(parse_address("mem[ 4 ]"), Some(4));
+    }
+
+    #[test]
+    fn triggers() {
+        let mut top = Watchpoint::Slot(Slot::FromSp(-1), WatchKind::Write);
+        let written = [Access::Read(1), Access::Write { slot: 1, old: 0 }];
+        assert!(top.triggered(2, &written, 0, 2, &[0; 4], &[]));
+        // sp-1 is taken from sp before the instruction
+        assert!(!top.triggered(3, &written, 0, 2, &[0; 4], &[]));
+        let mut read = Watchpoint::Slot(Slot::Absolute(1), WatchKind::Read);
+        assert!(read.triggered(2, &[Access::Read(1)], 0, 1, &[0; 4], &[]));
+        assert!(!read.triggered(2, &[Access::Write { slot: 1, old: 0 }], 0, 2, &[0; 4], &[]));
+
+        let mut word = Watchpoint::Address(3, WatchKind::Access);
+        assert!(word.triggered(0, &[Access::Load(3)], 0, 0, &[], &[]));
+        assert!(word.triggered(0, &[Access::Store { address: 3, old: 1 }], 0, 0, &[], &[]));
+        assert!(!word.triggered(0, &[Access::Read(3)], 0, 0, &[], &[]));
+
+        // a condition stops when it becomes true, not while it stays true
+        let mut deep = Watchpoint::condition("sp >= 2").unwrap();
+        let states = [1, 2, 3, 1, 2];
+        let hits: Vec<bool> = states
+            .iter()
+            .map(|&sp| deep.triggered(sp, &[], 0, sp, &[0; 4], &[]))
+            .collect();
+        assert_eq!(hits, [false, true, false, false, true]);
+    }
+}